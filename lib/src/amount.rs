@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::io::{Read, Result as IoResult, Write};
+use std::str::FromStr;
+
+use crate::encoding::{Decodable, Encodable};
+use crate::error::{BtcError, Result};
+
+// number of satoshis in one bitcoin
+pub const SATS_PER_BTC: u64 = 100_000_000;
+// number of decimal places of a bitcoin amount
+const BTC_DECIMALS: usize = 8;
+
+/// An amount of money in satoshis. All arithmetic is checked,
+/// so values can neither overflow nor go negative
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const ONE_SAT: Amount = Amount(1);
+    pub const ONE_BTC: Amount = Amount(SATS_PER_BTC);
+    // no amount, and no sum of amounts, can ever exceed the
+    // total supply
+    pub const MAX_MONEY: Amount =
+        Amount(21_000_000 * SATS_PER_BTC);
+
+    pub const fn from_sat(sat: u64) -> Self {
+        Amount(sat)
+    }
+
+    pub const fn to_sat(self) -> u64 {
+        self.0
+    }
+
+    // whole bitcoins, None if the amount does not fit
+    pub fn from_btc(btc: u64) -> Option<Self> {
+        btc.checked_mul(SATS_PER_BTC).map(Amount)
+    }
+
+    // whether the amount is within the total supply
+    pub fn is_valid(self) -> bool {
+        self <= Amount::MAX_MONEY
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Self> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    pub fn checked_mul(self, factor: u64) -> Option<Self> {
+        self.0.checked_mul(factor).map(Amount)
+    }
+
+    pub fn checked_div(self, divisor: u64) -> Option<Self> {
+        self.0.checked_div(divisor).map(Amount)
+    }
+
+    // sum of amounts, None if the total is above MAX_MONEY
+    pub fn checked_sum<I>(amounts: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = Amount>,
+    {
+        amounts.into_iter().try_fold(
+            Amount::ZERO,
+            |total, amount| {
+                total
+                    .checked_add(amount)
+                    .filter(|total| total.is_valid())
+            },
+        )
+    }
+
+    // parse a decimal number of bitcoins such as "1.5", with
+    // at most 8 decimal places
+    pub fn from_btc_str(s: &str) -> Result<Self> {
+        let (whole, fraction) =
+            s.split_once('.').unwrap_or((s, ""));
+        if (whole.is_empty() && fraction.is_empty())
+            || fraction.len() > BTC_DECIMALS
+            || !is_digits(whole)
+            || !is_digits(fraction)
+        {
+            return Err(BtcError::InvalidAmount);
+        }
+
+        let whole = if whole.is_empty() {
+            0
+        } else {
+            whole.parse().map_err(|_| BtcError::InvalidAmount)?
+        };
+        let fraction = format!(
+            "{:0<width$}",
+            fraction,
+            width = BTC_DECIMALS
+        )
+        .parse::<u64>()
+        .map_err(|_| BtcError::InvalidAmount)?;
+
+        Amount::from_btc(whole)
+            .and_then(|amount| {
+                amount.checked_add(Amount(fraction))
+            })
+            .ok_or(BtcError::InvalidAmount)
+    }
+
+    // parse a whole number of satoshis
+    pub fn from_sat_str(s: &str) -> Result<Self> {
+        if !is_digits(s) || s.is_empty() {
+            return Err(BtcError::InvalidAmount);
+        }
+        s.parse()
+            .map(Amount)
+            .map_err(|_| BtcError::InvalidAmount)
+    }
+}
+
+// only plain ASCII digits, no signs or whitespace
+fn is_digits(s: &str) -> bool {
+    s.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+// the exact number of bitcoins without trailing zeros, such
+// as "0.001 BTC"
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let whole = self.0 / SATS_PER_BTC;
+        let fraction = self.0 % SATS_PER_BTC;
+
+        if fraction == 0 {
+            return write!(f, "{} BTC", whole);
+        }
+
+        let fraction = format!(
+            "{:0width$}",
+            fraction,
+            width = BTC_DECIMALS
+        );
+        write!(
+            f,
+            "{}.{} BTC",
+            whole,
+            fraction.trim_end_matches('0')
+        )
+    }
+}
+
+// a number of bitcoins, optionally followed by "BTC", or a
+// number of satoshis followed by "sat" or "sats"
+impl FromStr for Amount {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Some(sats) = s
+            .strip_suffix("sats")
+            .or_else(|| s.strip_suffix("sat"))
+        {
+            Amount::from_sat_str(sats.trim_end())
+        } else if let Some(btc) = s.strip_suffix("BTC") {
+            Amount::from_btc_str(btc.trim_end())
+        } else {
+            Amount::from_btc_str(s)
+        }
+    }
+}
+
+// the number of satoshis as a u64
+impl Encodable for Amount {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.0.encode(writer)
+    }
+}
+
+impl Decodable for Amount {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        u64::decode(reader).map(Amount)
+    }
+}
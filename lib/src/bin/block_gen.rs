@@ -1,4 +1,6 @@
 use btclib::crypto::PrivateKey;
+use btclib::params::Network;
+use btclib::script::Script;
 use btclib::sha256::Hash;
 use btclib::types::{
     Block, BlockHeader, Transaction, TransactionOutput,
@@ -6,8 +8,6 @@ use btclib::types::{
 use btclib::util::{MerkleRoot, Saveable};
 
 use chrono::Utc;
-use uuid::Uuid;
-
 use std::env;
 use std::process::exit;
 
@@ -20,13 +20,15 @@ fn main() {
     };
 
     let private_key = PrivateKey::new_key();
+    let params = Network::Main.params();
 
-    let transactions = vec![Transaction::new(
-        vec![],
+    let transactions = vec![Transaction::coinbase(
+        0,
         vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
-            value: btclib::INITIAL_REWARD * 10u64.pow(8),
-            pubkey: private_key.public_key(),
+            value: params.block_reward(0),
+            locking_script: Script::p2pkh(
+                &private_key.public_key(),
+            ),
         }],
     )];
 
@@ -38,7 +40,7 @@ fn main() {
             0,
             Hash::zero(),
             merkle_root,
-            btclib::MIN_TARGET,
+            params.min_target.to_compact(),
         ),
         transactions,
     );
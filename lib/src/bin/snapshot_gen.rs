@@ -0,0 +1,43 @@
+use btclib::types::Blockchain;
+use btclib::util::Saveable;
+
+use std::env;
+use std::process::exit;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let (Some(blockchain_file), Some(height), Some(path)) = (
+        args.get(1),
+        args.get(2).and_then(|height| height.parse().ok()),
+        args.get(3),
+    ) else {
+        eprintln!(
+            "Usage: snapshot_gen <blockchain_file> <height> \
+            <snapshot_file>"
+        );
+        exit(1);
+    };
+
+    let blockchain = Blockchain::load_from_file(blockchain_file)
+        .expect("Failed to load blockchain");
+
+    let Some(snapshot) = blockchain.export_snapshot(height)
+    else {
+        eprintln!("No snapshot at height {height}");
+        exit(1);
+    };
+    snapshot
+        .save_to_file(path)
+        .expect("Failed to save snapshot");
+
+    // what a node starting from the snapshot has to be told
+    // to expect, see AssumedUtxo
+    let summary = snapshot
+        .summary()
+        .expect("BUG: snapshot without headers");
+    println!("height: {}", summary.height);
+    println!("block hash: {}", summary.tip);
+    println!("outputs: {}", summary.count);
+    println!("total value: {}", summary.total_value);
+    println!("commitment: {}", summary.commitment);
+}
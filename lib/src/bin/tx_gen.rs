@@ -1,9 +1,9 @@
 use btclib::crypto::PrivateKey;
+use btclib::params::Network;
+use btclib::script::Script;
 use btclib::types::{Transaction, TransactionOutput};
 use btclib::util::Saveable;
 
-use uuid::Uuid;
-
 use std::env;
 use std::process::exit;
 
@@ -16,13 +16,15 @@ fn main() {
     };
 
     let private_key = PrivateKey::new_key();
+    let params = Network::Main.params();
 
     let transaction = Transaction::new(
         vec![],
         vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
-            value: btclib::INITIAL_REWARD * 10u64.pow(8),
-            pubkey: private_key.public_key(),
+            value: params.block_reward(0),
+            locking_script: Script::p2pkh(
+                &private_key.public_key(),
+            ),
         }],
     );
 
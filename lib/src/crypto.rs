@@ -6,39 +6,178 @@ use k256::Secp256k1;
 use serde::{Deserialize, Serialize};
 use spki::EncodePublicKey;
 
+use std::collections::{HashSet, VecDeque};
 use std::io::{
     Error as IoError, ErrorKind as IoErrorKind, Read,
     Result as IoResult, Write,
 };
+use std::sync::{Mutex, MutexGuard, OnceLock};
 
-use crate::sha256::Hash;
+use crate::encoding::{invalid_data, Decodable, Encodable};
+use crate::error::{BtcError, Result};
+use crate::sha256::{Hash, Hasher};
 use crate::util::Saveable;
 
+// maximum number of valid signatures remembered by the
+// signature cache
+pub const SIGNATURE_CACHE_SIZE: usize = 100_000;
+// number of independently locked parts of the signature
+// cache, so that the threads of crate::script::verify_all
+// rarely wait for each other
+const SIGNATURE_CACHE_SHARDS: usize = 16;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Signature(ECDSASignature<Secp256k1>);
 
 impl Signature {
-    // sign a crate::types::TransactionOutput from its Sha256 hash
-    pub fn sign_output(
-        output_hash: &Hash,
+    // sign a transaction input from the transaction's
+    // sighash, see crate::types::Transaction::sighash
+    pub fn sign(
+        sighash: &Hash,
         private_key: &PrivateKey,
     ) -> Self {
         let signing_key = &private_key.0;
-        let signature =
-            signing_key.sign(&output_hash.as_bytes());
+        let signature = signing_key.sign(&sighash.as_bytes());
         Signature(signature)
     }
 
     // verify a signature
     pub fn verify(
         &self,
-        output_hash: &Hash,
+        sighash: &Hash,
         public_key: &PublicKey,
     ) -> bool {
-        public_key
-            .0
-            .verify(&output_hash.as_bytes(), &self.0)
-            .is_ok()
+        public_key.0.verify(&sighash.as_bytes(), &self.0).is_ok()
+    }
+
+    // verify a signature, skipping the work if it has been
+    // found valid before, e.g. when its transaction entered
+    // the mempool
+    pub fn verify_cached(
+        &self,
+        sighash: &Hash,
+        public_key: &PublicKey,
+    ) -> bool {
+        let cache = SignatureCache::global();
+        let key = SignatureCache::key(self, sighash, public_key);
+        if cache.contains(&key) {
+            return true;
+        }
+
+        let valid = self.verify(sighash, public_key);
+        if valid {
+            cache.insert(key);
+        }
+        valid
+    }
+
+    // fixed-size 64 byte encoding, used in scripts
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes().to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        ECDSASignature::from_slice(bytes)
+            .map(Signature)
+            .map_err(|_| BtcError::InvalidSignature)
+    }
+}
+
+// keys of a part of the signature cache, in a set and in
+// the order they were inserted
+type CacheShard = (HashSet<Hash>, VecDeque<Hash>);
+
+/// Bounded set of signature checks which succeeded, shared by
+/// mempool acceptance and block validation. Only valid
+/// signatures are remembered, the oldest are forgotten first.
+/// Keys are spread over shards with a lock each
+pub struct SignatureCache {
+    // capacity of every shard
+    shard_capacity: usize,
+    shards: Vec<Mutex<CacheShard>>,
+}
+
+impl SignatureCache {
+    pub fn new(capacity: usize) -> Self {
+        SignatureCache {
+            shard_capacity: capacity
+                .div_ceil(SIGNATURE_CACHE_SHARDS),
+            shards: (0..SIGNATURE_CACHE_SHARDS)
+                .map(|_| {
+                    Mutex::new((HashSet::new(), VecDeque::new()))
+                })
+                .collect(),
+        }
+    }
+
+    // the cache used by Signature::verify_cached
+    pub fn global() -> &'static SignatureCache {
+        static CACHE: OnceLock<SignatureCache> = OnceLock::new();
+        CACHE.get_or_init(|| {
+            SignatureCache::new(SIGNATURE_CACHE_SIZE)
+        })
+    }
+
+    // a signature check is identified by everything it
+    // depends on
+    pub fn key(
+        signature: &Signature,
+        sighash: &Hash,
+        public_key: &PublicKey,
+    ) -> Hash {
+        let mut hasher = Hasher::new();
+        hasher.update(&sighash.as_bytes());
+        hasher.update(&public_key.to_bytes());
+        hasher.update(&signature.to_bytes());
+        hasher.finalize()
+    }
+
+    pub fn contains(&self, key: &Hash) -> bool {
+        self.lock(key).0.contains(key)
+    }
+
+    pub fn insert(&self, key: Hash) {
+        let mut shard = self.lock(&key);
+        let (set, order) = &mut *shard;
+        if !set.insert(key) {
+            return;
+        }
+
+        order.push_back(key);
+        while order.len() > self.shard_capacity {
+            if let Some(oldest) = order.pop_front() {
+                set.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        (0..self.shards.len())
+            .map(|index| self.lock_shard(index).1.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // lock the shard of a key. The keys are hashes, so their
+    // first byte spreads them evenly
+    fn lock(&self, key: &Hash) -> MutexGuard<'_, CacheShard> {
+        self.lock_shard(
+            key.as_bytes()[0] as usize % self.shards.len(),
+        )
+    }
+
+    // the cache only ever holds valid signatures, so it stays
+    // usable even if a thread panicked while holding the lock
+    fn lock_shard(
+        &self,
+        index: usize,
+    ) -> MutexGuard<'_, CacheShard> {
+        self.shards[index]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
     }
 }
 
@@ -54,6 +193,19 @@ impl Signature {
 )]
 pub struct PublicKey(VerifyingKey<Secp256k1>);
 
+impl PublicKey {
+    // compressed SEC1 encoding, used in scripts
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_encoded_point(true).as_bytes().to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        VerifyingKey::from_sec1_bytes(bytes)
+            .map(PublicKey)
+            .map_err(|_| BtcError::InvalidPublicKey)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PrivateKey(
     #[serde(with = "signkey_serde")] pub SigningKey<Secp256k1>,
@@ -69,6 +221,22 @@ impl PrivateKey {
     }
 }
 
+// 33 bytes compressed SEC1
+impl Encodable for PublicKey {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(&self.to_bytes())
+    }
+}
+
+impl Decodable for PublicKey {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        let mut bytes = [0u8; 33];
+        reader.read_exact(&mut bytes)?;
+        PublicKey::from_bytes(&bytes)
+            .map_err(|_| invalid_data("invalid public key"))
+    }
+}
+
 impl Saveable for PrivateKey {
     fn load<I: Read>(reader: I) -> IoResult<Self> {
         ciborium::de::from_reader(reader).map_err(|_| {
@@ -0,0 +1,272 @@
+// Difficulty adjustment: how the target of the next block is
+// derived from the blocks before it. All algorithms work in
+// exact integer arithmetic on U256, so that every node
+// computes the very same target
+use chrono::{DateTime, Duration, Utc};
+
+use std::fmt;
+
+use crate::params::ChainParams;
+use crate::types::BlockHeader;
+use crate::U256;
+
+pub trait DifficultyAlgorithm: fmt::Debug + Send + Sync {
+    // number of most recent blocks next_target looks at
+    fn window(&self, params: &ChainParams) -> usize;
+
+    // target of the block at the given height. The headers
+    // are the last blocks before it, oldest first: as many
+    // as window() asks for, or all of them early in the
+    // chain. The result is clamped to params.min_target by
+    // the caller
+    fn next_target(
+        &self,
+        height: u64,
+        headers: &[BlockHeader],
+        params: &ChainParams,
+    ) -> U256;
+
+    // whether the block at the given height may have the
+    // given timestamp, on top of the median time past and
+    // future time rules that hold for all blocks. The
+    // headers are the same as for next_target
+    fn check_timestamp(
+        &self,
+        _height: u64,
+        _headers: &[BlockHeader],
+        _timestamp: DateTime<Utc>,
+        _params: &ChainParams,
+    ) -> bool {
+        true
+    }
+}
+
+/// Bitcoin's retarget: once every
+/// params.difficulty_update_interval blocks, scale the target
+/// by how long the last interval took compared to the ideal
+/// time, by at most 4x in either direction.
+///
+/// Unlike Bitcoin, the interval is measured from the last
+/// block of the previous period, so that every solve time
+/// counts exactly once, and the first block of a period
+/// cannot be much older than the last block of the previous
+/// one (as in BIP94). Together, they close the timewarp
+/// attack of lowering the difficulty with fake timestamps
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClassicRetarget;
+
+impl DifficultyAlgorithm for ClassicRetarget {
+    fn window(&self, params: &ChainParams) -> usize {
+        // the interval and the block before it
+        params.difficulty_update_interval as usize + 1
+    }
+
+    fn next_target(
+        &self,
+        height: u64,
+        headers: &[BlockHeader],
+        params: &ChainParams,
+    ) -> U256 {
+        let interval = params.difficulty_update_interval;
+        let Some(last) = headers.last() else {
+            return params.min_target;
+        };
+
+        // the target only changes at the interval boundaries
+        if !height.is_multiple_of(interval) || headers.len() < 2
+        {
+            return last.target();
+        }
+
+        // measure the time it took to mine the interval. The
+        // first interval has no block before it, so it is one
+        // solve time short
+        let solve_times =
+            (headers.len() as u64 - 1).min(interval);
+        let first =
+            &headers[headers.len() - 1 - solve_times as usize];
+        let time_diff =
+            (last.timestamp - first.timestamp).num_seconds();
+
+        // clamped to within 4x of the ideal time so that the
+        // target changes by at most 4x
+        let target_seconds =
+            params.ideal_block_time * solve_times;
+        let time_diff = time_diff.clamp(
+            target_seconds as i64 / 4,
+            target_seconds as i64 * 4,
+        ) as u64;
+
+        mul_div(last.target(), time_diff, target_seconds)
+            .unwrap_or(params.min_target)
+    }
+
+    fn check_timestamp(
+        &self,
+        height: u64,
+        headers: &[BlockHeader],
+        timestamp: DateTime<Utc>,
+        params: &ChainParams,
+    ) -> bool {
+        let Some(last) = headers.last() else {
+            return true;
+        };
+
+        // only the first block of a period is constrained,
+        // it could otherwise stretch the previous period by
+        // going back in time
+        !height.is_multiple_of(params.difficulty_update_interval)
+            || timestamp
+                >= last.timestamp
+                    - Duration::seconds(
+                        params.max_timewarp as i64,
+                    )
+    }
+}
+
+/// Linearly weighted moving average (zawy's LWMA-1): every
+/// block, scale the average target of the window by the
+/// solve times of its blocks, weighing recent ones more
+#[derive(Clone, Copy, Debug)]
+pub struct Lwma {
+    /// Number of solve times to average over
+    pub window: u64,
+}
+
+impl DifficultyAlgorithm for Lwma {
+    fn window(&self, _params: &ChainParams) -> usize {
+        // one more block for the first solve time
+        self.window as usize + 1
+    }
+
+    fn next_target(
+        &self,
+        _height: u64,
+        headers: &[BlockHeader],
+        params: &ChainParams,
+    ) -> U256 {
+        let n = self.window;
+        if n == 0 || (headers.len() as u64) < n + 1 {
+            return params.min_target;
+        }
+
+        let ideal = params.ideal_block_time as i64;
+        let headers = &headers[headers.len() - n as usize - 1..];
+
+        let mut previous = headers[0].timestamp.timestamp();
+        let mut weighted_solve_times = 0u64;
+        let mut average_target = U256::zero();
+        for (weight, header) in (1..).zip(&headers[1..]) {
+            // timestamps are forced to increase, and solve
+            // times are capped, so that a single block cannot
+            // swing the target
+            let timestamp =
+                header.timestamp.timestamp().max(previous + 1);
+            let solve_time =
+                (timestamp - previous).min(6 * ideal);
+            previous = timestamp;
+
+            weighted_solve_times += weight * solve_time as u64;
+            average_target += header.target() / n;
+        }
+
+        // the weighted solve times of a window mined at the
+        // ideal pace
+        let ideal_weighted = n * (n + 1) / 2 * ideal as u64;
+
+        mul_div(
+            average_target,
+            weighted_solve_times,
+            ideal_weighted,
+        )
+        .unwrap_or(params.min_target)
+    }
+}
+
+/// Absolutely scheduled exponentially rising targets
+/// (aserti3-2d, as used by Bitcoin Cash): every block, the
+/// target is the genesis target scaled by 2^(x / half_life),
+/// where x is how far the chain is ahead of or behind the
+/// schedule of the genesis block, in seconds
+#[derive(Clone, Copy, Debug)]
+pub struct Asert {
+    /// Seconds the chain has to fall behind schedule for the
+    /// target to double
+    pub half_life: u64,
+}
+
+impl DifficultyAlgorithm for Asert {
+    fn window(&self, _params: &ChainParams) -> usize {
+        1
+    }
+
+    fn next_target(
+        &self,
+        height: u64,
+        headers: &[BlockHeader],
+        params: &ChainParams,
+    ) -> U256 {
+        let Some(parent) = headers.last() else {
+            return params.min_target;
+        };
+        let anchor = &params.genesis_block.header;
+
+        // parent is at height - 1, ideally ideal_block_time
+        // seconds apart from the anchor per block
+        let time_delta = (parent.timestamp - anchor.timestamp)
+            .num_seconds() as i128;
+        let ideal_delta = params.ideal_block_time as i128
+            * height.saturating_sub(1) as i128;
+
+        // 2^(exponent / 2^16), split in whole shifts and a
+        // 16 bit fraction
+        let exponent = (time_delta - ideal_delta) * 65536
+            / self.half_life.max(1) as i128;
+        let shifts = exponent >> 16;
+        let fraction = (exponent & 0xFFFF) as u64;
+
+        // cubic approximation of 2^fraction * 2^16, good to
+        // within 0.013%
+        let factor = 65536
+            + ((195_766_423_245_049 * fraction as u128
+                + 971_821_376 * (fraction * fraction) as u128
+                + 5127
+                    * (fraction * fraction * fraction) as u128
+                + (1 << 47))
+                >> 48) as u64;
+
+        let Some(target) =
+            mul_div(anchor.target(), factor, 65536)
+        else {
+            return params.min_target;
+        };
+
+        let target = if shifts < 0 {
+            target >> (-shifts).min(255) as usize
+        } else if target.bits() as i128 + shifts > 256 {
+            return params.min_target;
+        } else {
+            target << shifts as usize
+        };
+
+        // the target never becomes impossible to meet
+        target.max(U256::one())
+    }
+}
+
+// value * numerator / denominator rounded down, None if the
+// result does not fit in 256 bits
+fn mul_div(
+    value: U256,
+    numerator: u64,
+    denominator: u64,
+) -> Option<U256> {
+    let numerator = U256::from(numerator);
+    let denominator = U256::from(denominator);
+    let quotient = value / denominator;
+    let remainder = value % denominator;
+
+    // the remainder is below 2^64, so this cannot overflow
+    let rest = remainder * numerator / denominator;
+    quotient.checked_mul(numerator)?.checked_add(rest)
+}
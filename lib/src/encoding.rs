@@ -0,0 +1,360 @@
+// Canonical binary encoding of consensus data, used for
+// hashing, the wire protocol and saving blocks and
+// transactions. Unlike the serde derives, the byte layout is
+// fixed and does not depend on field order or on the
+// serialization format:
+//
+// - integers are fixed width little-endian
+// - bools are a single byte, 0 or 1
+// - lengths are Bitcoin's CompactSize: values below 0xFD are
+//   a single byte, larger values are 0xFD, 0xFE or 0xFF
+//   followed by a 2, 4 or 8 byte integer. Only the shortest
+//   form is accepted
+// - sequences and byte strings are their length followed by
+//   their items
+// - 256-bit values and hashes are 32 bytes little-endian
+// - timestamps are i64 unix seconds followed by u32
+//   nanoseconds
+//
+// Compound types encode their fields one after another, in
+// the order documented on their implementations
+use chrono::{DateTime, Utc};
+
+use std::io::{
+    Error as IoError, ErrorKind as IoErrorKind, Read,
+    Result as IoResult, Write,
+};
+
+use crate::U256;
+
+// decoded sequences are allocated in chunks of at most this
+// many items, so that a bogus length cannot exhaust memory
+const MAX_PREALLOCATION: usize = 1024;
+
+pub trait Encodable {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()>;
+
+    fn encode_to_vec(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.encode(&mut bytes)
+            .expect("BUG: writing to a Vec cannot fail");
+        bytes
+    }
+
+    // number of bytes of the encoding, without keeping the
+    // encoded bytes around
+    fn encoded_size(&self) -> usize {
+        let mut counter = ByteCounter(0);
+        self.encode(&mut counter)
+            .expect("BUG: counting bytes cannot fail");
+        counter.0
+    }
+}
+
+pub trait Decodable
+where
+    Self: Sized,
+{
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self>;
+
+    // decode a value which has to take up all of the bytes
+    fn decode_from_slice(mut bytes: &[u8]) -> IoResult<Self> {
+        let value = Self::decode(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(invalid_data("trailing bytes"));
+        }
+        Ok(value)
+    }
+}
+
+pub(crate) fn invalid_data(message: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, message.to_owned())
+}
+
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+fn read_array<R: Read, const N: usize>(
+    reader: &mut R,
+) -> IoResult<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+macro_rules! impl_int {
+    ($($int:ty),*) => {
+        $(
+            impl Encodable for $int {
+                fn encode<W: Write>(
+                    &self,
+                    writer: &mut W,
+                ) -> IoResult<()> {
+                    writer.write_all(&self.to_le_bytes())
+                }
+            }
+
+            impl Decodable for $int {
+                fn decode<R: Read>(
+                    reader: &mut R,
+                ) -> IoResult<Self> {
+                    Ok(<$int>::from_le_bytes(read_array(reader)?))
+                }
+            }
+        )*
+    };
+}
+
+impl_int!(u8, u16, u32, u64, i64);
+
+impl Encodable for bool {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        (*self as u8).encode(writer)
+    }
+}
+
+impl Decodable for bool {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        match u8::decode(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data("invalid bool")),
+        }
+    }
+}
+
+/// A length or count in Bitcoin's CompactSize encoding
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactSize(pub u64);
+
+impl Encodable for CompactSize {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        match self.0 {
+            0..=0xFC => (self.0 as u8).encode(writer),
+            0xFD..=0xFFFF => {
+                0xFDu8.encode(writer)?;
+                (self.0 as u16).encode(writer)
+            }
+            0x1_0000..=0xFFFF_FFFF => {
+                0xFEu8.encode(writer)?;
+                (self.0 as u32).encode(writer)
+            }
+            _ => {
+                0xFFu8.encode(writer)?;
+                self.0.encode(writer)
+            }
+        }
+    }
+}
+
+impl Decodable for CompactSize {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        let (value, min) = match u8::decode(reader)? {
+            0xFD => (u16::decode(reader)? as u64, 0xFD),
+            0xFE => (u32::decode(reader)? as u64, 0x1_0000),
+            0xFF => (u64::decode(reader)?, 0x1_0000_0000),
+            byte => (byte as u64, 0),
+        };
+
+        // every value has exactly one valid encoding
+        if value < min {
+            return Err(invalid_data("non-canonical length"));
+        }
+
+        Ok(CompactSize(value))
+    }
+}
+
+// decode a length, which has to fit in memory
+fn decode_len<R: Read>(reader: &mut R) -> IoResult<usize> {
+    usize::try_from(CompactSize::decode(reader)?.0)
+        .map_err(|_| invalid_data("length too large"))
+}
+
+impl Encodable for usize {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        (*self as u64).encode(writer)
+    }
+}
+
+impl Decodable for usize {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        usize::try_from(u64::decode(reader)?)
+            .map_err(|_| invalid_data("value too large"))
+    }
+}
+
+impl<T: Encodable> Encodable for Vec<T> {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.as_slice().encode(writer)
+    }
+}
+
+impl<T: Encodable> Encodable for [T] {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        CompactSize(self.len() as u64).encode(writer)?;
+        for item in self {
+            item.encode(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Decodable> Decodable for Vec<T> {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        let len = decode_len(reader)?;
+        let mut items =
+            Vec::with_capacity(len.min(MAX_PREALLOCATION));
+        for _ in 0..len {
+            items.push(T::decode(reader)?);
+        }
+        Ok(items)
+    }
+}
+
+// fixed size arrays have no length prefix
+impl<T: Encodable, const N: usize> Encodable for [T; N] {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        for item in self {
+            item.encode(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl<A: Encodable, B: Encodable> Encodable for (A, B) {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.0.encode(writer)?;
+        self.1.encode(writer)
+    }
+}
+
+impl<A: Decodable, B: Decodable> Decodable for (A, B) {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok((A::decode(reader)?, B::decode(reader)?))
+    }
+}
+
+// a bool telling whether there is a value, followed by the
+// value if there is
+impl<T: Encodable> Encodable for Option<T> {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.is_some().encode(writer)?;
+        match self {
+            Some(value) => value.encode(writer),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: Decodable> Decodable for Option<T> {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        if bool::decode(reader)? {
+            Ok(Some(T::decode(reader)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T: Encodable + ?Sized> Encodable for &T {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        (**self).encode(writer)
+    }
+}
+
+// byte strings are their length followed by the bytes, the
+// same as a Vec<u8> but without going byte by byte
+pub fn encode_bytes<W: Write>(
+    bytes: &[u8],
+    writer: &mut W,
+) -> IoResult<()> {
+    CompactSize(bytes.len() as u64).encode(writer)?;
+    writer.write_all(bytes)
+}
+
+// decode a byte string, see encode_bytes
+pub fn decode_bytes<R: Read>(
+    reader: &mut R,
+) -> IoResult<Vec<u8>> {
+    decode_bytes_max(reader, usize::MAX)
+}
+
+// a byte string of at most the given length, which is
+// checked before reading any of it
+pub fn decode_bytes_max<R: Read>(
+    reader: &mut R,
+    max_len: usize,
+) -> IoResult<Vec<u8>> {
+    let len = decode_len(reader)?;
+    if len > max_len {
+        return Err(invalid_data("byte string too long"));
+    }
+    let mut bytes = vec![];
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(invalid_data("truncated byte string"));
+    }
+    Ok(bytes)
+}
+
+impl Encodable for String {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        encode_bytes(self.as_bytes(), writer)
+    }
+}
+
+impl Decodable for String {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        String::from_utf8(decode_bytes(reader)?)
+            .map_err(|_| invalid_data("invalid UTF-8"))
+    }
+}
+
+impl Encodable for U256 {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        let mut bytes = [0u8; 32];
+        self.to_little_endian(&mut bytes);
+        writer.write_all(&bytes)
+    }
+}
+
+impl Decodable for U256 {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        let bytes: [u8; 32] = read_array(reader)?;
+        Ok(U256::from_little_endian(&bytes))
+    }
+}
+
+impl Encodable for DateTime<Utc> {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.timestamp().encode(writer)?;
+        self.timestamp_subsec_nanos().encode(writer)
+    }
+}
+
+impl Decodable for DateTime<Utc> {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        let seconds = i64::decode(reader)?;
+        let nanos = u32::decode(reader)?;
+
+        // chrono uses larger values for leap seconds, which
+        // would give a second encoding of the same time
+        if nanos >= 1_000_000_000 {
+            return Err(invalid_data("invalid timestamp"));
+        }
+
+        DateTime::from_timestamp(seconds, nanos)
+            .ok_or_else(|| invalid_data("invalid timestamp"))
+    }
+}
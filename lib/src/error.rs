@@ -1,5 +1,11 @@
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use std::fmt;
+use std::io::{Read, Result as IoResult, Write};
+
+use crate::encoding::{invalid_data, Decodable, Encodable};
+
 #[derive(Error, Debug)]
 pub enum BtcError {
     #[error("Invalid transaction")]
@@ -18,10 +24,263 @@ pub enum BtcError {
     InvalidHash,
     #[error("Invalid signature")]
     InvalidSignature,
+    #[error("Invalid script")]
+    InvalidScript,
     #[error("Invalid public key")]
     InvalidPublicKey,
     #[error("Invalid private key")]
     InvalidPrivateKey,
+    #[error("Invalid network")]
+    InvalidNetwork,
+    #[error("Invalid amount")]
+    InvalidAmount,
+    #[error("Invalid UTXO snapshot")]
+    InvalidSnapshot,
+    #[error("Rejected: {0}")]
+    Rejected(RejectReason),
+}
+
+impl BtcError {
+    // the reason to report to whoever sent the rejected block
+    // or transaction
+    pub fn reject_reason(&self) -> RejectReason {
+        match self {
+            BtcError::Rejected(reason) => *reason,
+            BtcError::InvalidMerkleRoot => {
+                RejectReason::BadMerkleRoot
+            }
+            BtcError::InvalidSignature => {
+                RejectReason::BadSignature
+            }
+            BtcError::InvalidScript => RejectReason::BadScript,
+            _ => RejectReason::Invalid,
+        }
+    }
+}
+
+impl From<RejectReason> for BtcError {
+    fn from(reason: RejectReason) -> Self {
+        BtcError::Rejected(reason)
+    }
+}
+
+/// Why a block or transaction was rejected. The reasons are
+/// displayed with the same codes as Bitcoin Core uses
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq,
+)]
+pub enum RejectReason {
+    /// Invalid for a reason not covered by the others
+    Invalid,
+
+    /// The block is already known
+    DuplicateBlock,
+    /// The block's parent is not known
+    PrevBlockNotFound,
+    /// The block does not build on the block it was checked
+    /// against
+    BadPrevBlock,
+    /// The block is larger than crate::MAX_BLOCK_SIZE
+    BlockTooLarge,
+    /// The block's hash is above its target
+    HighHash,
+    /// The block's target is not the one required
+    BadDifficultyBits,
+    /// The merkle root does not match the transactions
+    BadMerkleRoot,
+    /// The timestamp is not after the median time past
+    TimeTooOld,
+    /// The timestamp is too far in the future
+    TimeTooNew,
+    /// The block is not the network's genesis block
+    BadGenesis,
+    /// The block has no transactions at all
+    NoTransactions,
+    /// The first transaction is not a coinbase
+    MissingCoinbase,
+    /// A transaction other than the first is a coinbase
+    MultipleCoinbases,
+    /// The coinbase does not commit to the block height
+    BadCoinbaseHeight,
+    /// The coinbase pays more or less than reward and fees
+    BadCoinbaseAmount,
+    /// The block contains the same transaction twice
+    DuplicateTransaction,
+
+    /// The transaction has no inputs
+    NoInputs,
+    /// The transaction has no outputs
+    NoOutputs,
+    /// An input is neither in the UTXO set nor in the block
+    MissingInput,
+    /// An output is spent twice
+    DuplicateInput,
+    /// A coinbase output is spent before it is mature
+    PrematureCoinbaseSpend,
+    /// The outputs are worth more than the money supply
+    OutputValueOutOfRange,
+    /// The inputs are worth more than the money supply
+    InputValueOutOfRange,
+    /// The outputs are worth more than the inputs
+    InputsBelowOutputs,
+    /// The transaction's lock time has not passed
+    NonFinal,
+    /// The relative lock time of an input has not passed
+    NonFinalSequence,
+    /// The transaction is too large to ever fit in a block
+    TransactionTooLarge,
+    /// Coinbase transactions only come in blocks
+    Coinbase,
+    /// A signature does not match
+    BadSignature,
+    /// An unlocking script does not satisfy its locking
+    /// script
+    BadScript,
+
+    /// The timestamp goes back too far at the start of a
+    /// retarget period
+    TimeWarp,
+
+    /// The requested block is not invalid, but the node only
+    /// has its header
+    NotAvailable,
+
+    /// A script pushes more than
+    /// crate::script::MAX_SCRIPT_ELEMENT_SIZE bytes
+    PushTooLarge,
+}
+
+impl RejectReason {
+    // every reason, in the order of declaration
+    pub const ALL: [RejectReason; 34] = [
+        RejectReason::Invalid,
+        RejectReason::DuplicateBlock,
+        RejectReason::PrevBlockNotFound,
+        RejectReason::BadPrevBlock,
+        RejectReason::BlockTooLarge,
+        RejectReason::HighHash,
+        RejectReason::BadDifficultyBits,
+        RejectReason::BadMerkleRoot,
+        RejectReason::TimeTooOld,
+        RejectReason::TimeTooNew,
+        RejectReason::BadGenesis,
+        RejectReason::NoTransactions,
+        RejectReason::MissingCoinbase,
+        RejectReason::MultipleCoinbases,
+        RejectReason::BadCoinbaseHeight,
+        RejectReason::BadCoinbaseAmount,
+        RejectReason::DuplicateTransaction,
+        RejectReason::NoInputs,
+        RejectReason::NoOutputs,
+        RejectReason::MissingInput,
+        RejectReason::DuplicateInput,
+        RejectReason::PrematureCoinbaseSpend,
+        RejectReason::OutputValueOutOfRange,
+        RejectReason::InputValueOutOfRange,
+        RejectReason::InputsBelowOutputs,
+        RejectReason::NonFinal,
+        RejectReason::NonFinalSequence,
+        RejectReason::TransactionTooLarge,
+        RejectReason::Coinbase,
+        RejectReason::BadSignature,
+        RejectReason::BadScript,
+        RejectReason::TimeWarp,
+        RejectReason::NotAvailable,
+        RejectReason::PushTooLarge,
+    ];
+
+    // the reason displayed with the given code
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|reason| reason.code() == code)
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            RejectReason::Invalid => "invalid",
+            RejectReason::DuplicateBlock => "duplicate",
+            RejectReason::PrevBlockNotFound => {
+                "prev-blk-not-found"
+            }
+            RejectReason::BadPrevBlock => "bad-prevblk",
+            RejectReason::BlockTooLarge => "bad-blk-length",
+            RejectReason::HighHash => "high-hash",
+            RejectReason::BadDifficultyBits => "bad-diffbits",
+            RejectReason::BadMerkleRoot => "bad-txnmrklroot",
+            RejectReason::TimeTooOld => "time-too-old",
+            RejectReason::TimeTooNew => "time-too-new",
+            RejectReason::BadGenesis => "bad-genesis",
+            RejectReason::NoTransactions => "bad-blk-empty",
+            RejectReason::MissingCoinbase => "bad-cb-missing",
+            RejectReason::MultipleCoinbases => "bad-cb-multiple",
+            RejectReason::BadCoinbaseHeight => "bad-cb-height",
+            RejectReason::BadCoinbaseAmount => "bad-cb-amount",
+            RejectReason::DuplicateTransaction => {
+                "bad-txns-duplicate"
+            }
+            RejectReason::NoInputs => "bad-txns-vin-empty",
+            RejectReason::NoOutputs => "bad-txns-vout-empty",
+            RejectReason::MissingInput => {
+                "bad-txns-inputs-missingorspent"
+            }
+            RejectReason::DuplicateInput => {
+                "bad-txns-inputs-duplicate"
+            }
+            RejectReason::PrematureCoinbaseSpend => {
+                "bad-txns-premature-spend-of-coinbase"
+            }
+            RejectReason::OutputValueOutOfRange => {
+                "bad-txns-txouttotal-toolarge"
+            }
+            RejectReason::InputValueOutOfRange => {
+                "bad-txns-inputvalues-outofrange"
+            }
+            RejectReason::InputsBelowOutputs => {
+                "bad-txns-in-belowout"
+            }
+            RejectReason::NonFinal => "bad-txns-nonfinal",
+            RejectReason::NonFinalSequence => "non-BIP68-final",
+            RejectReason::TransactionTooLarge => {
+                "bad-txns-oversize"
+            }
+            RejectReason::Coinbase => "coinbase",
+            RejectReason::BadSignature => "bad-signature",
+            RejectReason::BadScript => "bad-script",
+            RejectReason::TimeWarp => "time-timewarp-attack",
+            RejectReason::NotAvailable => "notfound",
+            RejectReason::PushTooLarge => "push-size",
+        }
+    }
+}
+
+// a reason added to the enum has to be added to ALL too
+const _: () = assert!(
+    RejectReason::ALL.len()
+        == RejectReason::PushTooLarge as usize + 1
+);
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+// a single byte, the position of the reason in the
+// declaration
+impl Encodable for RejectReason {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        (*self as u8).encode(writer)
+    }
+}
+
+impl Decodable for RejectReason {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        RejectReason::ALL
+            .get(u8::decode(reader)? as usize)
+            .copied()
+            .ok_or_else(|| invalid_data("unknown reject reason"))
+    }
 }
 
 pub type Result<T> = std::result::Result<T, BtcError>;
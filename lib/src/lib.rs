@@ -8,29 +8,91 @@ construct_uint! {
    pub struct U256(4);
 }
 
-// initial reward in bitcoin - multiply by 10^8 to get satoshis
-pub const INITIAL_REWARD: u64 = 50;
-// halving interval in blocks
-pub const HALVING_INTERVAL: u64 = 210;
-// ideal block time in seconds
-pub const IDEAL_BLOCK_TIME: u64 = 10;
-// minimum target
-pub const MIN_TARGET: U256 = U256([
-    0xFFFF_FFFF_FFFF_FFFF,
-    0xFFFF_FFFF_FFFF_FFFF,
-    0xFFFF_FFFF_FFFF_FFFF,
-    0x0000_FFFF_FFFF_FFFF,
-]);
-// difficulty update interval in blocks
-pub const DIFFICULTY_UPDATE_INTERVAL: u64 = 50;
+impl U256 {
+    // decode a target from its compact representation: the
+    // highest byte is the length of the target in bytes, the
+    // lower three bytes are its most significant bytes.
+    // Negative and overflowing encodings are invalid
+    pub fn from_compact(bits: u32) -> Option<U256> {
+        let size = (bits >> 24) as usize;
+        let mantissa = bits & 0x007F_FFFF;
+
+        if mantissa == 0 {
+            return Some(U256::zero());
+        }
+
+        // the sign bit, we have no use for negative targets
+        if bits & 0x0080_0000 != 0 {
+            return None;
+        }
+
+        if size <= 3 {
+            return Some(U256::from(
+                mantissa >> (8 * (3 - size)),
+            ));
+        }
+
+        let mantissa = U256::from(mantissa);
+        let shift = 8 * (size - 3);
+        if mantissa.bits() + shift > 256 {
+            return None;
+        }
+
+        Some(mantissa << shift)
+    }
+
+    // encode a target in its compact representation,
+    // rounding it down to three significant bytes
+    pub fn to_compact(&self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut mantissa = if size <= 3 {
+            (self.low_u64() << (8 * (3 - size))) as u32
+        } else {
+            (*self >> (8 * (size - 3))).low_u64() as u32
+        };
+
+        // keep the sign bit clear by moving the mantissa one
+        // byte down
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+
+        mantissa | (size as u32) << 24
+    }
+}
+
 // maximum mempool transaction age in seconds
 pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
-// maximum amount of transactions allowed in a block
-pub const BLOCK_TRANSACTION_CAP: usize = 20;
+// maximum serialized size of a block in bytes
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+// maximum number of headers sent in a single Headers
+// message
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+// number of blocks whose median timestamp a new block's
+// timestamp has to exceed
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// lock times below this value are block heights,
+// lock times above it are unix timestamps
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
+// inputs with this sequence bit set have no relative lock
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+// relative locks with this sequence bit set are measured in
+// time, otherwise in blocks
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+// sequence bits holding the relative lock
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_FFFF;
+// relative time locks are in units of 2^9 = 512 seconds
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
 
+pub mod amount;
 pub mod crypto;
+pub mod difficulty;
+pub mod encoding;
 pub mod error;
 pub mod network;
+pub mod params;
+pub mod script;
 pub mod sha256;
 pub mod types;
 pub mod util;
@@ -1,19 +1,30 @@
 use serde::{Deserialize, Serialize};
 
-use std::io::{Error as IoError, Read, Write};
+use std::io::{
+    Error as IoError, ErrorKind as IoErrorKind, Read,
+    Result as IoResult, Write,
+};
 use tokio::io::{
     AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
 };
 
 use crate::crypto::PublicKey;
-use crate::types::{Block, Transaction, TransactionOutput};
+use crate::encoding::{invalid_data, Decodable, Encodable};
+use crate::error::RejectReason;
+use crate::params::Network;
+use crate::sha256::Hash;
+use crate::types::{
+    Block, BlockHeader, HistoryEntry, OutPoint, Transaction,
+    TransactionOutput, TxLocation, UtxoSetSummary,
+};
+use crate::U256;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Message {
     /// Fetch all UTXOs belonging to a public key
     FetchUTXOs(PublicKey),
     /// UTXOs belonging to a public key
-    UTXOs(Vec<(TransactionOutput, bool)>),
+    UTXOs(Vec<UtxoInfo>),
     /// Send a transaction to the network
     SubmitTransaction(Transaction),
     /// Broadcast a new transaction to other nodes
@@ -40,41 +51,250 @@ pub enum Message {
     DiscoverNodes,
     /// This is the response to DiscoverNodes
     NodeList(Vec<String>),
-    /// Ask a node whats the highest block it knows about
-    /// in comparison to the local blockchain
-    AskDifference(u32),
-    /// This is the response to AskDifference
-    Difference(i32),
+    /// Ask a node how much more work its blockchain has
+    /// than the local one, given the local chainwork
+    AskDifference(U256),
+    /// This is the response to AskDifference, the extra work
+    /// is zero if the node's blockchain has no more work
+    Difference { extra_work: U256, height: u64 },
     /// Ask a node to send a block with the specified height
     FetchBlock(usize),
     /// Broadcast a new block to other nodes
     NewBlock(Block),
+
+    /// A submitted or broadcast block or transaction with the
+    /// given hash was rejected, or a fetched block is not
+    /// available
+    Reject { hash: Hash, reason: RejectReason },
+    /// A submitted block or transaction with the given hash
+    /// was accepted
+    Accepted(Hash),
+
+    /// Look up a transaction of the active chain by its id
+    FetchTransaction(Hash),
+    /// This is the response to FetchTransaction, None if the
+    /// transaction is unknown or the node keeps no
+    /// transaction index
+    IndexedTransaction(Option<(TxLocation, Transaction)>),
+    /// Fetch all outputs ever paid to a public key, spent or
+    /// not
+    FetchHistory(PublicKey),
+    /// This is the response to FetchHistory, oldest output
+    /// first, None if the node keeps no address index
+    History(Option<Vec<HistoryEntry>>),
+
+    /// Ask a node for the headers of its active chain after
+    /// the first of the given block hashes it knows, see
+    /// HeaderChain::locator
+    GetHeaders(Vec<Hash>),
+    /// This is the response to GetHeaders, at most
+    /// crate::MAX_HEADERS_PER_MESSAGE of them
+    Headers(Vec<BlockHeader>),
+
+    /// Ask a node to summarize its UTXO set, to check that
+    /// it agrees with ours
+    FetchUtxoSetSummary,
+    /// This is the response to FetchUtxoSetSummary
+    UtxoSetSummary(UtxoSetSummary),
 }
 
-// We are going to use length-prefixed encoding for message
-// And we are going to use ciborium (CBOR) for serialization
-impl Message {
-    pub fn encode(
-        &self,
-    ) -> Result<Vec<u8>, ciborium::ser::Error<IoError>> {
-        let mut bytes = Vec::new();
-        ciborium::into_writer(self, &mut bytes)?;
+/// An unspent output as reported to wallets
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UtxoInfo {
+    pub outpoint: OutPoint,
+    pub output: TransactionOutput,
+    /// Set if a mempool transaction already spends the output
+    pub marked: bool,
+    /// Set if the output is a coinbase output which cannot be
+    /// spent yet, see ChainParams::coinbase_maturity
+    pub immature: bool,
+}
 
-        Ok(bytes)
+// outpoint, output, marked, immature
+impl Encodable for UtxoInfo {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.outpoint.encode(writer)?;
+        self.output.encode(writer)?;
+        self.marked.encode(writer)?;
+        self.immature.encode(writer)
     }
+}
 
-    pub fn decode(
-        data: &[u8],
-    ) -> Result<Self, ciborium::de::Error<IoError>> {
-        ciborium::from_reader(data)
+impl Decodable for UtxoInfo {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(UtxoInfo {
+            outpoint: OutPoint::decode(reader)?,
+            output: TransactionOutput::decode(reader)?,
+            marked: bool::decode(reader)?,
+            immature: bool::decode(reader)?,
+        })
     }
+}
 
+// a one byte tag identifying the message, followed by its
+// fields in order
+impl Encodable for Message {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        use Message::*;
+        match self {
+            FetchUTXOs(key) => {
+                0x00u8.encode(writer)?;
+                key.encode(writer)
+            }
+            UTXOs(utxos) => {
+                0x01u8.encode(writer)?;
+                utxos.encode(writer)
+            }
+            SubmitTransaction(tx) => {
+                0x02u8.encode(writer)?;
+                tx.encode(writer)
+            }
+            NewTransaction(tx) => {
+                0x03u8.encode(writer)?;
+                tx.encode(writer)
+            }
+            FetchTemplate(key) => {
+                0x04u8.encode(writer)?;
+                key.encode(writer)
+            }
+            Template(block) => {
+                0x05u8.encode(writer)?;
+                block.encode(writer)
+            }
+            ValidateTemplate(block) => {
+                0x06u8.encode(writer)?;
+                block.encode(writer)
+            }
+            TemplateValidity(valid) => {
+                0x07u8.encode(writer)?;
+                valid.encode(writer)
+            }
+            SubmitTemplate(block) => {
+                0x08u8.encode(writer)?;
+                block.encode(writer)
+            }
+            DiscoverNodes => 0x09u8.encode(writer),
+            NodeList(nodes) => {
+                0x0Au8.encode(writer)?;
+                nodes.encode(writer)
+            }
+            AskDifference(chainwork) => {
+                0x0Bu8.encode(writer)?;
+                chainwork.encode(writer)
+            }
+            Difference { extra_work, height } => {
+                0x0Cu8.encode(writer)?;
+                extra_work.encode(writer)?;
+                height.encode(writer)
+            }
+            FetchBlock(height) => {
+                0x0Du8.encode(writer)?;
+                height.encode(writer)
+            }
+            NewBlock(block) => {
+                0x0Eu8.encode(writer)?;
+                block.encode(writer)
+            }
+            Reject { hash, reason } => {
+                0x0Fu8.encode(writer)?;
+                hash.encode(writer)?;
+                reason.encode(writer)
+            }
+            Accepted(hash) => {
+                0x10u8.encode(writer)?;
+                hash.encode(writer)
+            }
+            FetchTransaction(txid) => {
+                0x11u8.encode(writer)?;
+                txid.encode(writer)
+            }
+            IndexedTransaction(transaction) => {
+                0x12u8.encode(writer)?;
+                transaction.encode(writer)
+            }
+            FetchHistory(key) => {
+                0x13u8.encode(writer)?;
+                key.encode(writer)
+            }
+            History(history) => {
+                0x14u8.encode(writer)?;
+                history.encode(writer)
+            }
+            GetHeaders(locator) => {
+                0x15u8.encode(writer)?;
+                locator.encode(writer)
+            }
+            Headers(headers) => {
+                0x16u8.encode(writer)?;
+                headers.encode(writer)
+            }
+            FetchUtxoSetSummary => 0x17u8.encode(writer),
+            UtxoSetSummary(summary) => {
+                0x18u8.encode(writer)?;
+                summary.encode(writer)
+            }
+        }
+    }
+}
+
+impl Decodable for Message {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        use Message::*;
+        Ok(match u8::decode(reader)? {
+            0x00 => FetchUTXOs(Decodable::decode(reader)?),
+            0x01 => UTXOs(Decodable::decode(reader)?),
+            0x02 => {
+                SubmitTransaction(Decodable::decode(reader)?)
+            }
+            0x03 => NewTransaction(Decodable::decode(reader)?),
+            0x04 => FetchTemplate(Decodable::decode(reader)?),
+            0x05 => Template(Decodable::decode(reader)?),
+            0x06 => ValidateTemplate(Decodable::decode(reader)?),
+            0x07 => TemplateValidity(Decodable::decode(reader)?),
+            0x08 => SubmitTemplate(Decodable::decode(reader)?),
+            0x09 => DiscoverNodes,
+            0x0A => NodeList(Decodable::decode(reader)?),
+            0x0B => AskDifference(Decodable::decode(reader)?),
+            0x0C => Difference {
+                extra_work: Decodable::decode(reader)?,
+                height: Decodable::decode(reader)?,
+            },
+            0x0D => FetchBlock(Decodable::decode(reader)?),
+            0x0E => NewBlock(Decodable::decode(reader)?),
+            0x0F => Reject {
+                hash: Decodable::decode(reader)?,
+                reason: Decodable::decode(reader)?,
+            },
+            0x10 => Accepted(Decodable::decode(reader)?),
+            0x11 => FetchTransaction(Decodable::decode(reader)?),
+            0x12 => {
+                IndexedTransaction(Decodable::decode(reader)?)
+            }
+            0x13 => FetchHistory(Decodable::decode(reader)?),
+            0x14 => History(Decodable::decode(reader)?),
+            0x15 => GetHeaders(Decodable::decode(reader)?),
+            0x16 => Headers(Decodable::decode(reader)?),
+            0x17 => FetchUtxoSetSummary,
+            0x18 => UtxoSetSummary(Decodable::decode(reader)?),
+            _ => return Err(invalid_data("unknown message")),
+        })
+    }
+}
+
+// We are going to use length-prefixed encoding for message
+// And we are going to use the canonical encoding for the
+// message itself, see crate::encoding
+// Every message starts with the magic bytes of the network
+// it belongs to
+impl Message {
     pub fn send(
         &self,
+        network: Network,
         stream: &mut impl Write,
-    ) -> Result<(), ciborium::ser::Error<IoError>> {
-        let bytes = self.encode()?;
+    ) -> IoResult<()> {
+        let bytes = self.encode_to_vec();
         let len = bytes.len() as u64;
+        stream.write_all(&network.params().magic)?;
         stream.write_all(&len.to_be_bytes())?;
         stream.write_all(&bytes)?;
 
@@ -82,8 +302,13 @@ impl Message {
     }
 
     pub fn receive(
+        network: Network,
         stream: &mut impl Read,
-    ) -> Result<Self, ciborium::de::Error<IoError>> {
+    ) -> IoResult<Self> {
+        let mut magic = [0u8; 4];
+        stream.read_exact(&mut magic)?;
+        check_magic(network, magic)?;
+
         let mut len_bytes = [0u8; 8];
         stream.read_exact(&mut len_bytes)?;
         let len = u64::from_be_bytes(len_bytes) as usize;
@@ -91,15 +316,17 @@ impl Message {
         let mut data = vec![0u8; len];
         stream.read_exact(&mut data)?;
 
-        Self::decode(&data)
+        Self::decode_from_slice(&data)
     }
 
     pub async fn send_async(
         &self,
+        network: Network,
         stream: &mut (impl AsyncWrite + Unpin),
-    ) -> Result<(), ciborium::ser::Error<IoError>> {
-        let bytes = self.encode()?;
+    ) -> IoResult<()> {
+        let bytes = self.encode_to_vec();
         let len = bytes.len() as u64;
+        stream.write_all(&network.params().magic).await?;
         stream.write_all(&len.to_be_bytes()).await?;
         stream.write_all(&bytes).await?;
 
@@ -107,8 +334,13 @@ impl Message {
     }
 
     pub async fn receive_async(
+        network: Network,
         stream: &mut (impl AsyncRead + Unpin),
-    ) -> Result<Self, ciborium::de::Error<IoError>> {
+    ) -> IoResult<Self> {
+        let mut magic = [0u8; 4];
+        stream.read_exact(&mut magic).await?;
+        check_magic(network, magic)?;
+
         let mut len_bytes = [0u8; 8];
         stream.read_exact(&mut len_bytes).await?;
         let len = u64::from_be_bytes(len_bytes) as usize;
@@ -116,6 +348,21 @@ impl Message {
         let mut data = vec![0u8; len];
         stream.read_exact(&mut data).await?;
 
-        Self::decode(&data)
+        Self::decode_from_slice(&data)
+    }
+}
+
+// reject messages from peers on a different network
+fn check_magic(
+    network: Network,
+    magic: [u8; 4],
+) -> Result<(), IoError> {
+    if magic == network.params().magic {
+        Ok(())
+    } else {
+        Err(IoError::new(
+            IoErrorKind::InvalidData,
+            format!("message is not from the {network} network"),
+        ))
     }
 }
@@ -0,0 +1,303 @@
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::io::{Read, Result as IoResult, Write};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+
+use crate::amount::{Amount, SATS_PER_BTC};
+use crate::difficulty::{
+    Asert, ClassicRetarget, DifficultyAlgorithm, Lwma,
+};
+use crate::encoding::{invalid_data, Decodable, Encodable};
+use crate::error::BtcError;
+use crate::script::Script;
+use crate::sha256::Hash;
+use crate::types::{
+    Block, BlockHeader, Transaction, TransactionOutput,
+};
+use crate::util::MerkleRoot;
+use crate::U256;
+
+/// The networks a node can run on
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+)]
+pub enum Network {
+    #[default]
+    Main,
+    Test,
+    /// Local network with trivial proof-of-work, for
+    /// development
+    Regtest,
+}
+
+impl Network {
+    // consensus parameters of the network
+    pub fn params(&self) -> &'static ChainParams {
+        static MAIN: OnceLock<ChainParams> = OnceLock::new();
+        static TEST: OnceLock<ChainParams> = OnceLock::new();
+        static REGTEST: OnceLock<ChainParams> = OnceLock::new();
+
+        match self {
+            Network::Main => MAIN.get_or_init(ChainParams::main),
+            Network::Test => TEST.get_or_init(ChainParams::test),
+            Network::Regtest => {
+                REGTEST.get_or_init(ChainParams::regtest)
+            }
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "main" | "mainnet" => Ok(Network::Main),
+            "test" | "testnet" => Ok(Network::Test),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(BtcError::InvalidNetwork),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Network::Main => write!(f, "main"),
+            Network::Test => write!(f, "test"),
+            Network::Regtest => write!(f, "regtest"),
+        }
+    }
+}
+
+// the magic bytes of the network, see ChainParams::magic
+impl Encodable for Network {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(&self.params().magic)
+    }
+}
+
+impl Decodable for Network {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        [Network::Main, Network::Test, Network::Regtest]
+            .into_iter()
+            .find(|network| network.params().magic == magic)
+            .ok_or_else(|| invalid_data("unknown network"))
+    }
+}
+
+/// Consensus parameters of a network
+#[derive(Clone, Debug)]
+pub struct ChainParams {
+    pub network: Network,
+    /// Bytes prefixing every network message, so that nodes
+    /// of different networks cannot talk to each other
+    pub magic: [u8; 4],
+    /// The first block of the chain, known to every node
+    pub genesis_block: Block,
+    /// Initial block reward
+    pub initial_reward: Amount,
+    /// Number of blocks after which the reward halves
+    pub halving_interval: u64,
+    /// Ideal time between blocks in seconds
+    pub ideal_block_time: u64,
+    /// How far ahead of a node's clock a block's timestamp
+    /// can be, in seconds
+    pub max_future_block_time: u64,
+    /// Number of blocks between target adjustments of the
+    /// classic retarget
+    pub difficulty_update_interval: u64,
+    /// How the target of each block is derived from the
+    /// blocks before it
+    pub difficulty_algorithm: Arc<dyn DifficultyAlgorithm>,
+    /// How far the first block of a retarget period can be
+    /// before the last block of the previous period, in
+    /// seconds
+    pub max_timewarp: u64,
+    /// Easiest target a block can have, has to be exactly
+    /// representable in compact encoding
+    pub min_target: U256,
+    /// Number of blocks a coinbase output has to be buried
+    /// under before it can be spent
+    pub coinbase_maturity: u64,
+    /// UTXO snapshots known to be valid, which nodes can
+    /// start from instead of the genesis block. None have
+    /// been published for any network yet, so a node has to
+    /// be told the commitment of a snapshot to start from
+    pub assumed_utxos: Vec<AssumedUtxo>,
+}
+
+/// A UTXO snapshot nodes can start from without replaying
+/// the chain up to it, see Blockchain::from_snapshot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AssumedUtxo {
+    /// Height of the last block of the snapshot
+    pub height: u64,
+    /// Hash of the last block of the snapshot
+    pub block_hash: Hash,
+    /// Commitment to the UTXO set after the last block, see
+    /// UtxoSetSummary
+    pub commitment: Hash,
+}
+
+impl ChainParams {
+    pub fn main() -> Self {
+        // 0x1F00FFFF in compact encoding
+        let min_target = U256([
+            0x0000_0000_0000_0000,
+            0x0000_0000_0000_0000,
+            0x0000_0000_0000_0000,
+            0x0000_FFFF_0000_0000,
+        ]);
+        let initial_reward = Amount::from_sat(50 * SATS_PER_BTC);
+
+        ChainParams {
+            network: Network::Main,
+            magic: [0xF9, 0xBE, 0xB4, 0xD9],
+            genesis_block: genesis_block(
+                1_718_409_600,
+                15523,
+                min_target,
+                initial_reward,
+            ),
+            initial_reward,
+            halving_interval: 210,
+            ideal_block_time: 10,
+            max_future_block_time: 120,
+            difficulty_update_interval: 50,
+            difficulty_algorithm: Arc::new(ClassicRetarget),
+            max_timewarp: 60,
+            min_target,
+            coinbase_maturity: 100,
+            assumed_utxos: vec![],
+        }
+    }
+
+    pub fn test() -> Self {
+        let main = ChainParams::main();
+
+        ChainParams {
+            network: Network::Test,
+            magic: [0x0B, 0x11, 0x09, 0x07],
+            genesis_block: genesis_block(
+                1_718_409_601,
+                14794,
+                main.min_target,
+                main.initial_reward,
+            ),
+            // a retarget every few minutes is too slow to
+            // follow the few miners of a test network
+            difficulty_algorithm: Arc::new(Lwma { window: 60 }),
+            assumed_utxos: vec![],
+            ..main
+        }
+    }
+
+    pub fn regtest() -> Self {
+        // 0x207FFFFF in compact encoding
+        let min_target = U256([
+            0x0000_0000_0000_0000,
+            0x0000_0000_0000_0000,
+            0x0000_0000_0000_0000,
+            0x7FFF_FF00_0000_0000,
+        ]);
+        let initial_reward = Amount::from_sat(50 * SATS_PER_BTC);
+
+        ChainParams {
+            network: Network::Regtest,
+            magic: [0xFA, 0xBF, 0xB5, 0xDA],
+            genesis_block: genesis_block(
+                1_718_409_602,
+                2,
+                min_target,
+                initial_reward,
+            ),
+            initial_reward,
+            halving_interval: 150,
+            ideal_block_time: 10,
+            max_future_block_time: 120,
+            difficulty_update_interval: 50,
+            difficulty_algorithm: Arc::new(Asert {
+                half_life: 3600,
+            }),
+            max_timewarp: 60,
+            min_target,
+            coinbase_maturity: 100,
+            assumed_utxos: vec![],
+        }
+    }
+
+    // known UTXO snapshot at the given height
+    pub fn assumed_utxo(
+        &self,
+        height: u64,
+    ) -> Option<&AssumedUtxo> {
+        self.assumed_utxos
+            .iter()
+            .find(|assumed| assumed.height == height)
+    }
+
+    // block reward at the given height
+    pub fn block_reward(&self, height: u64) -> Amount {
+        let halvings = height / self.halving_interval;
+
+        if halvings >= 64 {
+            // After 64 halvings, the reward becomes 0
+            Amount::ZERO
+        } else {
+            Amount::from_sat(
+                self.initial_reward.to_sat() >> halvings,
+            )
+        }
+    }
+}
+
+// the genesis block pays its reward to an unspendable output,
+// the nonce has to be found in advance for the given
+// timestamp, target and reward
+fn genesis_block(
+    timestamp: i64,
+    nonce: u64,
+    target: U256,
+    initial_reward: Amount,
+) -> Block {
+    let transactions = vec![Transaction::coinbase(
+        0,
+        vec![TransactionOutput {
+            value: initial_reward,
+            locking_script: Script::unspendable(),
+        }],
+    )];
+
+    let block = Block::new(
+        BlockHeader::new(
+            DateTime::from_timestamp(timestamp, 0)
+                .expect("BUG: invalid genesis timestamp"),
+            nonce,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            target.to_compact(),
+        ),
+        transactions,
+    );
+    assert!(
+        block
+            .header
+            .hash()
+            .matches_target(block.header.target()),
+        "BUG: genesis nonce does not meet the target"
+    );
+    block
+}
@@ -0,0 +1,658 @@
+use serde::{Deserialize, Serialize};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+
+use std::io::{Read, Result as IoResult, Write};
+
+use crate::crypto::{PublicKey, Signature};
+use crate::encoding::{
+    decode_bytes_max, encode_bytes, invalid_data, Decodable,
+    Encodable,
+};
+use crate::error::{BtcError, RejectReason, Result};
+use crate::types::{SigHashType, Transaction};
+use crate::{
+    LOCKTIME_THRESHOLD, SEQUENCE_LOCKTIME_DISABLE_FLAG,
+    SEQUENCE_LOCKTIME_MASK, SEQUENCE_LOCKTIME_TYPE_FLAG,
+};
+
+// maximum number of opcodes in a script
+pub const MAX_SCRIPT_OPS: usize = 201;
+// maximum number of items on the stack
+pub const MAX_STACK_SIZE: usize = 1000;
+// maximum size of a single item pushed onto the stack, so
+// that neither decoding nor running a script can take much
+// memory
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+// maximum number of public keys in a multisig
+pub const MAX_MULTISIG_KEYS: usize = 20;
+// below this many scripts, verifying them on other threads
+// costs more than it saves
+pub const PARALLEL_VERIFY_THRESHOLD: usize = 16;
+
+/// A single script instruction
+#[derive(
+    Serialize, Deserialize, Clone, Debug, PartialEq, Eq,
+)]
+pub enum Opcode {
+    /// Push bytes onto the stack
+    PushBytes(Vec<u8>),
+    /// Push a number onto the stack
+    PushNumber(i64),
+    /// Duplicate the top item
+    Dup,
+    /// Remove the top item
+    Drop,
+    /// Replace the two top items with true if they are
+    /// equal and false otherwise
+    Equal,
+    /// Equal followed by Verify
+    EqualVerify,
+    /// Fail unless the top item is true, removing it
+    Verify,
+    /// Replace the top item with its SHA-256 hash
+    Sha256,
+    /// Replace a public key and a signature with true if
+    /// the signature is valid for the spending transaction
+    CheckSig,
+    /// CheckSig followed by Verify
+    CheckSigVerify,
+    /// Replace n, n public keys, m and m signatures with
+    /// true if every signature is valid for one of the keys.
+    /// Signatures have to be in the same order as the keys
+    CheckMultisig,
+    /// CheckMultisig followed by Verify
+    CheckMultisigVerify,
+    /// Fail unless the transaction's lock time is of the same
+    /// kind as the top item and at least as large. The item
+    /// is left on the stack
+    CheckLockTimeVerify,
+    /// Fail unless the input's relative lock is of the same
+    /// kind as the top item and at least as large. The item
+    /// is left on the stack
+    CheckSequenceVerify,
+    /// Fail immediately, marking the output as unspendable
+    Return,
+}
+
+/// Everything a script needs to know about the transaction
+/// it is verifying
+pub struct ScriptContext<'a> {
+    /// The spending transaction
+    pub transaction: &'a Transaction,
+    /// Index of the input being verified
+    pub input_index: usize,
+}
+
+/// An input's unlocking script and the locking script of
+/// the output it spends, collected to be verified in bulk
+pub struct ScriptCheck<'a> {
+    /// Script of the spending input
+    pub unlocking_script: &'a Script,
+    /// Script of the spent output
+    pub locking_script: &'a Script,
+    /// The spending transaction and input
+    pub context: ScriptContext<'a>,
+}
+
+impl ScriptCheck<'_> {
+    pub fn verify(&self) -> Result<()> {
+        verify(
+            self.unlocking_script,
+            self.locking_script,
+            &self.context,
+        )
+    }
+}
+
+/// A program locking a transaction output, or unlocking it
+/// in a transaction input
+#[derive(
+    Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq,
+)]
+pub struct Script(pub Vec<Opcode>);
+
+impl Script {
+    pub fn new(opcodes: Vec<Opcode>) -> Self {
+        Script(opcodes)
+    }
+
+    // pay to a public key
+    pub fn p2pk(public_key: &PublicKey) -> Self {
+        Script(vec![
+            Opcode::PushBytes(public_key.to_bytes()),
+            Opcode::CheckSig,
+        ])
+    }
+
+    // pay to the hash of a public key, the key itself is only
+    // revealed when the output is spent
+    pub fn p2pkh(public_key: &PublicKey) -> Self {
+        Script(vec![
+            Opcode::Dup,
+            Opcode::Sha256,
+            Opcode::PushBytes(sha256(&public_key.to_bytes())),
+            Opcode::EqualVerify,
+            Opcode::CheckSig,
+        ])
+    }
+
+    // require signatures from m out of the given keys
+    pub fn multisig(
+        required: usize,
+        public_keys: &[PublicKey],
+    ) -> Self {
+        let mut opcodes =
+            vec![Opcode::PushNumber(required as i64)];
+        opcodes.extend(
+            public_keys
+                .iter()
+                .map(|key| Opcode::PushBytes(key.to_bytes())),
+        );
+        opcodes
+            .push(Opcode::PushNumber(public_keys.len() as i64));
+        opcodes.push(Opcode::CheckMultisig);
+
+        Script(opcodes)
+    }
+
+    // require the SHA-256 preimage of the given hash and a
+    // signature from the given key
+    pub fn hashlock(
+        hash: &[u8],
+        public_key: &PublicKey,
+    ) -> Self {
+        Script(vec![
+            Opcode::Sha256,
+            Opcode::PushBytes(hash.to_vec()),
+            Opcode::EqualVerify,
+            Opcode::PushBytes(public_key.to_bytes()),
+            Opcode::CheckSig,
+        ])
+    }
+
+    // make a script spendable only by transactions with a
+    // lock time of at least the given block height (or time)
+    pub fn timelock(lock_time: u64, script: Script) -> Self {
+        let mut opcodes = vec![
+            Opcode::PushNumber(lock_time as i64),
+            Opcode::CheckLockTimeVerify,
+            Opcode::Drop,
+        ];
+        opcodes.extend(script.0);
+
+        Script(opcodes)
+    }
+
+    // make a script spendable only by inputs with a relative
+    // lock of at least the given sequence
+    pub fn relative_timelock(
+        sequence: u32,
+        script: Script,
+    ) -> Self {
+        let mut opcodes = vec![
+            Opcode::PushNumber(sequence as i64),
+            Opcode::CheckSequenceVerify,
+            Opcode::Drop,
+        ];
+        opcodes.extend(script.0);
+
+        Script(opcodes)
+    }
+
+    // a script that can never be spent
+    pub fn unspendable() -> Self {
+        Script(vec![Opcode::Return])
+    }
+
+    // check if this is a P2PK or P2PKH script of the given key
+    pub fn pays_to(&self, public_key: &PublicKey) -> bool {
+        *self == Script::p2pk(public_key)
+            || *self == Script::p2pkh(public_key)
+    }
+
+    // create the unlocking script for a P2PK or P2PKH script
+    pub fn unlock_single_key(
+        &self,
+        signature: &Signature,
+        sighash_type: SigHashType,
+        public_key: &PublicKey,
+    ) -> Result<Script> {
+        let signature = signature_push(signature, sighash_type);
+
+        if *self == Script::p2pk(public_key) {
+            Ok(Script(vec![signature]))
+        } else if *self == Script::p2pkh(public_key) {
+            Ok(Script(vec![
+                signature,
+                Opcode::PushBytes(public_key.to_bytes()),
+            ]))
+        } else {
+            Err(BtcError::InvalidScript)
+        }
+    }
+
+    // create the unlocking script for a multisig script,
+    // there have to be exactly as many signatures as the
+    // script requires, in the order of the public keys
+    pub fn unlock_multisig(
+        signatures: &[(Signature, SigHashType)],
+    ) -> Script {
+        Script(
+            signatures
+                .iter()
+                .map(|(signature, sighash_type)| {
+                    signature_push(signature, *sighash_type)
+                })
+                .collect(),
+        )
+    }
+
+    // check if the script consists only of pushes
+    pub fn is_push_only(&self) -> bool {
+        self.0.iter().all(|opcode| {
+            matches!(
+                opcode,
+                Opcode::PushBytes(_) | Opcode::PushNumber(_)
+            )
+        })
+    }
+
+    // run the script on the given stack
+    fn execute(
+        &self,
+        stack: &mut Vec<Vec<u8>>,
+        context: &ScriptContext,
+    ) -> Result<()> {
+        if self.0.len() > MAX_SCRIPT_OPS {
+            return Err(BtcError::InvalidScript);
+        }
+
+        for opcode in &self.0 {
+            match opcode {
+                Opcode::PushBytes(bytes) => {
+                    if bytes.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                        return Err(
+                            RejectReason::PushTooLarge.into()
+                        );
+                    }
+                    stack.push(bytes.clone())
+                }
+                Opcode::PushNumber(number) => {
+                    stack.push(encode_number(*number))
+                }
+                Opcode::Dup => {
+                    let top = stack
+                        .last()
+                        .ok_or(BtcError::InvalidScript)?
+                        .clone();
+                    stack.push(top);
+                }
+                Opcode::Drop => {
+                    pop(stack)?;
+                }
+                Opcode::Equal | Opcode::EqualVerify => {
+                    let a = pop(stack)?;
+                    let b = pop(stack)?;
+                    stack.push(encode_bool(a == b));
+                }
+                Opcode::Verify => {}
+                Opcode::Sha256 => {
+                    let data = pop(stack)?;
+                    stack.push(sha256(&data));
+                }
+                Opcode::CheckSig | Opcode::CheckSigVerify => {
+                    let public_key = pop(stack)?;
+                    let signature = pop(stack)?;
+                    let valid = check_signature(
+                        &signature,
+                        &public_key,
+                        context,
+                    );
+                    stack.push(encode_bool(valid));
+                }
+                Opcode::CheckMultisig
+                | Opcode::CheckMultisigVerify => {
+                    let valid = check_multisig(stack, context)?;
+                    stack.push(encode_bool(valid));
+                }
+                Opcode::CheckLockTimeVerify => {
+                    let lock_time = decode_number(
+                        stack
+                            .last()
+                            .ok_or(BtcError::InvalidScript)?,
+                    )?;
+                    check_lock_time(lock_time, context)?;
+                }
+                Opcode::CheckSequenceVerify => {
+                    let sequence = decode_number(
+                        stack
+                            .last()
+                            .ok_or(BtcError::InvalidScript)?,
+                    )?;
+                    check_sequence(sequence, context)?;
+                }
+                Opcode::Return => {
+                    return Err(BtcError::InvalidScript)
+                }
+            }
+
+            // the verify variants consume their result
+            if matches!(
+                opcode,
+                Opcode::EqualVerify
+                    | Opcode::Verify
+                    | Opcode::CheckSigVerify
+                    | Opcode::CheckMultisigVerify
+            ) && !decode_bool(&pop(stack)?)
+            {
+                return Err(failure(opcode));
+            }
+
+            if stack.len() > MAX_STACK_SIZE {
+                return Err(BtcError::InvalidScript);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// a one byte tag, followed by the data of PushBytes (as a
+// byte string) and PushNumber (as an i64)
+impl Encodable for Opcode {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        match self {
+            Opcode::PushBytes(bytes) => {
+                0x00u8.encode(writer)?;
+                encode_bytes(bytes, writer)
+            }
+            Opcode::PushNumber(number) => {
+                0x01u8.encode(writer)?;
+                number.encode(writer)
+            }
+            Opcode::Dup => 0x02u8.encode(writer),
+            Opcode::Drop => 0x03u8.encode(writer),
+            Opcode::Equal => 0x04u8.encode(writer),
+            Opcode::EqualVerify => 0x05u8.encode(writer),
+            Opcode::Verify => 0x06u8.encode(writer),
+            Opcode::Sha256 => 0x07u8.encode(writer),
+            Opcode::CheckSig => 0x08u8.encode(writer),
+            Opcode::CheckSigVerify => 0x09u8.encode(writer),
+            Opcode::CheckMultisig => 0x0Au8.encode(writer),
+            Opcode::CheckMultisigVerify => 0x0Bu8.encode(writer),
+            Opcode::CheckLockTimeVerify => 0x0Cu8.encode(writer),
+            Opcode::CheckSequenceVerify => 0x0Du8.encode(writer),
+            Opcode::Return => 0x0Eu8.encode(writer),
+        }
+    }
+}
+
+impl Decodable for Opcode {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(match u8::decode(reader)? {
+            0x00 => Opcode::PushBytes(decode_bytes_max(
+                reader,
+                MAX_SCRIPT_ELEMENT_SIZE,
+            )?),
+            0x01 => Opcode::PushNumber(i64::decode(reader)?),
+            0x02 => Opcode::Dup,
+            0x03 => Opcode::Drop,
+            0x04 => Opcode::Equal,
+            0x05 => Opcode::EqualVerify,
+            0x06 => Opcode::Verify,
+            0x07 => Opcode::Sha256,
+            0x08 => Opcode::CheckSig,
+            0x09 => Opcode::CheckSigVerify,
+            0x0A => Opcode::CheckMultisig,
+            0x0B => Opcode::CheckMultisigVerify,
+            0x0C => Opcode::CheckLockTimeVerify,
+            0x0D => Opcode::CheckSequenceVerify,
+            0x0E => Opcode::Return,
+            _ => return Err(invalid_data("unknown opcode")),
+        })
+    }
+}
+
+// the sequence of opcodes
+impl Encodable for Script {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.0.encode(writer)
+    }
+}
+
+impl Decodable for Script {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Vec::decode(reader).map(Script)
+    }
+}
+
+// verify that an unlocking script satisfies the locking
+// script of the output it spends
+pub fn verify(
+    unlocking_script: &Script,
+    locking_script: &Script,
+    context: &ScriptContext,
+) -> Result<()> {
+    // anything else than data in the unlocking script would
+    // let third parties change it without invalidating it
+    if !unlocking_script.is_push_only() {
+        return Err(BtcError::InvalidScript);
+    }
+
+    let mut stack = vec![];
+    unlocking_script.execute(&mut stack, context)?;
+    locking_script.execute(&mut stack, context)?;
+
+    match stack.last() {
+        Some(top) if decode_bool(top) => Ok(()),
+        _ => Err(locking_script
+            .0
+            .last()
+            .map_or(BtcError::InvalidScript, failure)),
+    }
+}
+
+// the error for a script whose result is false after the
+// given opcode, so that bad signatures can be told apart
+fn failure(opcode: &Opcode) -> BtcError {
+    match opcode {
+        Opcode::CheckSig
+        | Opcode::CheckSigVerify
+        | Opcode::CheckMultisig
+        | Opcode::CheckMultisigVerify => {
+            BtcError::InvalidSignature
+        }
+        _ => BtcError::InvalidScript,
+    }
+}
+
+// verify many scripts, spread over all available cores by
+// rayon's global thread pool, which is started once and then
+// reused for every block. Signature checks make up nearly
+// all of the work, and each script is independent of the
+// others. Batch verification does not apply: it needs the
+// full nonce point R of every signature, of which an ECDSA
+// signature only keeps the x coordinate, and k256 does not
+// offer it
+pub fn verify_all(checks: &[ScriptCheck]) -> Result<()> {
+    if checks.len() < PARALLEL_VERIFY_THRESHOLD {
+        return checks.iter().try_for_each(ScriptCheck::verify);
+    }
+
+    // stops handing out checks once one of them fails
+    checks.par_iter().try_for_each(ScriptCheck::verify)
+}
+
+// a signature followed by its sighash type
+fn signature_push(
+    signature: &Signature,
+    sighash_type: SigHashType,
+) -> Opcode {
+    let mut bytes = signature.to_bytes();
+    bytes.push(sighash_type.to_byte());
+    Opcode::PushBytes(bytes)
+}
+
+fn check_signature(
+    signature: &[u8],
+    public_key: &[u8],
+    context: &ScriptContext,
+) -> bool {
+    let Some((&sighash_type, signature)) =
+        signature.split_last()
+    else {
+        return false;
+    };
+
+    let (Ok(signature), Ok(public_key), Some(sighash_type)) = (
+        Signature::from_bytes(signature),
+        PublicKey::from_bytes(public_key),
+        SigHashType::from_byte(sighash_type),
+    ) else {
+        return false;
+    };
+
+    match context
+        .transaction
+        .sighash(context.input_index, sighash_type)
+    {
+        Ok(sighash) => {
+            signature.verify_cached(&sighash, &public_key)
+        }
+        Err(_) => false,
+    }
+}
+
+fn check_multisig(
+    stack: &mut Vec<Vec<u8>>,
+    context: &ScriptContext,
+) -> Result<bool> {
+    let key_count = decode_count(&pop(stack)?)?;
+    let mut public_keys = (0..key_count)
+        .map(|_| pop(stack))
+        .collect::<Result<Vec<_>>>()?;
+    public_keys.reverse();
+
+    let signature_count = decode_count(&pop(stack)?)?;
+    if signature_count > key_count {
+        return Err(BtcError::InvalidScript);
+    }
+    let mut signatures = (0..signature_count)
+        .map(|_| pop(stack))
+        .collect::<Result<Vec<_>>>()?;
+    signatures.reverse();
+
+    // every signature has to match one of the remaining keys,
+    // keys that have been skipped cannot be used anymore
+    let mut public_keys = public_keys.iter();
+    for signature in &signatures {
+        let matched = public_keys.any(|public_key| {
+            check_signature(signature, public_key, context)
+        });
+        if !matched {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+fn check_lock_time(
+    lock_time: i64,
+    context: &ScriptContext,
+) -> Result<()> {
+    let Ok(lock_time) = u64::try_from(lock_time) else {
+        return Err(BtcError::InvalidScript);
+    };
+    let tx_lock_time = context.transaction.lock_time;
+
+    // comparing heights to timestamps is meaningless, the
+    // transaction's lock time itself is enforced by the
+    // blockchain
+    let same_kind = (lock_time < LOCKTIME_THRESHOLD)
+        == (tx_lock_time < LOCKTIME_THRESHOLD);
+
+    if same_kind && lock_time <= tx_lock_time {
+        Ok(())
+    } else {
+        Err(BtcError::InvalidScript)
+    }
+}
+
+fn check_sequence(
+    sequence: i64,
+    context: &ScriptContext,
+) -> Result<()> {
+    let Ok(sequence) = u32::try_from(sequence) else {
+        return Err(BtcError::InvalidScript);
+    };
+    // a disabled relative lock makes this a no-op
+    if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+        return Ok(());
+    }
+
+    let input_sequence = context
+        .transaction
+        .inputs
+        .get(context.input_index)
+        .ok_or(BtcError::InvalidScript)?
+        .sequence;
+    if input_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+        return Err(BtcError::InvalidScript);
+    }
+
+    let same_kind = (sequence & SEQUENCE_LOCKTIME_TYPE_FLAG)
+        == (input_sequence & SEQUENCE_LOCKTIME_TYPE_FLAG);
+
+    if same_kind
+        && sequence & SEQUENCE_LOCKTIME_MASK
+            <= input_sequence & SEQUENCE_LOCKTIME_MASK
+    {
+        Ok(())
+    } else {
+        Err(BtcError::InvalidScript)
+    }
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>> {
+    stack.pop().ok_or(BtcError::InvalidScript)
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+// empty and all-zero items are false, anything else is true
+fn decode_bool(bytes: &[u8]) -> bool {
+    bytes.iter().any(|byte| *byte != 0)
+}
+
+// numbers are 8 bytes, little endian
+fn encode_number(number: i64) -> Vec<u8> {
+    number.to_le_bytes().to_vec()
+}
+
+fn decode_number(bytes: &[u8]) -> Result<i64> {
+    let bytes: [u8; 8] =
+        bytes.try_into().map_err(|_| BtcError::InvalidScript)?;
+    Ok(i64::from_le_bytes(bytes))
+}
+
+// number of keys or signatures in a multisig
+fn decode_count(bytes: &[u8]) -> Result<usize> {
+    let count = decode_number(bytes)?;
+    if !(0..=MAX_MULTISIG_KEYS as i64).contains(&count) {
+        return Err(BtcError::InvalidScript);
+    }
+
+    Ok(count as usize)
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
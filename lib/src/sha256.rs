@@ -1,8 +1,12 @@
 use serde::{Deserialize, Serialize};
-use sha256::digest;
+use sha2::{Digest, Sha256};
 
 use std::fmt;
+use std::io::{Read, Result as IoResult, Write};
+use std::str::FromStr;
 
+use crate::encoding::{Decodable, Encodable};
+use crate::error::{BtcError, Result};
 use crate::U256;
 
 #[derive(
@@ -18,25 +22,12 @@ use crate::U256;
 pub struct Hash(U256);
 
 impl Hash {
-    // hash anything that can be serde Serialized via ciborium
-    pub fn hash<T: serde::Serialize>(data: &T) -> Self {
-        let mut serialized: Vec<u8> = vec![];
-
-        if let Err(e) =
-            ciborium::into_writer(data, &mut serialized)
-        {
-            panic!(
-                "Failed to serialize data: {:?}. \
-                This should not happen",
-                e
-            );
-        }
-        let hash = digest(&serialized);
-        let hash_bytes = hex::decode(hash).unwrap();
-        let hash_array: [u8; 32] =
-            hash_bytes.as_slice().try_into().unwrap();
-
-        Hash(U256::from(hash_array))
+    // hash the canonical encoding of anything encodable
+    pub fn hash<T: Encodable + ?Sized>(data: &T) -> Self {
+        let mut hasher = Hasher::new();
+        data.encode(&mut hasher)
+            .expect("BUG: writing to a hasher cannot fail");
+        hasher.finalize()
     }
 
     // check if a hash matches a target
@@ -49,16 +40,88 @@ impl Hash {
         Hash(U256::zero())
     }
 
+    // the hash as 32 bytes little-endian, the same as in its
+    // encoding
     pub fn as_bytes(&self) -> [u8; 32] {
-        let mut bytes: Vec<u8> = vec![0; 32];
+        let mut bytes = [0u8; 32];
         self.0.to_little_endian(&mut bytes);
+        bytes
+    }
+
+    // inverse of as_bytes
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Hash(U256::from_little_endian(&bytes))
+    }
+}
+
+/// Streaming SHA-256 of everything written into it, so data
+/// can be hashed without being serialized into a buffer first
+#[derive(Clone, Default)]
+pub struct Hasher(Sha256);
+
+impl Hasher {
+    pub fn new() -> Self {
+        Hasher(Sha256::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    // the digest is read as a big-endian number, the way it
+    // is displayed
+    pub fn finalize(self) -> Hash {
+        let digest: [u8; 32] = self.0.finalize().into();
+        Hash(U256::from_big_endian(&digest))
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> IoResult<()> {
+        self.update(buf);
+        Ok(())
+    }
 
-        bytes.as_slice().try_into().unwrap()
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
     }
 }
 
+// 32 bytes little-endian
+impl Encodable for Hash {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.0.encode(writer)
+    }
+}
+
+impl Decodable for Hash {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        U256::decode(reader).map(Hash)
+    }
+}
+
+// 64 hex digits, most significant first
 impl fmt::Display for Hash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:x}", self.0)
+        let mut bytes = [0u8; 32];
+        self.0.to_big_endian(&mut bytes);
+        write!(f, "{}", hex::encode(bytes))
+    }
+}
+
+// parse the Display representation
+impl FromStr for Hash {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(s, &mut bytes)
+            .map_err(|_| BtcError::InvalidHash)?;
+        Ok(Hash(U256::from_big_endian(&bytes)))
     }
 }
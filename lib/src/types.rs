@@ -1,9 +1,18 @@
 mod block;
 mod blockchain;
+mod header_chain;
+mod index;
 mod transaction;
+mod utxo_set;
 
 pub use block::{Block, BlockHeader};
-pub use blockchain::Blockchain;
+pub use blockchain::{Blockchain, UtxoEntry};
+pub use header_chain::HeaderChain;
+pub use index::{HistoryEntry, SpendInfo, TxLocation};
 pub use transaction::{
-    Transaction, TransactionInput, TransactionOutput,
+    OutPoint, SigHashType, Transaction, TransactionInput,
+    TransactionOutput,
+};
+pub use utxo_set::{
+    UtxoCommitment, UtxoSetSummary, UtxoSnapshot,
 };
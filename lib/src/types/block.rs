@@ -3,15 +3,18 @@ use serde::{Deserialize, Serialize};
 
 // add this to the imports at the top of the file
 use crate::util::Saveable;
-use std::io::{
-    Error as IoError, ErrorKind as IoErrorKind, Read,
-    Result as IoResult, Write,
-};
+use std::io::{Read, Result as IoResult, Write};
 
-use super::{Transaction, TransactionOutput};
-use crate::error::{BtcError, Result};
+use super::{
+    OutPoint, Transaction, TransactionOutput, UtxoEntry,
+};
+use crate::amount::Amount;
+use crate::encoding::{Decodable, Encodable};
+use crate::error::{BtcError, RejectReason, Result};
+use crate::params::ChainParams;
+use crate::script::{self, ScriptCheck, ScriptContext};
 use crate::sha256::Hash;
-use crate::util::MerkleRoot;
+use crate::util::{MerkleProof, MerkleRoot};
 use crate::U256;
 
 use std::collections::HashMap;
@@ -33,96 +36,141 @@ impl Block {
         }
     }
 
+    // a block is identified by its header, which commits to
+    // the transactions through the merkle root
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        self.header.hash()
+    }
+
+    // size of the serialized block in bytes
+    pub fn serialized_size(&self) -> usize {
+        self.encoded_size()
+    }
+
+    // check that the header's merkle root commits to exactly
+    // the block's transactions
+    pub fn verify_merkle_root(&self) -> Result<()> {
+        match MerkleRoot::calculate_checked(&self.transactions) {
+            Some(root) if root == self.header.merkle_root => {
+                Ok(())
+            }
+            _ => Err(BtcError::InvalidMerkleRoot),
+        }
+    }
+
+    // prove that the transaction with the given id is part of
+    // the block
+    pub fn merkle_proof(
+        &self,
+        txid: &Hash,
+    ) -> Option<MerkleProof> {
+        let index = self.transactions.iter().position(
+            |transaction| transaction.hash() == *txid,
+        )?;
+        MerkleProof::generate(&self.transactions, index)
     }
 
     pub fn calculate_miner_fees(
         &self,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
-    ) -> Result<u64> {
-        let mut inputs: HashMap<Hash, TransactionOutput> =
+        utxos: &HashMap<OutPoint, UtxoEntry>,
+    ) -> Result<Amount> {
+        let mut inputs: HashMap<OutPoint, TransactionOutput> =
             HashMap::new();
-        let mut outputs: HashMap<Hash, TransactionOutput> =
+        let mut outputs: HashMap<OutPoint, TransactionOutput> =
             HashMap::new();
 
         // Check every transaction after coinbase
         for transaction in self.transactions.iter().skip(1) {
             for input in &transaction.inputs {
-                let prev_output = utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .map(|(_, output)| output);
-                if prev_output.is_none() {
-                    println!("yoho");
-                    return Err(BtcError::InvalidTransaction);
-                }
-                let prev_output = prev_output.unwrap();
-
-                if inputs.contains_key(
-                    &input.prev_transaction_output_hash,
-                ) {
-                    println!("heeho");
-                    return Err(BtcError::InvalidTransaction);
+                let Some(prev_output) = utxos
+                    .get(&input.prev_output)
+                    .map(|entry| &entry.output)
+                else {
+                    return Err(
+                        RejectReason::MissingInput.into()
+                    );
+                };
+
+                if inputs.contains_key(&input.prev_output) {
+                    return Err(
+                        RejectReason::DuplicateInput.into()
+                    );
                 }
 
                 inputs.insert(
-                    input.prev_transaction_output_hash,
+                    input.prev_output,
                     prev_output.clone(),
                 );
             }
 
-            for output in &transaction.outputs {
-                if outputs.contains_key(&output.hash()) {
-                    println!("hoooo");
-                    return Err(BtcError::InvalidTransaction);
+            let txid = transaction.hash();
+            for (index, output) in
+                transaction.outputs.iter().enumerate()
+            {
+                let outpoint = OutPoint::new(txid, index as u32);
+                if outputs.contains_key(&outpoint) {
+                    return Err(
+                        RejectReason::DuplicateTransaction
+                            .into(),
+                    );
                 }
 
-                outputs.insert(output.hash(), output.clone());
+                outputs.insert(outpoint, output.clone());
             }
         }
 
-        let input_value: u64 =
-            inputs.values().map(|output| output.value).sum();
+        let input_value = Amount::checked_sum(
+            inputs.values().map(|output| output.value),
+        )
+        .ok_or(RejectReason::InputValueOutOfRange)?;
 
-        let output_value: u64 =
-            outputs.values().map(|output| output.value).sum();
+        let output_value = Amount::checked_sum(
+            outputs.values().map(|output| output.value),
+        )
+        .ok_or(RejectReason::OutputValueOutOfRange)?;
 
-        Ok(input_value - output_value)
+        Ok(input_value
+            .checked_sub(output_value)
+            .ok_or(RejectReason::InputsBelowOutputs)?)
     }
 
     // Verify coinbase transaction
     pub fn verify_coinbase_transaction(
         &self,
         predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &HashMap<OutPoint, UtxoEntry>,
+        params: &ChainParams,
     ) -> Result<()> {
         // coinbase tx is the first transaction in the block
         let coinbase_transaction = &self.transactions[0];
 
         if coinbase_transaction.inputs.len() != 0 {
-            return Err(BtcError::InvalidTransaction);
+            return Err(RejectReason::MissingCoinbase.into());
         }
 
         if coinbase_transaction.outputs.len() == 0 {
-            return Err(BtcError::InvalidTransaction);
+            return Err(RejectReason::NoOutputs.into());
+        }
+
+        // the coinbase commits to the block height, so that
+        // its id is different from all other coinbases
+        if coinbase_transaction.coinbase_height
+            != predicted_block_height
+        {
+            return Err(RejectReason::BadCoinbaseHeight.into());
         }
 
         let miner_fees = self.calculate_miner_fees(utxos)?;
-        let block_reward = crate::INITIAL_REWARD * 10u64.pow(8)
-            / 2u64.pow(
-                (predicted_block_height
-                    / crate::HALVING_INTERVAL)
-                    as u32,
-            );
-
-        let total_coinbase_outputs: u64 = coinbase_transaction
-            .outputs
-            .iter()
-            .map(|output| output.value)
-            .sum();
-
-        if total_coinbase_outputs != block_reward + miner_fees {
-            return Err(BtcError::InvalidTransaction);
+        let block_reward =
+            params.block_reward(predicted_block_height);
+
+        let total_coinbase_outputs =
+            coinbase_transaction.output_value()?;
+
+        if Some(total_coinbase_outputs)
+            != block_reward.checked_add(miner_fees)
+        {
+            return Err(RejectReason::BadCoinbaseAmount.into());
         }
 
         Ok(())
@@ -132,93 +180,154 @@ impl Block {
     pub fn verify_transactions(
         &self,
         predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &HashMap<OutPoint, UtxoEntry>,
+        params: &ChainParams,
     ) -> Result<()> {
-        let mut inputs: HashMap<Hash, TransactionOutput> =
+        let mut inputs: HashMap<OutPoint, TransactionOutput> =
             HashMap::new();
 
         // reject completely empty blocks
         if self.transactions.is_empty() {
-            return Err(BtcError::InvalidTransaction);
+            return Err(RejectReason::NoTransactions.into());
         }
 
         // verify coinbase transaction
         self.verify_coinbase_transaction(
             predicted_block_height,
             utxos,
+            params,
         )?;
 
+        // scripts are verified at the end, all at once
+        let mut script_checks = vec![];
+
         for transaction in self.transactions.iter().skip(1) {
-            let mut input_value = 0;
-            let mut output_value = 0;
+            let mut input_value = Amount::ZERO;
 
-            for input in &transaction.inputs {
-                let prev_output = utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .map(|(_, output)| output);
-                if prev_output.is_none() {
-                    return Err(BtcError::InvalidTransaction);
+            // only the coinbase may create money out of thin air
+            // or carry a height
+            if transaction.coinbase_height != 0 {
+                return Err(
+                    RejectReason::MultipleCoinbases.into()
+                );
+            }
+            if transaction.inputs.is_empty() {
+                return Err(RejectReason::NoInputs.into());
+            }
+
+            if !transaction.is_final(
+                predicted_block_height,
+                self.header.timestamp,
+            ) {
+                return Err(RejectReason::NonFinal.into());
+            }
+
+            for (index, input) in
+                transaction.inputs.iter().enumerate()
+            {
+                let Some(entry) = utxos.get(&input.prev_output)
+                else {
+                    return Err(
+                        RejectReason::MissingInput.into()
+                    );
+                };
+                let prev_output = &entry.output;
+
+                // coinbase outputs cannot be spent until
+                // they are buried deep enough
+                if !entry
+                    .is_mature(predicted_block_height, params)
+                {
+                    return Err(
+                        RejectReason::PrematureCoinbaseSpend
+                            .into(),
+                    );
                 }
-                let prev_output = prev_output.unwrap();
 
-                // prevent same-block double-spending
-                if inputs.contains_key(
-                    &input.prev_transaction_output_hash,
+                // check the input's relative lock against the
+                // block that created the spent output
+                if !input.is_final(
+                    entry,
+                    predicted_block_height,
+                    self.header.timestamp,
                 ) {
-                    return Err(BtcError::InvalidTransaction);
+                    return Err(
+                        RejectReason::NonFinalSequence.into()
+                    );
                 }
 
-                // check if the signature is valid
-                if !input.signature.verify(
-                    &input.prev_transaction_output_hash,
-                    &prev_output.pubkey,
-                ) {
-                    return Err(BtcError::InvalidSignature);
+                // prevent same-block double-spending
+                if inputs.contains_key(&input.prev_output) {
+                    return Err(
+                        RejectReason::DuplicateInput.into()
+                    );
                 }
 
-                input_value += prev_output.value;
+                // check if the unlocking script satisfies
+                // the output's locking script
+                script_checks.push(ScriptCheck {
+                    unlocking_script: &input.unlocking_script,
+                    locking_script: &prev_output.locking_script,
+                    context: ScriptContext {
+                        transaction,
+                        input_index: index,
+                    },
+                });
+
+                input_value = Amount::checked_sum([
+                    input_value,
+                    prev_output.value,
+                ])
+                .ok_or(RejectReason::InputValueOutOfRange)?;
                 inputs.insert(
-                    input.prev_transaction_output_hash,
+                    input.prev_output,
                     prev_output.clone(),
                 );
             }
 
-            for output in &transaction.outputs {
-                output_value += output.value;
-            }
+            let output_value = transaction.output_value()?;
 
             // It is fine for output value to be less than input value
             // as the difference is the fee for the miner
             if input_value < output_value {
-                return Err(BtcError::InvalidTransaction);
+                return Err(
+                    RejectReason::InputsBelowOutputs.into()
+                );
             }
         }
 
-        Ok(())
+        script::verify_all(&script_checks)
     }
 }
 
-// save and load expecting CBOR from ciborium as format
-impl Saveable for Block {
-    fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader).map_err(|_| {
-            IoError::new(
-                IoErrorKind::InvalidData,
-                "Failed to deserialize Block",
-            )
-        })
+// header, transactions
+impl Encodable for Block {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.header.encode(writer)?;
+        self.transactions.encode(writer)
     }
+}
 
-    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
-        ciborium::ser::into_writer(self, writer).map_err(|_| {
-            IoError::new(
-                IoErrorKind::InvalidData,
-                "Failed to serialize Block",
-            )
+impl Decodable for Block {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(Block {
+            header: BlockHeader::decode(reader)?,
+            transactions: Vec::decode(reader)?,
         })
     }
 }
 
+// save and load in the canonical encoding
+impl Saveable for Block {
+    fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+        Block::decode(&mut reader)
+    }
+
+    fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
+        self.encode(&mut writer)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BlockHeader {
     /// Timestamp of the block
@@ -229,8 +338,8 @@ pub struct BlockHeader {
     pub prev_block_hash: Hash,
     /// Merkle root of the block's transactions
     pub merkle_root: MerkleRoot,
-    /// target
-    pub target: U256,
+    /// Compact encoding of the target, see U256::from_compact
+    pub bits: u32,
 }
 
 impl BlockHeader {
@@ -239,20 +348,28 @@ impl BlockHeader {
         nonce: u64,
         prev_block_hash: Hash,
         merkle_root: MerkleRoot,
-        target: U256,
+        bits: u32,
     ) -> Self {
         BlockHeader {
             timestamp,
             nonce,
             prev_block_hash,
             merkle_root,
-            target,
+            bits,
         }
     }
 
+    // the target decoded from the header's bits. Invalid
+    // encodings decode to zero, which no hash can match
+    pub fn target(&self) -> U256 {
+        U256::from_compact(self.bits).unwrap_or_default()
+    }
+
     pub fn mine(&mut self, steps: usize) -> bool {
+        let target = self.target();
+
         // if the block already matches target, return early
-        if self.hash().matches_target(self.target) {
+        if self.hash().matches_target(target) {
             return true;
         }
 
@@ -264,7 +381,7 @@ impl BlockHeader {
                 self.timestamp = Utc::now()
             }
 
-            if self.hash().matches_target(self.target) {
+            if self.hash().matches_target(target) {
                 return true;
             }
         }
@@ -275,4 +392,46 @@ impl BlockHeader {
     pub fn hash(&self) -> Hash {
         Hash::hash(self)
     }
+
+    // amount of work (expected number of hashes) needed to
+    // find a block with this header's target. A zero target,
+    // which is what invalid bits decode to, cannot be met and
+    // counts for no work
+    pub fn work(&self) -> U256 {
+        let target = self.target();
+        if target.is_zero() {
+            return U256::zero();
+        }
+        if target == U256::MAX {
+            return U256::one();
+        }
+
+        // 2^256 / (target + 1), computed as
+        // !target / (target + 1) + 1 so that it fits in 256 bits
+        !target / (target + 1) + 1
+    }
+}
+
+// timestamp, nonce, previous block hash, merkle root, bits,
+// 88 bytes in total
+impl Encodable for BlockHeader {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.timestamp.encode(writer)?;
+        self.nonce.encode(writer)?;
+        self.prev_block_hash.encode(writer)?;
+        self.merkle_root.encode(writer)?;
+        self.bits.encode(writer)
+    }
+}
+
+impl Decodable for BlockHeader {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(BlockHeader {
+            timestamp: DateTime::decode(reader)?,
+            nonce: u64::decode(reader)?,
+            prev_block_hash: Hash::decode(reader)?,
+            merkle_root: MerkleRoot::decode(reader)?,
+            bits: u32::decode(reader)?,
+        })
+    }
 }
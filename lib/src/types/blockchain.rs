@@ -1,237 +1,1182 @@
-use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
 
-use crate::util::Saveable;
+use crate::util::{MerkleRoot, Saveable};
 use std::io::{
     Error as IoError, ErrorKind as IoErrorKind, Read,
     Result as IoResult, Write,
 };
 
-use super::{Block, Transaction, TransactionOutput};
-use crate::error::{BtcError, Result};
+use super::header_chain::{
+    check_header, context_len, locator, median_time_past,
+    required_target,
+};
+use super::index::{AddressIndex, TxIndex};
+use super::utxo_set::UtxoCommitment;
+use super::{
+    Block, BlockHeader, HeaderChain, HistoryEntry, OutPoint,
+    Transaction, TransactionOutput, TxLocation, UtxoSetSummary,
+    UtxoSnapshot,
+};
+use crate::amount::Amount;
+use crate::crypto::PublicKey;
+use crate::encoding::{invalid_data, Decodable, Encodable};
+use crate::error::{BtcError, RejectReason, Result};
+use crate::params::{AssumedUtxo, ChainParams, Network};
+use crate::script::{self, Script, ScriptContext};
 use crate::sha256::Hash;
-use crate::util::MerkleRoot;
 use crate::U256;
 
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+// bytes reserved in block templates for the coinbase reward
+// and the length prefix of the transaction list
+const TEMPLATE_SIZE_MARGIN: usize = 32;
 
+// everything needed to disconnect a block from the tip of
+// the active chain without replaying the chain from genesis
+#[derive(Clone, Debug)]
+struct BlockUndo {
+    // outputs spent by the block's transactions
+    spent_outputs: Vec<(OutPoint, UtxoEntry)>,
+}
+
+/// An unspent transaction output together with the block
+/// that created it
 #[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UtxoEntry {
+    pub output: TransactionOutput,
+    /// Height of the block that created the output
+    pub height: u64,
+    /// Timestamp of the block that created the output
+    pub timestamp: DateTime<Utc>,
+    /// Set if the output was created by a coinbase transaction
+    pub is_coinbase: bool,
+    /// Set if a mempool transaction spends the output
+    pub marked: bool,
+}
+
+impl UtxoEntry {
+    // check if the output can be spent in a block at the given
+    // height. Coinbase outputs have to be buried under
+    // params.coinbase_maturity blocks first, so that they
+    // cannot vanish from under their spenders in a
+    // reorganization
+    pub fn is_mature(
+        &self,
+        height: u64,
+        params: &ChainParams,
+    ) -> bool {
+        !self.is_coinbase
+            || height >= self.height + params.coinbase_maturity
+    }
+}
+
+// output, height, timestamp, is coinbase. Whether a mempool
+// transaction spends the output is local to a node, so it is
+// not part of the encoding
+impl Encodable for UtxoEntry {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.output.encode(writer)?;
+        self.height.encode(writer)?;
+        self.timestamp.encode(writer)?;
+        self.is_coinbase.encode(writer)
+    }
+}
+
+impl Decodable for UtxoEntry {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(UtxoEntry {
+            output: TransactionOutput::decode(reader)?,
+            height: u64::decode(reader)?,
+            timestamp: DateTime::decode(reader)?,
+            is_coinbase: bool::decode(reader)?,
+            marked: false,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Blockchain {
-    utxos: HashMap<Hash, (bool, TransactionOutput)>,
-    target: U256,
+    network: Network,
+    utxos: HashMap<OutPoint, UtxoEntry>,
+    // multiset hash of the UTXO set, updated along with it
+    utxo_commitment: UtxoCommitment,
     blocks: Vec<Block>,
-    #[serde(default, skip_serializing)]
+    // height of every block of the active chain, keyed by
+    // its hash
+    heights: HashMap<Hash, usize>,
+    // cumulative work of the active chain up to and
+    // including each of its blocks
+    chainwork: Vec<U256>,
+    // undo data for every block of the active chain
+    undo: Vec<BlockUndo>,
+    // valid blocks on competing branches that are not
+    // part of the active chain, keyed by their hash
+    forks: HashMap<Hash, Block>,
     mempool: Vec<(DateTime<Utc>, Transaction)>,
+    // optional lookup indexes over the active chain, built
+    // from the blocks when they are enabled
+    tx_index: Option<TxIndex>,
+    address_index: Option<AddressIndex>,
+    // the snapshot the blockchain was started from, as long
+    // as the blocks up to it are only headers, see
+    // from_snapshot
+    snapshot: Option<UtxoSetSummary>,
+    // the UTXO set of that snapshot in canonical order, to
+    // be saved with the blockchain without rolling back to it
+    snapshot_utxos: Arc<Vec<(OutPoint, UtxoEntry)>>,
 }
 
 impl Blockchain {
-    pub fn new() -> Self {
+    // create a blockchain of the given network, containing
+    // only its genesis block
+    pub fn new(network: Network) -> Self {
+        let mut blockchain = Self::empty(network);
+        blockchain
+            .connect_block(
+                network.params().genesis_block.clone(),
+            )
+            .expect("BUG: invalid genesis block");
+
+        blockchain
+    }
+
+    // a blockchain of the given network without any blocks,
+    // not even the genesis block
+    fn empty(network: Network) -> Self {
         Blockchain {
+            network,
             utxos: HashMap::new(),
+            utxo_commitment: UtxoCommitment::new(),
             blocks: vec![],
-            target: crate::MIN_TARGET,
+            heights: HashMap::new(),
+            chainwork: vec![],
+            undo: vec![],
+            forks: HashMap::new(),
             mempool: vec![],
+            tx_index: None,
+            address_index: None,
+            snapshot: None,
+            snapshot_utxos: Arc::default(),
         }
     }
 
+    // network the blockchain belongs to
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    // consensus parameters of the blockchain's network
+    pub fn params(&self) -> &'static ChainParams {
+        self.network.params()
+    }
+
     // try to add a new block to the blockchain,
     // return an error if it is not valid to insert this
     // block to this blockchain
     pub fn add_block(&mut self, block: Block) -> Result<()> {
-        // check if the block is valid
-        if self.blocks.is_empty() {
-            // if this is the first block, check if the
-            // block's prev_block_hash is all zeroes
-            if block.header.prev_block_hash != Hash::zero() {
-                println!("zero hash");
-                return Err(BtcError::InvalidBlock);
-            }
-        } else {
-            // if this is not the first block, check if the
-            // block's prev_block_hash is the hash of the last block
-            let last_block = self.blocks.last().unwrap();
-            if block.header.prev_block_hash != last_block.hash()
-            {
-                println!("prev hash is wrong");
-                return Err(BtcError::InvalidBlock);
-            }
+        let hash = block.hash();
+        if self.forks.contains_key(&hash)
+            || self.height_of(&hash).is_some()
+        {
+            return Err(RejectReason::DuplicateBlock.into());
+        }
 
-            // check if the block's hash is less than the target
-            if !block
-                .header
-                .hash()
-                .matches_target(block.header.target)
-            {
-                println!("does not match target");
-                return Err(BtcError::InvalidBlock);
-            }
+        let tip = self
+            .blocks
+            .last()
+            .map(|last_block| last_block.hash())
+            .unwrap_or(Hash::zero());
 
-            // check if the block's merkle root is correct
-            let calculated_merkle_root =
-                MerkleRoot::calculate(&block.transactions);
-            if calculated_merkle_root != block.header.merkle_root
-            {
-                return Err(BtcError::InvalidMerkleRoot);
-            }
+        // the common case, the block extends the active chain
+        if block.header.prev_block_hash == tip {
+            return self.connect_block(block);
+        }
 
-            // check if the block's timestamp is after the
-            // last block's timestamp
-            if block.header.timestamp
-                <= last_block.header.timestamp
-            {
-                println!("old timestamp");
-                return Err(BtcError::InvalidBlock);
-            }
+        // only the genesis block has no parent, and the active
+        // chain always starts with it
+        if block.header.prev_block_hash == Hash::zero() {
+            Self::check_genesis(&block, self.params())?;
+            return Err(RejectReason::DuplicateBlock.into());
+        }
 
-            // Verify all transactions in the block
-            block.verify_transactions(
-                self.block_height(),
-                &self.utxos,
-            )?;
+        // otherwise it has to build on a block we already
+        // know, either on the active chain or on a fork
+        let Some(parent) =
+            self.find_block(&block.header.prev_block_hash)
+        else {
+            return Err(RejectReason::PrevBlockNotFound.into());
+        };
+
+        // transactions can only be checked once the branch
+        // is connected, so check everything else now
+        self.check_block(&block, parent)?;
+
+        debug!("block {} added to a side branch", hash);
+        self.forks.insert(hash, block);
+        self.try_reorganize(hash)
+    }
+
+    // check a block against its parent, without looking at
+    // the validity of its transactions
+    fn check_block(
+        &self,
+        block: &Block,
+        parent: &Block,
+    ) -> Result<()> {
+        let parent_hash = parent.hash();
+        if block.header.prev_block_hash != parent_hash {
+            return Err(RejectReason::BadPrevBlock.into());
+        }
+
+        if block.serialized_size() > crate::MAX_BLOCK_SIZE {
+            return Err(RejectReason::BlockTooLarge.into());
         }
 
-        // Remove transactions from mempool that are now in the block
+        // proof of work, target and timestamp have to match
+        // what its ancestors require, whether it extends the
+        // active chain or a fork
+        let params = self.params();
+        let (height, ancestors) = self
+            .ancestor_headers(&parent_hash, context_len(params))
+            .expect("BUG: parent is not a known block");
+        check_header(&block.header, height, &ancestors, params)?;
+
+        // check if the block's merkle root is correct
+        block.verify_merkle_root()
+    }
+
+    // median timestamp of the last crate::MEDIAN_TIME_SPAN
+    // blocks of the active chain. Timestamps of new blocks
+    // have to be after it
+    pub fn median_time_past(&self) -> DateTime<Utc> {
+        let tip = self
+            .blocks
+            .last()
+            .expect("BUG: blockchain without genesis block")
+            .hash();
+        self.median_time_past_of(&tip)
+            .expect("BUG: tip is not a known block")
+    }
+
+    // median timestamp of the given block and its ancestors,
+    // crate::MEDIAN_TIME_SPAN blocks in total, following
+    // forks back to the active chain
+    fn median_time_past_of(
+        &self,
+        hash: &Hash,
+    ) -> Option<DateTime<Utc>> {
+        let (_, headers) = self
+            .ancestor_headers(hash, crate::MEDIAN_TIME_SPAN)?;
+        Some(median_time_past(&headers))
+    }
+
+    // check that a block is the network's genesis block. Its
+    // proof of work is checked once, when the network's
+    // params are built
+    fn check_genesis(
+        block: &Block,
+        params: &ChainParams,
+    ) -> Result<()> {
+        if block.hash() != params.genesis_block.hash() {
+            return Err(RejectReason::BadGenesis.into());
+        }
+
+        // the header only commits to the transactions through
+        // the merkle root
+        block.verify_merkle_root()
+    }
+
+    // validate a block extending the current tip and make it
+    // the new tip, updating the UTXO set and the mempool
+    fn connect_block(&mut self, block: Block) -> Result<()> {
+        // check if the block is valid
+        match self.blocks.last() {
+            Some(last_block) => {
+                self.check_block(&block, last_block)?
+            }
+            None => Self::check_genesis(&block, self.params())?,
+        }
+
+        // Verify all transactions in the block
+        block.verify_transactions(
+            self.block_height(),
+            &self.utxos,
+            self.params(),
+        )?;
+
+        // Remove transactions from mempool that are now in the
+        // block, or that spend the same outputs as the block
         let block_transactions: HashSet<_> = block
             .transactions
             .iter()
             .map(|tx| tx.hash())
             .collect();
+        let spent_outputs: HashSet<_> = block
+            .transactions
+            .iter()
+            .flat_map(|tx| tx.inputs.iter())
+            .map(|input| input.prev_output)
+            .collect();
+        let mut utxos_to_unmark: Vec<OutPoint> = vec![];
         self.mempool.retain(|(_, tx)| {
-            !block_transactions.contains(&tx.hash())
+            if block_transactions.contains(&tx.hash()) {
+                return false;
+            }
+
+            let conflicts = tx.inputs.iter().any(|input| {
+                spent_outputs.contains(&input.prev_output)
+            });
+            if conflicts {
+                utxos_to_unmark.extend(
+                    tx.inputs
+                        .iter()
+                        .map(|input| input.prev_output),
+                );
+            }
+
+            !conflicts
         });
 
+        let height = self.block_height();
+        let spent_outputs = Self::apply_block(
+            &mut self.utxos,
+            &mut self.utxo_commitment,
+            &block,
+            height,
+        )
+        .expect("BUG: valid block spends a missing output");
+        self.undo.push(BlockUndo { spent_outputs });
+        self.index_block(&block, height);
+
+        // unmark the UTXOs of evicted conflicting transactions
+        // that have not been spent by the block
+        for outpoint in utxos_to_unmark {
+            self.utxos.entry(outpoint).and_modify(|entry| {
+                entry.marked = false;
+            });
+        }
+
+        self.chainwork.push(
+            self.chainwork().saturating_add(block.header.work()),
+        );
+        self.heights
+            .insert(block.hash(), self.blocks.len());
         self.blocks.push(block);
-        self.try_adjust_target();
 
         Ok(())
     }
 
-    // try to adjust the target of the blockchain
-    pub fn try_adjust_target(&mut self) {
-        if self.blocks.len()
-            < crate::DIFFICULTY_UPDATE_INTERVAL as usize
+    // spend the inputs and add the outputs of all of the
+    // block's transactions to a UTXO set and its commitment,
+    // returning the spent outputs. None if an input is not in
+    // the UTXO set, which is then left half updated
+    fn apply_block(
+        utxos: &mut HashMap<OutPoint, UtxoEntry>,
+        commitment: &mut UtxoCommitment,
+        block: &Block,
+        height: u64,
+    ) -> Option<Vec<(OutPoint, UtxoEntry)>> {
+        let mut spent_outputs = vec![];
+
+        for (tx_index, transaction) in
+            block.transactions.iter().enumerate()
         {
-            return;
+            for input in &transaction.inputs {
+                let outpoint = input.prev_output;
+                let entry = utxos.remove(&outpoint)?;
+                commitment.remove(&outpoint, &entry);
+                spent_outputs.push((outpoint, entry));
+            }
+
+            let txid = transaction.hash();
+            for (index, output) in
+                transaction.outputs.iter().enumerate()
+            {
+                let outpoint = OutPoint::new(txid, index as u32);
+                let entry = UtxoEntry {
+                    output: output.clone(),
+                    height,
+                    timestamp: block.header.timestamp,
+                    is_coinbase: tx_index == 0,
+                    marked: false,
+                };
+                commitment.add(&outpoint, &entry);
+                utxos.insert(outpoint, entry);
+            }
         }
 
-        if self.blocks.len()
-            % crate::DIFFICULTY_UPDATE_INTERVAL as usize
-            != 0
-        {
-            println!("won't update on this block");
-            return;
-        }
-
-        println!("actually updating");
-        // measure the time it took to mine the last
-        // crate::DIFFICULTY_UPDATE_INTERVAL blocks
-        // with chrono
-        let start_time = self.blocks[self.blocks.len()
-            - crate::DIFFICULTY_UPDATE_INTERVAL as usize]
-            .header
-            .timestamp;
-        let end_time =
-            self.blocks.last().unwrap().header.timestamp;
-        let time_diff = end_time - start_time;
-
-        // convert time_diff to seconds
-        let time_diff_seconds = time_diff.num_seconds();
-        // calculate the ideal number of seconds
-        let target_seconds = crate::IDEAL_BLOCK_TIME
-            * crate::DIFFICULTY_UPDATE_INTERVAL;
-
-        // multiply the current target by actual time divided by ideal time
-        let new_target = BigDecimal::parse_bytes(
-            &self.target.to_string().as_bytes(),
-            10,
-        )
-        .expect("BUG: impossible")
-            * (BigDecimal::from(time_diff_seconds)
-                / BigDecimal::from(target_seconds));
-
-        // cut off decimal point and everything after
-        // it from string representation of new_target
-        let new_target_str = new_target
-            .to_string()
-            .split('.')
-            .next()
-            .expect("BUG: Expected a decimal point")
-            .to_owned();
-
-        let new_target: U256 =
-            U256::from_str_radix(&new_target_str, 10)
-                .expect("BUG: impossible");
-
-        dbg!(new_target);
-
-        // clamp new_target to be within the range of
-        // 4 * self.target and self.target / 4
-        let new_target = if new_target < self.target / 4 {
-            dbg!(self.target / 4)
-        } else if new_target > self.target * 4 {
-            dbg!(self.target * 4)
-        } else {
-            new_target
-        };
+        Some(spent_outputs)
+    }
+
+    // disconnect the tip of the active chain, restoring the
+    // UTXO set from its undo data. Mempool transactions
+    // spending its outputs are evicted. The block is returned
+    // so that its transactions can be resubmitted to the
+    // mempool
+    pub fn disconnect_tip(&mut self) -> Option<Block> {
+        // the genesis block cannot be disconnected, and
+        // neither can the last block of the snapshot the
+        // blockchain was started from, which has no undo data
+        if self.block_height() <= self.first_block_with_undo() {
+            return None;
+        }
+
+        let block = self.blocks.pop()?;
+        self.heights.remove(&block.hash());
+        self.chainwork.pop();
+        let undo =
+            self.undo.pop().expect("BUG: missing undo data");
+        Self::unapply_block(
+            &mut self.utxos,
+            &mut self.utxo_commitment,
+            &block,
+            undo.spent_outputs,
+        );
 
-        dbg!(new_target);
+        if let Some(index) = &mut self.tx_index {
+            index.disconnect(&block);
+        }
+        if let Some(index) = &mut self.address_index {
+            index.disconnect(&block);
+        }
 
-        // if the new target is more than the minimum target,
-        // set it to the minimum target
-        self.target = new_target.min(crate::MIN_TARGET);
-        dbg!(self.target);
+        // evict mempool transactions whose inputs are gone or
+        // are coinbase outputs which are not mature anymore
+        let height = self.block_height();
+        let params = self.params();
+        let mut utxos_to_unmark: Vec<OutPoint> = vec![];
+        self.mempool.retain(|(_, tx)| {
+            let valid = tx.inputs.iter().all(|input| {
+                self.utxos.get(&input.prev_output).is_some_and(
+                    |entry| entry.is_mature(height, params),
+                )
+            });
+            if !valid {
+                utxos_to_unmark.extend(
+                    tx.inputs
+                        .iter()
+                        .map(|input| input.prev_output),
+                );
+            }
+
+            valid
+        });
+
+        for outpoint in utxos_to_unmark {
+            self.utxos.entry(outpoint).and_modify(|entry| {
+                entry.marked = false;
+            });
+        }
+
+        Some(block)
     }
 
-    // Rebuild UTXO set from the blockchain
-    pub fn rebuild_utxos(&mut self) {
-        for block in &self.blocks {
-            for transaction in &block.transactions {
-                for input in &transaction.inputs {
-                    self.utxos.remove(
-                        &input.prev_transaction_output_hash,
+    // undo apply_block: remove the outputs of the block's
+    // transactions from a UTXO set and its commitment, and
+    // add back the outputs they spent
+    fn unapply_block(
+        utxos: &mut HashMap<OutPoint, UtxoEntry>,
+        commitment: &mut UtxoCommitment,
+        block: &Block,
+        spent_outputs: Vec<(OutPoint, UtxoEntry)>,
+    ) {
+        for transaction in block.transactions.iter().rev() {
+            let txid = transaction.hash();
+            for index in 0..transaction.outputs.len() {
+                let outpoint = OutPoint::new(txid, index as u32);
+                if let Some(entry) = utxos.remove(&outpoint) {
+                    commitment.remove(&outpoint, &entry);
+                }
+            }
+        }
+
+        for (outpoint, mut entry) in spent_outputs {
+            entry.marked = false;
+            commitment.add(&outpoint, &entry);
+            utxos.insert(outpoint, entry);
+        }
+    }
+
+    // height of the first block of the active chain that can
+    // be disconnected
+    fn first_block_with_undo(&self) -> u64 {
+        self.snapshot.map_or(1, |snapshot| snapshot.height + 1)
+    }
+
+    // check if the branch ending in the given fork block has
+    // more cumulative work than the active chain, and switch
+    // to it if it does
+    fn try_reorganize(
+        &mut self,
+        branch_tip: Hash,
+    ) -> Result<()> {
+        // walk back from the tip of the branch until we reach
+        // the block where it forks off the active chain
+        let mut branch = vec![];
+        let mut cursor = branch_tip;
+        while let Some(block) = self.forks.get(&cursor) {
+            branch.push(cursor);
+            cursor = block.header.prev_block_hash;
+        }
+        branch.reverse();
+
+        let fork_point = self
+            .height_of(&cursor)
+            .expect("BUG: fork does not connect to the chain")
+            + 1;
+
+        // the blocks up to a snapshot are assumed to be valid,
+        // a branch forking off before it is not
+        if (fork_point as u64) < self.first_block_with_undo() {
+            warn!("ignoring a branch below the snapshot");
+            return Ok(());
+        }
+
+        let branch_work = branch
+            .iter()
+            .map(|hash| self.forks[hash].header.work())
+            .fold(
+                self.chainwork[fork_point - 1],
+                |acc, work| acc.saturating_add(work),
+            );
+
+        // on a tie, stay on the branch we have seen first
+        if branch_work <= self.chainwork() {
+            return Ok(());
+        }
+
+        self.reorganize(fork_point, branch)
+    }
+
+    // disconnect all blocks of the active chain from the given
+    // height and connect the given fork blocks instead. If any
+    // of the fork blocks turns out to be invalid, the previous
+    // active chain is restored
+    fn reorganize(
+        &mut self,
+        fork_point: usize,
+        branch: Vec<Hash>,
+    ) -> Result<()> {
+        info!(
+            "reorganizing: disconnecting {} blocks, \
+            connecting {} blocks",
+            self.blocks.len() - fork_point,
+            branch.len()
+        );
+
+        let mempool = std::mem::take(&mut self.mempool);
+        let mut disconnected = vec![];
+        while self.blocks.len() > fork_point {
+            disconnected.push(
+                self.disconnect_tip()
+                    .expect("BUG: chain is below fork point"),
+            );
+        }
+        disconnected.reverse();
+
+        for hash in &branch {
+            let block = self
+                .forks
+                .remove(hash)
+                .expect("BUG: branch block is missing");
+
+            if let Err(e) = self.connect_block(block) {
+                warn!("invalid block on the new branch: {e}");
+
+                // blocks of the branch that connected fine stay
+                // around, the invalid one and everything built
+                // on top of it is dropped
+                while self.blocks.len() > fork_point {
+                    let block = self.disconnect_tip().expect(
+                        "BUG: chain is below fork point",
                     );
+                    self.forks.insert(block.hash(), block);
                 }
+                self.remove_fork_descendants(*hash);
 
-                for output in transaction.outputs.iter() {
-                    self.utxos.insert(
-                        output.hash(),
-                        (false, output.clone()),
+                for block in disconnected {
+                    self.connect_block(block).expect(
+                        "BUG: previously connected block \
+                        is invalid",
                     );
                 }
+                self.resubmit_transactions(mempool);
+
+                return Err(e);
+            }
+        }
+
+        // transactions of the disconnected blocks go back to
+        // the mempool, except for the coinbase transactions
+        let mut transactions = vec![];
+        for block in disconnected {
+            transactions.extend(
+                block
+                    .transactions
+                    .iter()
+                    .skip(1)
+                    .map(|tx| (Utc::now(), tx.clone())),
+            );
+            self.forks.insert(block.hash(), block);
+        }
+        transactions.extend(mempool);
+        self.resubmit_transactions(transactions);
+
+        Ok(())
+    }
+
+    // try to add transactions back to the mempool, dropping
+    // those that are no longer valid
+    fn resubmit_transactions(
+        &mut self,
+        transactions: Vec<(DateTime<Utc>, Transaction)>,
+    ) {
+        for (_, transaction) in transactions {
+            if let Err(e) = self.add_to_mempool(transaction) {
+                debug!("dropping transaction from mempool: {e}");
+            }
+        }
+    }
+
+    // remove a fork block and all fork blocks built on it
+    fn remove_fork_descendants(&mut self, hash: Hash) {
+        let mut removed = HashSet::from([hash]);
+        self.forks.remove(&hash);
+
+        loop {
+            let children: Vec<Hash> = self
+                .forks
+                .iter()
+                .filter(|(_, block)| {
+                    removed
+                        .contains(&block.header.prev_block_hash)
+                })
+                .map(|(hash, _)| *hash)
+                .collect();
+
+            if children.is_empty() {
+                break;
+            }
+
+            for child in children {
+                self.forks.remove(&child);
+                removed.insert(child);
             }
         }
     }
 
-    pub fn calculate_block_reward(&self) -> u64 {
-        let block_height = self.block_height();
-        let halvings = block_height / crate::HALVING_INTERVAL;
+    // find the position of a block on the active chain
+    fn height_of(&self, hash: &Hash) -> Option<usize> {
+        self.heights.get(hash).copied()
+    }
+
+    // find a block on the active chain or on one of the forks
+    fn find_block(&self, hash: &Hash) -> Option<&Block> {
+        self.forks.get(hash).or_else(|| {
+            self.height_of(hash)
+                .map(|height| &self.blocks[height])
+        })
+    }
+
+    // height of the block after the given one, and the
+    // headers of the given block and its ancestors, at most
+    // count of them and oldest first, following forks back
+    // to the active chain
+    fn ancestor_headers(
+        &self,
+        hash: &Hash,
+        count: usize,
+    ) -> Option<(u64, Vec<BlockHeader>)> {
+        let mut headers = vec![];
+        let mut fork_blocks = 0;
+        let mut cursor = *hash;
+        while let Some(block) = self.forks.get(&cursor) {
+            if headers.len() < count {
+                headers.push(block.header.clone());
+            }
+            fork_blocks += 1;
+            cursor = block.header.prev_block_hash;
+        }
 
-        if halvings >= 64 {
-            // After 64 halvings, the reward becomes 0
-            0
+        // the rest of the ancestors are on the active chain
+        let height = self.height_of(&cursor)? + 1;
+        headers.extend(
+            self.blocks[..height]
+                .iter()
+                .rev()
+                .take(count - headers.len())
+                .map(|block| block.header.clone()),
+        );
+        headers.reverse();
+
+        Some(((height + fork_blocks) as u64, headers))
+    }
+
+    // Rebuild UTXO set, undo data and indexes from the
+    // blockchain
+    pub fn rebuild_utxos(&mut self) {
+        // there are no transactions to replay up to a
+        // snapshot, so roll back to it with the undo data
+        // instead of starting over from the empty set
+        let blocks = if self.snapshot.is_some() {
+            let mut blocks = vec![];
+            while let Some(block) = self.disconnect_tip() {
+                blocks.push(block);
+            }
+            blocks.reverse();
+            blocks
         } else {
-            (crate::INITIAL_REWARD * 10u64.pow(8)) >> halvings
+            self.utxos.clear();
+            self.utxo_commitment = UtxoCommitment::new();
+            self.heights.clear();
+            self.chainwork.clear();
+            self.undo.clear();
+            std::mem::take(&mut self.blocks)
+        };
+
+        if let Some(index) = &mut self.tx_index {
+            *index = TxIndex::default();
+        }
+        if let Some(index) = &mut self.address_index {
+            *index = AddressIndex::default();
         }
+
+        self.replay_blocks(blocks)
+            .expect("BUG: the active chain does not replay");
     }
 
-    // utxos
-    pub fn utxos(
+    // extend the active chain with blocks that were valid
+    // when they were added, without checking their proof of
+    // work and scripts again. Blocks which do not link up,
+    // do not match their merkle root or spend missing outputs
+    // are damaged, e.g. in a saved file
+    fn replay_blocks(
+        &mut self,
+        blocks: impl IntoIterator<Item = Block>,
+    ) -> IoResult<()> {
+        for block in blocks {
+            let tip = self
+                .blocks
+                .last()
+                .map_or(Hash::zero(), |tip| tip.hash());
+            if block.header.prev_block_hash != tip {
+                return Err(invalid_data(
+                    "block does not extend the chain",
+                ));
+            }
+            if block.verify_merkle_root().is_err() {
+                return Err(invalid_data("invalid merkle root"));
+            }
+            if self.blocks.is_empty()
+                && block.hash()
+                    != self.params().genesis_block.hash()
+            {
+                return Err(invalid_data("wrong genesis block"));
+            }
+
+            let height = self.block_height();
+            let Some(spent_outputs) = Self::apply_block(
+                &mut self.utxos,
+                &mut self.utxo_commitment,
+                &block,
+                height,
+            ) else {
+                return Err(invalid_data(
+                    "block spends a missing output",
+                ));
+            };
+            self.undo.push(BlockUndo { spent_outputs });
+            self.index_block(&block, height);
+
+            self.chainwork.push(
+                self.chainwork()
+                    .saturating_add(block.header.work()),
+            );
+            self.heights
+                .insert(block.hash(), self.blocks.len());
+            self.blocks.push(block);
+        }
+
+        Ok(())
+    }
+
+    // add a block connected to the active chain to the
+    // enabled indexes
+    fn index_block(&mut self, block: &Block, height: u64) {
+        if let Some(index) = &mut self.tx_index {
+            index.connect(block, height);
+        }
+        if let Some(index) = &mut self.address_index {
+            index.connect(block, height);
+        }
+    }
+
+    // maintain a txid -> location index of the active chain
+    // from now on, see find_transaction
+    pub fn enable_tx_index(&mut self) {
+        let mut index = TxIndex::default();
+        for (height, block) in self.blocks.iter().enumerate() {
+            index.connect(block, height as u64);
+        }
+        self.tx_index = Some(index);
+    }
+
+    // maintain a per-key history of the outputs of the active
+    // chain from now on, see history
+    pub fn enable_address_index(&mut self) {
+        let mut index = AddressIndex::default();
+        for (height, block) in self.blocks.iter().enumerate() {
+            index.connect(block, height as u64);
+        }
+        self.address_index = Some(index);
+    }
+
+    // look up a transaction of the active chain, None if it
+    // is unknown or the transaction index is not enabled
+    pub fn find_transaction(
         &self,
-    ) -> &HashMap<Hash, (bool, TransactionOutput)> {
+        txid: &Hash,
+    ) -> Option<(TxLocation, &Transaction)> {
+        let location = self.tx_index.as_ref()?.get(txid)?;
+        let transaction = self.blocks[location.height as usize]
+            .transactions
+            .get(location.position as usize)?;
+        Some((location, transaction))
+    }
+
+    // all outputs of the active chain paying to the key, and
+    // their spends, oldest first. None if the address index
+    // is not enabled
+    pub fn history(
+        &self,
+        public_key: &PublicKey,
+    ) -> Option<Vec<HistoryEntry>> {
+        Some(self.address_index.as_ref()?.history(public_key))
+    }
+
+    // unspent outputs paying to the key, looked up in the
+    // address index if it is enabled. Until the blocks up to
+    // a snapshot are validated, the index misses the outputs
+    // of the snapshot, so the whole UTXO set is searched
+    pub fn utxos_of(
+        &self,
+        public_key: &PublicKey,
+    ) -> Vec<(OutPoint, &UtxoEntry)> {
+        let index = self
+            .address_index
+            .as_ref()
+            .filter(|_| self.snapshot.is_none());
+        match index {
+            Some(index) => index
+                .history(public_key)
+                .into_iter()
+                .filter_map(|entry| {
+                    self.utxos
+                        .get(&entry.outpoint)
+                        .map(|utxo| (entry.outpoint, utxo))
+                })
+                .collect(),
+            None => self
+                .utxos
+                .iter()
+                .filter(|(_, entry)| {
+                    entry
+                        .output
+                        .locking_script
+                        .pays_to(public_key)
+                })
+                .map(|(outpoint, entry)| (*outpoint, entry))
+                .collect(),
+        }
+    }
+
+    pub fn calculate_block_reward(&self) -> Amount {
+        self.params().block_reward(self.block_height())
+    }
+
+    // utxos
+    pub fn utxos(&self) -> &HashMap<OutPoint, UtxoEntry> {
         &self.utxos
     }
 
-    // target
+    // count, total value and commitment of the UTXO set at
+    // the tip of the active chain, to compare with other
+    // nodes
+    pub fn utxo_set_summary(&self) -> UtxoSetSummary {
+        let tip = self
+            .blocks
+            .last()
+            .expect("BUG: blockchain without genesis block");
+        UtxoSetSummary {
+            height: self.block_height() - 1,
+            tip: tip.hash(),
+            count: self.utxo_commitment.count(),
+            total_value: self.utxo_commitment.total_value(),
+            commitment: self.utxo_commitment.hash(),
+        }
+    }
+
+    // the UTXO set after the block at the given height of
+    // the active chain, with the headers up to it. None if
+    // there is no such block, or if the chain was started
+    // from a later snapshot
+    pub fn export_snapshot(
+        &self,
+        height: u64,
+    ) -> Option<UtxoSnapshot> {
+        let first = self.first_block_with_undo();
+        if height + 1 < first || height >= self.block_height() {
+            return None;
+        }
+
+        // roll a copy of the UTXO set back to the block
+        let mut utxos = self.utxos.clone();
+        let mut commitment = self.utxo_commitment;
+        for (block, undo) in self
+            .blocks
+            .iter()
+            .zip(&self.undo)
+            .skip(height as usize + 1)
+            .rev()
+        {
+            Self::unapply_block(
+                &mut utxos,
+                &mut commitment,
+                block,
+                undo.spent_outputs.clone(),
+            );
+        }
+
+        let snapshot = UtxoSnapshot {
+            headers: self.blocks[..=height as usize]
+                .iter()
+                .map(|block| block.header.clone())
+                .collect(),
+            utxos: canonical_order(utxos),
+        };
+
+        // the commitment was rolled back along with the UTXO
+        // set, so it has to match the exported outputs
+        let summary = snapshot
+            .summary()
+            .expect("BUG: snapshot without headers");
+        assert_eq!(
+            summary.commitment,
+            commitment.hash(),
+            "BUG: undo data does not match the UTXO set"
+        );
+
+        Some(snapshot)
+    }
+
+    // start a blockchain from a snapshot instead of the
+    // genesis block. The headers of the snapshot have to be
+    // valid and lead to the block of the known snapshot, and
+    // the UTXO set has to have its commitment. The blocks up
+    // to the snapshot are only headers until they have been
+    // validated, see complete_snapshot
+    pub fn from_snapshot(
+        network: Network,
+        snapshot: UtxoSnapshot,
+        assumed: &AssumedUtxo,
+    ) -> Result<Self> {
+        let params = network.params();
+        let mut headers = snapshot.headers.into_iter();
+        if headers.next().map(|header| header.hash())
+            != Some(params.genesis_block.hash())
+        {
+            return Err(BtcError::InvalidSnapshot);
+        }
+
+        let mut header_chain = HeaderChain::new(network);
+        header_chain.add_headers(headers)?;
+        if header_chain.height() != assumed.height + 1
+            || header_chain.tip().hash() != assumed.block_hash
+        {
+            return Err(BtcError::InvalidSnapshot);
+        }
+
+        let mut utxos = HashMap::new();
+        for (outpoint, entry) in snapshot.utxos {
+            if utxos.insert(outpoint, entry).is_some() {
+                return Err(BtcError::InvalidSnapshot);
+            }
+        }
+        let utxo_commitment = UtxoCommitment::from_utxos(&utxos);
+        if utxo_commitment.hash() != assumed.commitment {
+            return Err(BtcError::InvalidSnapshot);
+        }
+
+        let blocks = (0..header_chain.height()).map(|height| {
+            let header = header_chain
+                .header_at(height)
+                .expect("BUG: header chain is too short");
+            Block::new(header.clone(), vec![])
+        });
+        let blockchain =
+            Self::start_snapshot(network, blocks, utxos);
+
+        info!(
+            "started from the UTXO snapshot at height {}",
+            assumed.height
+        );
+        Ok(blockchain)
+    }
+
+    // a blockchain of the blocks up to a snapshot, which are
+    // only headers, and the UTXO set after them
+    fn start_snapshot(
+        network: Network,
+        blocks: impl IntoIterator<Item = Block>,
+        utxos: HashMap<OutPoint, UtxoEntry>,
+    ) -> Self {
+        let mut blockchain = Self::empty(network);
+        for block in blocks {
+            blockchain.chainwork.push(
+                blockchain
+                    .chainwork()
+                    .saturating_add(block.header.work()),
+            );
+            blockchain
+                .heights
+                .insert(block.hash(), blockchain.blocks.len());
+            blockchain.blocks.push(block);
+            blockchain.undo.push(BlockUndo {
+                spent_outputs: vec![],
+            });
+        }
+        blockchain.utxo_commitment =
+            UtxoCommitment::from_utxos(&utxos);
+        blockchain.snapshot_utxos =
+            Arc::new(canonical_order(utxos.clone()));
+        blockchain.utxos = utxos;
+        blockchain.snapshot =
+            Some(blockchain.utxo_set_summary());
+        blockchain
+    }
+
+    // the snapshot the blockchain was started from, if the
+    // blocks up to it have not been validated yet. Until
+    // then, blocks() only has their headers
+    pub fn snapshot(&self) -> Option<UtxoSetSummary> {
+        self.snapshot
+    }
+
+    // replace the headers up to the snapshot the blockchain
+    // was started from with the blocks of a blockchain
+    // validated from the genesis block up to the snapshot.
+    // Fails if the validated blockchain does not end up with
+    // the UTXO set of the snapshot
+    pub fn complete_snapshot(
+        &mut self,
+        validated: Blockchain,
+    ) -> Result<()> {
+        let Some(snapshot) = self.snapshot else {
+            return Ok(());
+        };
+        if validated.network != self.network
+            || validated.utxo_set_summary() != snapshot
+        {
+            return Err(BtcError::InvalidSnapshot);
+        }
+
+        let end = snapshot.height as usize + 1;
+        self.blocks.splice(..end, validated.blocks);
+        // the blocks have the hashes of the headers they
+        // replace, so their heights are already known
+        self.heights.extend(validated.heights);
+        self.undo.splice(..end, validated.undo);
+        self.snapshot = None;
+        self.snapshot_utxos = Arc::default();
+
+        // the indexes can now cover the whole chain
+        if self.tx_index.is_some() {
+            self.enable_tx_index();
+        }
+        if self.address_index.is_some() {
+            self.enable_address_index();
+        }
+
+        info!(
+            "UTXO snapshot at height {} validated",
+            snapshot.height
+        );
+        Ok(())
+    }
+
+    // target the next block on the active chain has to have
     pub fn target(&self) -> U256 {
-        self.target
+        let tip = self
+            .blocks
+            .last()
+            .expect("BUG: blockchain without genesis block")
+            .hash();
+        let params = self.params();
+        let window = params.difficulty_algorithm.window(params);
+        let (height, headers) = self
+            .ancestor_headers(&tip, window)
+            .expect("BUG: tip is not a known block");
+        required_target(height, &headers, params)
     }
 
-    // blocks
+    // hashes of the active chain to send in GetHeaders, see
+    // HeaderChain::locator
+    pub fn locator(&self) -> Vec<Hash> {
+        let hashes: Vec<Hash> = self
+            .blocks
+            .iter()
+            .map(|block| block.hash())
+            .collect();
+        locator(&hashes)
+    }
+
+    // headers of the active chain following the first
+    // locator hash that is on it, at most max of them
+    pub fn headers_after(
+        &self,
+        locator: &[Hash],
+        max: usize,
+    ) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.height_of(hash))
+            .map_or(0, |height| height + 1);
+
+        self.blocks
+            .iter()
+            .skip(start)
+            .take(max)
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    // cumulative work of the active chain
+    pub fn chainwork(&self) -> U256 {
+        self.chainwork.last().copied().unwrap_or_default()
+    }
+
+    // cumulative work of the active chain up to and including
+    // the block at the given height
+    pub fn chainwork_at(&self, height: u64) -> Option<U256> {
+        self.chainwork.get(height as usize).copied()
+    }
+
+    // blocks of the active chain, only headers up to an
+    // unvalidated snapshot, see snapshot
     pub fn blocks(&self) -> impl Iterator<Item = &Block> {
         self.blocks.iter()
     }
 
+    // blocks on competing branches
+    pub fn forks(&self) -> impl Iterator<Item = &Block> {
+        self.forks.values()
+    }
+
     // block height
     pub fn block_height(&self) -> u64 {
         self.blocks.len() as u64
@@ -242,32 +1187,158 @@ impl Blockchain {
         &self.mempool
     }
 
-    // add a transaction to mempool
+    // check if the lock time and the relative locks of a
+    // transaction allow it to be included in the next block,
+    // mined at the given time
+    pub fn is_final(
+        &self,
+        transaction: &Transaction,
+        time: DateTime<Utc>,
+    ) -> bool {
+        let height = self.block_height();
+        transaction.is_final(height, time)
+            && transaction.inputs.iter().all(|input| {
+                self.utxos.get(&input.prev_output).is_some_and(
+                    |entry| input.is_final(entry, height, time),
+                )
+            })
+    }
+
+    // a block on top of the active chain, paying its reward
+    // and fees to the given script, to be mined at the given
+    // time. Mempool transactions are picked best fee rate
+    // first, skipping the ones that are still locked or do
+    // not fit anymore
+    pub fn block_template(
+        &self,
+        locking_script: Script,
+        now: DateTime<Utc>,
+    ) -> Result<Block> {
+        // the timestamp has to be after the median time past,
+        // even if our clock is behind
+        let timestamp = now.max(
+            self.median_time_past()
+                + chrono::Duration::seconds(1),
+        );
+
+        // the reward is filled in once the fees are known
+        let coinbase = Transaction::coinbase(
+            self.block_height(),
+            vec![TransactionOutput {
+                locking_script,
+                value: Amount::ZERO,
+            }],
+        );
+
+        let tip = self
+            .blocks
+            .last()
+            .expect("BUG: blockchain without genesis block");
+        let mut block = Block::new(
+            BlockHeader {
+                timestamp,
+                prev_block_hash: tip.hash(),
+                nonce: 0,
+                bits: self.target().to_compact(),
+                // recalculated once the block is complete
+                merkle_root: MerkleRoot::calculate(
+                    std::slice::from_ref(&coinbase),
+                ),
+            },
+            vec![coinbase],
+        );
+
+        // leave room for the final reward and the growing
+        // length of the transaction list
+        let mut block_size =
+            block.serialized_size() + TEMPLATE_SIZE_MARGIN;
+        for (_, transaction) in &self.mempool {
+            if !self.is_final(transaction, timestamp) {
+                continue;
+            }
+
+            let size = transaction.serialized_size();
+            if block_size + size > crate::MAX_BLOCK_SIZE {
+                continue;
+            }
+
+            block_size += size;
+            block.transactions.push(transaction.clone());
+        }
+
+        let miner_fees =
+            block.calculate_miner_fees(&self.utxos)?;
+        block.transactions[0].outputs[0].value = self
+            .calculate_block_reward()
+            .checked_add(miner_fees)
+            .ok_or(BtcError::InvalidAmount)?;
+        block.header.merkle_root =
+            MerkleRoot::calculate(&block.transactions);
+
+        Ok(block)
+    }
+
+    // add a transaction to mempool. Transactions that are
+    // not final yet are accepted, but held back from block
+    // templates until they are, see Blockchain::is_final
     pub fn add_to_mempool(
         &mut self,
         transaction: Transaction,
     ) -> Result<()> {
         // validate transaction before insertion
+        // coinbase transactions only come in blocks
+        if transaction.coinbase_height != 0 {
+            return Err(RejectReason::Coinbase.into());
+        }
+        if transaction.inputs.is_empty() {
+            return Err(RejectReason::NoInputs.into());
+        }
+
+        // a transaction that does not fit in a block can never
+        // be mined
+        if transaction.serialized_size() >= crate::MAX_BLOCK_SIZE
+        {
+            return Err(RejectReason::TransactionTooLarge.into());
+        }
+
         // all inputs must match known UTXOs, and must be unique
         let mut known_inputs = HashSet::new();
-        for input in &transaction.inputs {
-            if !self.utxos.contains_key(
-                &input.prev_transaction_output_hash,
-            ) {
-                println!("UTXO not found");
-                dbg!(&self.utxos);
-                return Err(BtcError::InvalidTransaction);
-            }
+        for (index, input) in
+            transaction.inputs.iter().enumerate()
+        {
+            let Some(entry) = self.utxos.get(&input.prev_output)
+            else {
+                return Err(RejectReason::MissingInput.into());
+            };
+            let prev_output = &entry.output;
 
-            if known_inputs
-                .contains(&input.prev_transaction_output_hash)
+            // coinbase outputs have to be mature in the next
+            // block already
+            if !entry
+                .is_mature(self.block_height(), self.params())
             {
-                println!("duplicate input");
-                return Err(BtcError::InvalidTransaction);
+                return Err(
+                    RejectReason::PrematureCoinbaseSpend.into(),
+                );
+            }
+
+            // check if the unlocking script satisfies the
+            // output's locking script
+            let context = ScriptContext {
+                transaction: &transaction,
+                input_index: index,
+            };
+            script::verify(
+                &input.unlocking_script,
+                &prev_output.locking_script,
+                &context,
+            )?;
+
+            if known_inputs.contains(&input.prev_output) {
+                return Err(RejectReason::DuplicateInput.into());
             }
 
-            known_inputs
-                .insert(input.prev_transaction_output_hash);
+            known_inputs.insert(input.prev_output);
         }
 
         // check if any of the utxos have the bool mark set to true
@@ -275,26 +1346,22 @@ impl Blockchain {
         // in mempool, remove it, and set all the utxos it references
         // to false
         for input in &transaction.inputs {
-            if let Some((true, _)) = self
-                .utxos
-                .get(&input.prev_transaction_output_hash)
+            if let Some(UtxoEntry { marked: true, .. }) =
+                self.utxos.get(&input.prev_output)
             {
                 // find the transaction that references the UTXO
                 // we are trying to reference
-                let referencing_transaction = self.mempool
-            .iter()
-            .enumerate()
-            .find(
-                |(_, (_, transaction))| {
-                    transaction
-                        .outputs
-                        .iter()
-                        .any(|output| {
-                            output.hash()
-                                == input.prev_transaction_output_hash
-                        })
-                },
-            );
+                let referencing_transaction =
+                    self.mempool.iter().enumerate().find(
+                        |(_, (_, transaction))| {
+                            transaction.inputs.iter().any(
+                                |mempool_input| {
+                                    mempool_input.prev_output
+                                        == input.prev_output
+                                },
+                            )
+                        },
+                    );
 
                 // If we have found one, unmark all of its UTXOs
                 if let Some((
@@ -306,10 +1373,10 @@ impl Blockchain {
                     {
                         // set all utxos from this transaction to false
                         self.utxos
-                        .entry(input.prev_transaction_output_hash)
-                        .and_modify(|(marked, _)| {
-                            *marked = false;
-                        });
+                            .entry(input.prev_output)
+                            .and_modify(|entry| {
+                                entry.marked = false;
+                            });
                     }
 
                     // remove the transaction from the mempool
@@ -318,73 +1385,40 @@ impl Blockchain {
                     // if, somehow, there is no matching transaction,
                     // set this utxo to false
                     self.utxos
-                        .entry(
-                            input.prev_transaction_output_hash,
-                        )
-                        .and_modify(|(marked, _)| {
-                            *marked = false;
+                        .entry(input.prev_output)
+                        .and_modify(|entry| {
+                            entry.marked = false;
                         });
                 }
             }
         }
 
         // all inputs must be lower than all outputs
-        let all_inputs = transaction
-            .inputs
-            .iter()
-            .map(|input| {
-                self.utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .expect("BUG: impossible")
-                    .1
-                    .value
-            })
-            .sum::<u64>();
-        let all_outputs = transaction
-            .outputs
-            .iter()
-            .map(|output| output.value)
-            .sum();
-
-        if all_inputs < all_outputs {
-            print!("inputs are lower than outputs");
-            return Err(BtcError::InvalidTransaction);
-        }
+        transaction_fee(&self.utxos, &transaction)?;
 
         // Mark the UTXOs as used
         for input in &transaction.inputs {
-            self.utxos
-                .entry(input.prev_transaction_output_hash)
-                .and_modify(|(marked, _)| {
-                    *marked = true;
-                });
+            self.utxos.entry(input.prev_output).and_modify(
+                |entry| {
+                    entry.marked = true;
+                },
+            );
         }
 
         // push the transaction to the mempool
         self.mempool.push((Utc::now(), transaction));
 
-        // sort by miner fee
+        // sort by miner fee per kilobyte, highest first, so
+        // that block templates pick the most profitable
+        // transactions that fit
+        let utxos = &self.utxos;
         self.mempool.sort_by_key(|(_, transaction)| {
-            let all_inputs = transaction
-                .inputs
-                .iter()
-                .map(|input| {
-                    self.utxos
-                        .get(&input.prev_transaction_output_hash)
-                        .expect("BUG: impossible")
-                        .1
-                        .value
-                })
-                .sum::<u64>();
-
-            let all_outputs: u64 = transaction
-                .outputs
-                .iter()
-                .map(|output| output.value)
-                .sum();
-
-            let miner_fee = all_inputs - all_outputs;
-            miner_fee
+            let miner_fee = transaction_fee(utxos, transaction)
+                .unwrap_or(Amount::ZERO);
+            Reverse(
+                miner_fee.to_sat() * 1000
+                    / transaction.serialized_size() as u64,
+            )
         });
 
         Ok(())
@@ -394,7 +1428,7 @@ impl Blockchain {
     // MAX_MEMPOOL_TRANSACTION_AGE
     pub fn cleanup_mempool(&mut self) {
         let now = Utc::now();
-        let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
+        let mut utxos_to_unmark: Vec<OutPoint> = vec![];
 
         self.mempool.retain(|(timestamp, transaction)| {
             if now - *timestamp
@@ -404,10 +1438,11 @@ impl Blockchain {
             {
                 // push all utxos to unmark to the vector
                 // so we can unmark them later
-                utxo_hashes_to_unmark.extend(
-                    transaction.inputs.iter().map(|input| {
-                        input.prev_transaction_output_hash
-                    }),
+                utxos_to_unmark.extend(
+                    transaction
+                        .inputs
+                        .iter()
+                        .map(|input| input.prev_output),
                 );
 
                 false
@@ -417,31 +1452,119 @@ impl Blockchain {
         });
 
         // unmark all of the UTXOs
-        for hash in utxo_hashes_to_unmark {
-            self.utxos.entry(hash).and_modify(|(marked, _)| {
-                *marked = false;
+        for outpoint in utxos_to_unmark {
+            self.utxos.entry(outpoint).and_modify(|entry| {
+                entry.marked = false;
             });
         }
     }
 }
 
-// save and load expecting CBOR from ciborium as format
-impl Saveable for Blockchain {
-    fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader).map_err(|_| {
-            IoError::new(
-                IoErrorKind::InvalidData,
-                "Failed to deserialize Blockchain",
-            )
+// fee paid by a transaction spending the given utxos, the
+// difference between the value of its inputs and outputs
+fn transaction_fee(
+    utxos: &HashMap<OutPoint, UtxoEntry>,
+    transaction: &Transaction,
+) -> Result<Amount> {
+    let input_values = transaction
+        .inputs
+        .iter()
+        .map(|input| {
+            utxos
+                .get(&input.prev_output)
+                .map(|entry| entry.output.value)
+                .ok_or(RejectReason::MissingInput)
         })
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let input_value = Amount::checked_sum(input_values)
+        .ok_or(RejectReason::InputValueOutOfRange)?;
+
+    Ok(input_value
+        .checked_sub(transaction.output_value()?)
+        .ok_or(RejectReason::InputsBelowOutputs)?)
+}
+
+// unspent outputs ordered by outpoint, so that the same UTXO
+// set is always encoded the same
+fn canonical_order(
+    utxos: HashMap<OutPoint, UtxoEntry>,
+) -> Vec<(OutPoint, UtxoEntry)> {
+    let mut utxos: Vec<_> = utxos.into_iter().collect();
+    utxos.sort_unstable_by_key(|(outpoint, _)| {
+        (outpoint.txid.as_bytes(), outpoint.index)
+    });
+    utxos
+}
+
+// network, blocks of the active chain, blocks on competing
+// branches ordered by hash, and for a blockchain started
+// from a snapshot, the height of the snapshot and its
+// unspent outputs. Everything else follows from the blocks
+// and is rebuilt when loading, see crate::encoding
+impl Saveable for Blockchain {
+    fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+        let network = Network::decode(&mut reader)?;
+        let blocks: Vec<Block> = Vec::decode(&mut reader)?;
+        let forks: Vec<Block> = Vec::decode(&mut reader)?;
+        let snapshot: Option<(u64, Vec<(OutPoint, UtxoEntry)>)> =
+            Option::decode(&mut reader)?;
+
+        let mut blocks = blocks.into_iter();
+        let mut blockchain = match snapshot {
+            Some((height, utxos)) => {
+                let headers: Vec<_> = blocks
+                    .by_ref()
+                    .take(height as usize + 1)
+                    .collect();
+                if headers.len() as u64 != height + 1 {
+                    return Err(IoError::new(
+                        IoErrorKind::InvalidData,
+                        "blockchain ends before its snapshot",
+                    ));
+                }
+                // the headers have to link up like blocks
+                let linked = headers.windows(2).all(|pair| {
+                    pair[1].header.prev_block_hash
+                        == pair[0].hash()
+                });
+                if !linked
+                    || headers[0].hash()
+                        != network.params().genesis_block.hash()
+                {
+                    return Err(invalid_data(
+                        "snapshot headers do not extend the chain",
+                    ));
+                }
+                Self::start_snapshot(
+                    network,
+                    headers,
+                    utxos.into_iter().collect(),
+                )
+            }
+            None => Self::empty(network),
+        };
+        blockchain.replay_blocks(blocks)?;
+        blockchain.forks = forks
+            .into_iter()
+            .map(|block| (block.hash(), block))
+            .collect();
+        Ok(blockchain)
     }
 
-    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
-        ciborium::ser::into_writer(self, writer).map_err(|_| {
-            IoError::new(
-                IoErrorKind::InvalidData,
-                "Failed to serialize Blockchain",
-            )
-        })
+    fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
+        let mut forks: Vec<_> = self.forks.iter().collect();
+        forks.sort_unstable_by_key(|(hash, _)| hash.as_bytes());
+        let snapshot = self.snapshot.map(|snapshot| {
+            (snapshot.height, self.snapshot_utxos.as_ref())
+        });
+
+        self.network.encode(&mut writer)?;
+        self.blocks.encode(&mut writer)?;
+        forks
+            .into_iter()
+            .map(|(_, block)| block)
+            .collect::<Vec<_>>()
+            .encode(&mut writer)?;
+        snapshot.encode(&mut writer)
     }
 }
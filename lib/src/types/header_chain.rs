@@ -0,0 +1,371 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use std::collections::HashMap;
+
+use super::BlockHeader;
+use crate::error::{RejectReason, Result};
+use crate::params::{ChainParams, Network};
+use crate::sha256::Hash;
+use crate::U256;
+
+// a known header with its position in the tree of headers
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct HeaderEntry {
+    header: BlockHeader,
+    height: u64,
+    // cumulative work of the chain ending in this header
+    chainwork: U256,
+}
+
+/// A chain of block headers, validated without the blocks
+/// themselves: linkage, proof of work, targets and
+/// timestamps. Competing branches are kept, the chain with
+/// the most work is the best chain. Used to sync headers
+/// before blocks, and by light clients to follow the chain
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HeaderChain {
+    network: Network,
+    // every valid header, on the best chain or not
+    entries: HashMap<Hash, HeaderEntry>,
+    // hashes of the best chain, genesis first
+    best_chain: Vec<Hash>,
+}
+
+impl HeaderChain {
+    // create a header chain of the given network, containing
+    // only its genesis header
+    pub fn new(network: Network) -> Self {
+        let genesis =
+            network.params().genesis_block.header.clone();
+        let hash = genesis.hash();
+        let entry = HeaderEntry {
+            chainwork: genesis.work(),
+            header: genesis,
+            height: 0,
+        };
+
+        HeaderChain {
+            network,
+            entries: HashMap::from([(hash, entry)]),
+            best_chain: vec![hash],
+        }
+    }
+
+    // network the header chain belongs to
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    // validate a header and add it to the tree of headers,
+    // switching the best chain to it if it has the most work
+    pub fn add_header(
+        &mut self,
+        header: BlockHeader,
+    ) -> Result<()> {
+        let hash = header.hash();
+        if self.entries.contains_key(&hash) {
+            return Err(RejectReason::DuplicateBlock.into());
+        }
+
+        let Some(parent) =
+            self.entries.get(&header.prev_block_hash)
+        else {
+            return Err(RejectReason::PrevBlockNotFound.into());
+        };
+
+        let params = self.network.params();
+        let height = parent.height + 1;
+        let parent_chainwork = parent.chainwork;
+        let ancestors = self.ancestors(
+            &header.prev_block_hash,
+            context_len(params),
+        );
+        check_header(&header, height, &ancestors, params)?;
+
+        // only a header with the required bits has a target
+        // its work can be derived from
+        let chainwork =
+            parent_chainwork.saturating_add(header.work());
+
+        self.entries.insert(
+            hash,
+            HeaderEntry {
+                header,
+                height,
+                chainwork,
+            },
+        );
+
+        // on a tie, stay on the chain we have seen first
+        if chainwork > self.chainwork() {
+            self.set_best_tip(hash);
+        }
+
+        Ok(())
+    }
+
+    // add headers in order, stopping at the first invalid one
+    pub fn add_headers(
+        &mut self,
+        headers: impl IntoIterator<Item = BlockHeader>,
+    ) -> Result<()> {
+        for header in headers {
+            self.add_header(header)?;
+        }
+
+        Ok(())
+    }
+
+    // make the chain ending in the given header the best
+    // chain, replacing the best chain from where they fork
+    fn set_best_tip(&mut self, tip: Hash) {
+        let mut branch = vec![];
+        let mut cursor = tip;
+        loop {
+            let entry = &self.entries[&cursor];
+            if self.best_chain.get(entry.height as usize)
+                == Some(&cursor)
+            {
+                break;
+            }
+            branch.push(cursor);
+            cursor = entry.header.prev_block_hash;
+        }
+
+        let fork_point =
+            self.entries[&cursor].height as usize + 1;
+        if fork_point < self.best_chain.len() {
+            debug!(
+                "header chain reorganizing: {} headers replaced",
+                self.best_chain.len() - fork_point
+            );
+        }
+        self.best_chain.truncate(fork_point);
+        self.best_chain.extend(branch.into_iter().rev());
+    }
+
+    // the given header and its ancestors on whatever branch
+    // it is on, at most count of them, oldest first
+    fn ancestors(
+        &self,
+        hash: &Hash,
+        count: usize,
+    ) -> Vec<BlockHeader> {
+        let mut headers = vec![];
+        let mut cursor = *hash;
+        while headers.len() < count {
+            let Some(entry) = self.entries.get(&cursor) else {
+                break;
+            };
+            headers.push(entry.header.clone());
+            cursor = entry.header.prev_block_hash;
+        }
+
+        headers.reverse();
+        headers
+    }
+
+    // number of headers on the best chain, including the
+    // genesis header
+    pub fn height(&self) -> u64 {
+        self.best_chain.len() as u64
+    }
+
+    // last header of the best chain
+    pub fn tip(&self) -> &BlockHeader {
+        let tip = self
+            .best_chain
+            .last()
+            .expect("BUG: header chain without genesis header");
+        &self.entries[tip].header
+    }
+
+    // cumulative work of the best chain
+    pub fn chainwork(&self) -> U256 {
+        let tip = self
+            .best_chain
+            .last()
+            .expect("BUG: header chain without genesis header");
+        self.entries[tip].chainwork
+    }
+
+    // header of the best chain at the given height
+    pub fn header_at(
+        &self,
+        height: u64,
+    ) -> Option<&BlockHeader> {
+        let hash = self.best_chain.get(height as usize)?;
+        Some(&self.entries[hash].header)
+    }
+
+    // hash of the best chain's header at the given height
+    pub fn hash_at(&self, height: u64) -> Option<Hash> {
+        self.best_chain.get(height as usize).copied()
+    }
+
+    // height of a header on the best chain
+    pub fn height_of(&self, hash: &Hash) -> Option<u64> {
+        let entry = self.entries.get(hash)?;
+        (self.best_chain.get(entry.height as usize)
+            == Some(hash))
+        .then_some(entry.height)
+    }
+
+    // the target the next header on the best chain has to
+    // have
+    pub fn target(&self) -> U256 {
+        let params = self.network.params();
+        let tip = self.tip().hash();
+        let window = params.difficulty_algorithm.window(params);
+        required_target(
+            self.height(),
+            &self.ancestors(&tip, window),
+            params,
+        )
+    }
+
+    // hashes of the best chain to send in GetHeaders, from
+    // the tip back to genesis: the last ten, then
+    // exponentially further apart
+    pub fn locator(&self) -> Vec<Hash> {
+        locator(&self.best_chain)
+    }
+
+    // headers of the best chain following the first locator
+    // hash that is on it, at most max of them
+    pub fn headers_after(
+        &self,
+        locator: &[Hash],
+        max: usize,
+    ) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.height_of(hash))
+            .map_or(0, |height| height as usize + 1);
+
+        self.best_chain
+            .iter()
+            .skip(start)
+            .take(max)
+            .map(|hash| self.entries[hash].header.clone())
+            .collect()
+    }
+}
+
+// block locator of a chain given by its hashes, genesis
+// first
+pub(crate) fn locator(chain: &[Hash]) -> Vec<Hash> {
+    let mut locator = vec![];
+    let mut height = chain.len() as i64 - 1;
+    let mut step = 1;
+    while height > 0 {
+        locator.push(chain[height as usize]);
+        if locator.len() >= 10 {
+            step *= 2;
+        }
+        height -= step;
+    }
+
+    // always end with the genesis block, which every chain
+    // of the network shares
+    if let Some(genesis) = chain.first() {
+        locator.push(*genesis);
+    }
+    locator
+}
+
+// number of ancestors check_header looks at
+pub(crate) fn context_len(params: &ChainParams) -> usize {
+    params
+        .difficulty_algorithm
+        .window(params)
+        .max(crate::MEDIAN_TIME_SPAN)
+}
+
+// median timestamp of the last crate::MEDIAN_TIME_SPAN
+// headers
+pub(crate) fn median_time_past(
+    headers: &[BlockHeader],
+) -> DateTime<Utc> {
+    let mut timestamps: Vec<_> = headers
+        .iter()
+        .rev()
+        .take(crate::MEDIAN_TIME_SPAN)
+        .map(|header| header.timestamp)
+        .collect();
+    timestamps.sort();
+    timestamps[timestamps.len() / 2]
+}
+
+// target the header at the given height has to have, derived
+// from the headers before it. Headers carry the target in
+// compact encoding, so it is rounded to what they can express
+pub(crate) fn required_target(
+    height: u64,
+    headers: &[BlockHeader],
+    params: &ChainParams,
+) -> U256 {
+    let window = params.difficulty_algorithm.window(params);
+    let headers =
+        &headers[headers.len().saturating_sub(window)..];
+    let target = params
+        .difficulty_algorithm
+        .next_target(height, headers, params)
+        .min(params.min_target);
+    U256::from_compact(target.to_compact())
+        .expect("BUG: compact target out of range")
+}
+
+// check everything about a header that follows from its
+// ancestors: proof of work, target and timestamp. The
+// ancestors are the last context_len headers before it,
+// oldest first, or all of them early in the chain
+pub(crate) fn check_header(
+    header: &BlockHeader,
+    height: u64,
+    ancestors: &[BlockHeader],
+    params: &ChainParams,
+) -> Result<()> {
+    // the header has to declare the target its ancestors
+    // require, not one that is easier to meet
+    let required_target =
+        required_target(height, ancestors, params);
+    if header.bits != required_target.to_compact() {
+        return Err(RejectReason::BadDifficultyBits.into());
+    }
+
+    // check if the header's hash is less than the target
+    if !header.hash().matches_target(header.target()) {
+        return Err(RejectReason::HighHash.into());
+    }
+
+    // check if the timestamp is after the median timestamp
+    // of the last blocks, a single block's timestamp can be
+    // off in either direction
+    if header.timestamp <= median_time_past(ancestors) {
+        return Err(RejectReason::TimeTooOld.into());
+    }
+
+    // and that it is not too far ahead of our own clock
+    let max_future_time = Utc::now()
+        + chrono::Duration::seconds(
+            params.max_future_block_time as i64,
+        );
+    if header.timestamp > max_future_time {
+        return Err(RejectReason::TimeTooNew.into());
+    }
+
+    let window = params.difficulty_algorithm.window(params);
+    if !params.difficulty_algorithm.check_timestamp(
+        height,
+        &ancestors[ancestors.len().saturating_sub(window)..],
+        header.timestamp,
+        params,
+    ) {
+        return Err(RejectReason::TimeWarp.into());
+    }
+
+    Ok(())
+}
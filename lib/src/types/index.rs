@@ -0,0 +1,247 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::io::{Read, Result as IoResult, Write};
+
+use super::{Block, OutPoint};
+use crate::amount::Amount;
+use crate::crypto::PublicKey;
+use crate::encoding::{Decodable, Encodable};
+use crate::script::Script;
+use crate::sha256::Hash;
+
+/// Where a transaction of the active chain is
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq,
+)]
+pub struct TxLocation {
+    /// Height of the block containing the transaction
+    pub height: u64,
+    /// Position of the transaction in the block, the coinbase
+    /// is at 0
+    pub position: u32,
+}
+
+// height, position
+impl Encodable for TxLocation {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.height.encode(writer)?;
+        self.position.encode(writer)
+    }
+}
+
+impl Decodable for TxLocation {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(TxLocation {
+            height: u64::decode(reader)?,
+            position: u32::decode(reader)?,
+        })
+    }
+}
+
+/// An input of the active chain spending an indexed output
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq,
+)]
+pub struct SpendInfo {
+    /// Id of the spending transaction
+    pub txid: Hash,
+    /// Position of the input in the spending transaction
+    pub input: u32,
+    /// Height of the block containing the spending transaction
+    pub height: u64,
+}
+
+// txid, input, height
+impl Encodable for SpendInfo {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.txid.encode(writer)?;
+        self.input.encode(writer)?;
+        self.height.encode(writer)
+    }
+}
+
+impl Decodable for SpendInfo {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(SpendInfo {
+            txid: Hash::decode(reader)?,
+            input: u32::decode(reader)?,
+            height: u64::decode(reader)?,
+        })
+    }
+}
+
+/// An output of the active chain paying to a key, and the
+/// input spending it if it has been spent
+#[derive(
+    Serialize, Deserialize, Clone, Debug, PartialEq, Eq,
+)]
+pub struct HistoryEntry {
+    pub outpoint: OutPoint,
+    pub value: Amount,
+    /// Height of the block creating the output
+    pub height: u64,
+    pub spent_by: Option<SpendInfo>,
+}
+
+// outpoint, value, height, spent by
+impl Encodable for HistoryEntry {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.outpoint.encode(writer)?;
+        self.value.encode(writer)?;
+        self.height.encode(writer)?;
+        self.spent_by.encode(writer)
+    }
+}
+
+impl Decodable for HistoryEntry {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(HistoryEntry {
+            outpoint: OutPoint::decode(reader)?,
+            value: Amount::decode(reader)?,
+            height: u64::decode(reader)?,
+            spent_by: Option::decode(reader)?,
+        })
+    }
+}
+
+// txid -> location of every transaction of the active chain
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TxIndex {
+    locations: HashMap<Hash, TxLocation>,
+}
+
+impl TxIndex {
+    pub(crate) fn get(&self, txid: &Hash) -> Option<TxLocation> {
+        self.locations.get(txid).copied()
+    }
+
+    pub(crate) fn connect(
+        &mut self,
+        block: &Block,
+        height: u64,
+    ) {
+        for (position, transaction) in
+            block.transactions.iter().enumerate()
+        {
+            self.locations.insert(
+                transaction.hash(),
+                TxLocation {
+                    height,
+                    position: position as u32,
+                },
+            );
+        }
+    }
+
+    pub(crate) fn disconnect(&mut self, block: &Block) {
+        for transaction in &block.transactions {
+            self.locations.remove(&transaction.hash());
+        }
+    }
+}
+
+// locking script hash -> every output of the active chain
+// paying to the script, in chain order, with its spend
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AddressIndex {
+    outputs: HashMap<Hash, Vec<OutPoint>>,
+    entries: HashMap<OutPoint, HistoryEntry>,
+}
+
+impl AddressIndex {
+    // outputs paying to the key in P2PK or P2PKH scripts,
+    // oldest first
+    pub(crate) fn history(
+        &self,
+        public_key: &PublicKey,
+    ) -> Vec<HistoryEntry> {
+        let mut history: Vec<HistoryEntry> = [
+            Script::p2pk(public_key),
+            Script::p2pkh(public_key),
+        ]
+        .iter()
+        .filter_map(|script| {
+            self.outputs.get(&Hash::hash(script))
+        })
+        .flatten()
+        .map(|outpoint| self.entries[outpoint].clone())
+        .collect();
+
+        history.sort_by_key(|entry| entry.height);
+        history
+    }
+
+    pub(crate) fn connect(
+        &mut self,
+        block: &Block,
+        height: u64,
+    ) {
+        for transaction in &block.transactions {
+            let txid = transaction.hash();
+            for (input_index, input) in
+                transaction.inputs.iter().enumerate()
+            {
+                if let Some(entry) =
+                    self.entries.get_mut(&input.prev_output)
+                {
+                    entry.spent_by = Some(SpendInfo {
+                        txid,
+                        input: input_index as u32,
+                        height,
+                    });
+                }
+            }
+
+            for (index, output) in
+                transaction.outputs.iter().enumerate()
+            {
+                let outpoint = OutPoint::new(txid, index as u32);
+                self.outputs
+                    .entry(Hash::hash(&output.locking_script))
+                    .or_default()
+                    .push(outpoint);
+                self.entries.insert(
+                    outpoint,
+                    HistoryEntry {
+                        outpoint,
+                        value: output.value,
+                        height,
+                        spent_by: None,
+                    },
+                );
+            }
+        }
+    }
+
+    // undo connect, going through the block backwards
+    pub(crate) fn disconnect(&mut self, block: &Block) {
+        for transaction in block.transactions.iter().rev() {
+            let txid = transaction.hash();
+            for (index, output) in
+                transaction.outputs.iter().enumerate().rev()
+            {
+                let script_hash =
+                    Hash::hash(&output.locking_script);
+                if let Some(outpoints) =
+                    self.outputs.get_mut(&script_hash)
+                {
+                    outpoints.pop();
+                    if outpoints.is_empty() {
+                        self.outputs.remove(&script_hash);
+                    }
+                }
+                self.entries
+                    .remove(&OutPoint::new(txid, index as u32));
+            }
+
+            for input in &transaction.inputs {
+                if let Some(entry) =
+                    self.entries.get_mut(&input.prev_output)
+                {
+                    entry.spent_by = None;
+                }
+            }
+        }
+    }
+}
@@ -1,19 +1,35 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 
 use crate::util::Saveable;
-use std::io::{
-    Error as IoError, ErrorKind as IoErrorKind, Read,
-    Result as IoResult, Write,
-};
+use std::io::{Read, Result as IoResult, Write};
 
-use crate::crypto::{PublicKey, Signature};
+use super::UtxoEntry;
+use crate::amount::Amount;
+use crate::encoding::{Decodable, Encodable};
+use crate::error::{BtcError, RejectReason, Result};
+use crate::script::Script;
 use crate::sha256::Hash;
+use crate::{
+    LOCKTIME_THRESHOLD, SEQUENCE_LOCKTIME_DISABLE_FLAG,
+    SEQUENCE_LOCKTIME_GRANULARITY, SEQUENCE_LOCKTIME_MASK,
+    SEQUENCE_LOCKTIME_TYPE_FLAG,
+};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction {
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
+    /// Height of the block a coinbase transaction is mined in,
+    /// so that coinbase transaction ids are unique. Always zero
+    /// for other transactions
+    #[serde(default)]
+    pub coinbase_height: u64,
+    /// Block height (or unix timestamp, for values of at
+    /// least LOCKTIME_THRESHOLD) before which the transaction
+    /// cannot be included in a block. Zero for no lock
+    #[serde(default)]
+    pub lock_time: u64,
 }
 
 impl Transaction {
@@ -22,52 +38,349 @@ impl Transaction {
         outputs: Vec<TransactionOutput>,
     ) -> Self {
         Transaction {
-            inputs: inputs,
-            outputs: outputs,
+            inputs,
+            outputs,
+            coinbase_height: 0,
+            lock_time: 0,
+        }
+    }
+
+    // create a coinbase transaction for the block at the
+    // given height
+    pub fn coinbase(
+        height: u64,
+        outputs: Vec<TransactionOutput>,
+    ) -> Self {
+        Transaction {
+            inputs: vec![],
+            outputs,
+            coinbase_height: height,
+            lock_time: 0,
         }
     }
 
     pub fn hash(&self) -> Hash {
         Hash::hash(self)
     }
-}
 
-// save and load expecting CBOR from ciborium as format
-impl Saveable for Transaction {
-    fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader).map_err(|_| {
-            IoError::new(
-                IoErrorKind::InvalidData,
-                "Failed to deserialize Transaction",
-            )
-        })
+    // size of the serialized transaction in bytes
+    pub fn serialized_size(&self) -> usize {
+        self.encoded_size()
+    }
+
+    // total value of the outputs, which has to be within the
+    // money supply like every single output
+    pub fn output_value(&self) -> Result<Amount> {
+        Amount::checked_sum(
+            self.outputs.iter().map(|output| output.value),
+        )
+        .ok_or(RejectReason::OutputValueOutOfRange.into())
     }
 
-    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
-        ciborium::ser::into_writer(self, writer).map_err(|_| {
-            IoError::new(
-                IoErrorKind::InvalidData,
-                "Failed to serialize Transaction",
+    // check if the transaction's lock time allows it to be
+    // included in a block with the given height and time
+    pub fn is_final(
+        &self,
+        height: u64,
+        time: DateTime<Utc>,
+    ) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+
+        if self.lock_time < LOCKTIME_THRESHOLD {
+            self.lock_time < height
+        } else {
+            // lock times beyond any timestamp are never final
+            i64::try_from(self.lock_time).is_ok_and(
+                |lock_time| lock_time < time.timestamp(),
             )
+        }
+    }
+
+    // hash signed by the input with the given index. The
+    // unlocking scripts are not part of it, so inputs can be
+    // signed once the rest of the transaction is assembled
+    pub fn sighash(
+        &self,
+        input_index: usize,
+        sighash_type: SigHashType,
+    ) -> Result<Hash> {
+        let input = self
+            .inputs
+            .get(input_index)
+            .ok_or(BtcError::InvalidTransactionInput)?;
+
+        let inputs = if sighash_type.anyone_can_pay() {
+            vec![]
+        } else {
+            self.inputs
+                .iter()
+                .map(|input| (input.prev_output, input.sequence))
+                .collect()
+        };
+
+        let outputs = if sighash_type.single() {
+            // there has to be an output to commit to
+            let output = self
+                .outputs
+                .get(input_index)
+                .ok_or(BtcError::InvalidTransactionInput)?;
+            std::slice::from_ref(output)
+        } else {
+            &self.outputs
+        };
+
+        Ok(Hash::hash(&SigHashPreimage {
+            sighash_type,
+            spent_output: &input.prev_output,
+            sequence: input.sequence,
+            inputs,
+            outputs,
+            lock_time: self.lock_time,
+        }))
+    }
+}
+
+// data committed to by an input's signature
+struct SigHashPreimage<'a> {
+    sighash_type: SigHashType,
+    spent_output: &'a OutPoint,
+    sequence: u32,
+    inputs: Vec<(OutPoint, u32)>,
+    outputs: &'a [TransactionOutput],
+    lock_time: u64,
+}
+
+// the fields in order, with the sighash type as its byte
+impl Encodable for SigHashPreimage<'_> {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.sighash_type.to_byte().encode(writer)?;
+        self.spent_output.encode(writer)?;
+        self.sequence.encode(writer)?;
+        self.inputs.encode(writer)?;
+        self.outputs.encode(writer)?;
+        self.lock_time.encode(writer)
+    }
+}
+
+/// Selects which parts of the spending transaction an
+/// input's signature commits to
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+)]
+pub enum SigHashType {
+    /// All inputs and all outputs
+    #[default]
+    All,
+    /// All inputs and the output with the same index as
+    /// the signed input
+    Single,
+    /// Only the signed input and all outputs, so anyone
+    /// can add more inputs
+    AllAnyoneCanPay,
+    /// Only the signed input and the output with the same
+    /// index as the signed input
+    SingleAnyoneCanPay,
+}
+
+impl SigHashType {
+    pub fn anyone_can_pay(&self) -> bool {
+        matches!(
+            self,
+            SigHashType::AllAnyoneCanPay
+                | SigHashType::SingleAnyoneCanPay
+        )
+    }
+
+    pub fn single(&self) -> bool {
+        matches!(
+            self,
+            SigHashType::Single
+                | SigHashType::SingleAnyoneCanPay
+        )
+    }
+
+    // encoding appended to signatures in scripts
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            SigHashType::All => 0x01,
+            SigHashType::Single => 0x03,
+            SigHashType::AllAnyoneCanPay => 0x81,
+            SigHashType::SingleAnyoneCanPay => 0x83,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x01 => Some(SigHashType::All),
+            0x03 => Some(SigHashType::Single),
+            0x81 => Some(SigHashType::AllAnyoneCanPay),
+            0x83 => Some(SigHashType::SingleAnyoneCanPay),
+            _ => None,
+        }
+    }
+}
+
+// inputs, outputs, coinbase height, lock time
+impl Encodable for Transaction {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.inputs.encode(writer)?;
+        self.outputs.encode(writer)?;
+        self.coinbase_height.encode(writer)?;
+        self.lock_time.encode(writer)
+    }
+}
+
+impl Decodable for Transaction {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(Transaction {
+            inputs: Vec::decode(reader)?,
+            outputs: Vec::decode(reader)?,
+            coinbase_height: u64::decode(reader)?,
+            lock_time: u64::decode(reader)?,
         })
     }
 }
 
+// save and load in the canonical encoding
+impl Saveable for Transaction {
+    fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+        Transaction::decode(&mut reader)
+    }
+
+    fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
+        self.encode(&mut writer)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionInput {
-    pub prev_transaction_output_hash: Hash,
-    pub signature: Signature,
+    /// The output being spent
+    pub prev_output: OutPoint,
+    /// Script satisfying the locking script of the
+    /// output being spent
+    pub unlocking_script: Script,
+    /// Relative lock on the input: the number of blocks (or
+    /// of 512 second intervals, if SEQUENCE_LOCKTIME_TYPE_FLAG
+    /// is set) the spent output has to be buried under. No
+    /// lock if SEQUENCE_LOCKTIME_DISABLE_FLAG is set
+    #[serde(default)]
+    pub sequence: u32,
+}
+
+// prev output, unlocking script, sequence
+impl Encodable for TransactionInput {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.prev_output.encode(writer)?;
+        self.unlocking_script.encode(writer)?;
+        self.sequence.encode(writer)
+    }
+}
+
+impl Decodable for TransactionInput {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(TransactionInput {
+            prev_output: OutPoint::decode(reader)?,
+            unlocking_script: Script::decode(reader)?,
+            sequence: u32::decode(reader)?,
+        })
+    }
+}
+
+impl TransactionInput {
+    // check if the input's relative lock allows spending the
+    // given output in a block with the given height and time
+    pub fn is_final(
+        &self,
+        spent_output: &UtxoEntry,
+        height: u64,
+        time: DateTime<Utc>,
+    ) -> bool {
+        if self.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return true;
+        }
+
+        let lock = self.sequence & SEQUENCE_LOCKTIME_MASK;
+        if self.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            let seconds =
+                (lock as i64) << SEQUENCE_LOCKTIME_GRANULARITY;
+            spent_output.timestamp.timestamp() + seconds
+                <= time.timestamp()
+        } else {
+            spent_output.height + lock as u64 <= height
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionOutput {
-    pub value: u64,
-    pub unique_id: Uuid,
-    pub pubkey: PublicKey,
+    pub value: Amount,
+    /// Conditions under which the output can be spent
+    pub locking_script: Script,
 }
 
-impl TransactionOutput {
-    pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+// value, locking script
+impl Encodable for TransactionOutput {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.value.encode(writer)?;
+        self.locking_script.encode(writer)
+    }
+}
+
+impl Decodable for TransactionOutput {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(TransactionOutput {
+            value: Amount::decode(reader)?,
+            locking_script: Script::decode(reader)?,
+        })
+    }
+}
+
+/// Reference to a transaction output by the id of the
+/// transaction that created it and its position in the
+/// transaction's outputs
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+)]
+pub struct OutPoint {
+    pub txid: Hash,
+    pub index: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: Hash, index: u32) -> Self {
+        OutPoint { txid, index }
+    }
+}
+
+// txid, index
+impl Encodable for OutPoint {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.txid.encode(writer)?;
+        self.index.encode(writer)
+    }
+}
+
+impl Decodable for OutPoint {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(OutPoint {
+            txid: Hash::decode(reader)?,
+            index: u32::decode(reader)?,
+        })
     }
 }
@@ -0,0 +1,248 @@
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::ProjectivePoint;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Result as IoResult, Write};
+
+use super::{BlockHeader, OutPoint, UtxoEntry};
+use crate::amount::Amount;
+use crate::encoding::{Decodable, Encodable};
+use crate::sha256::{Hash, Hasher};
+use crate::util::Saveable;
+
+/// What a node's UTXO set looks like at the tip of its active
+/// chain. Two nodes at the same tip agree on the UTXO set if
+/// and only if they agree on the commitment
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq,
+)]
+pub struct UtxoSetSummary {
+    /// Height of the tip of the active chain
+    pub height: u64,
+    /// Hash of the tip of the active chain
+    pub tip: Hash,
+    /// Number of unspent outputs
+    pub count: u64,
+    /// Sum of the values of the unspent outputs
+    pub total_value: Amount,
+    /// Hash of the whole UTXO set, see UtxoCommitment
+    pub commitment: Hash,
+}
+
+// height, tip, count, total value, commitment
+impl Encodable for UtxoSetSummary {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.height.encode(writer)?;
+        self.tip.encode(writer)?;
+        self.count.encode(writer)?;
+        self.total_value.encode(writer)?;
+        self.commitment.encode(writer)
+    }
+}
+
+impl Decodable for UtxoSetSummary {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(UtxoSetSummary {
+            height: u64::decode(reader)?,
+            tip: Hash::decode(reader)?,
+            count: u64::decode(reader)?,
+            total_value: Amount::decode(reader)?,
+            commitment: Hash::decode(reader)?,
+        })
+    }
+}
+
+/// The UTXO set after a block of the active chain, with the
+/// headers of the chain up to it, so that a node can start
+/// from the block instead of replaying the chain before it.
+/// See Blockchain::export_snapshot and
+/// Blockchain::from_snapshot
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UtxoSnapshot {
+    /// Headers from the genesis block up to and including
+    /// the last block of the snapshot
+    pub headers: Vec<BlockHeader>,
+    /// The unspent outputs after the last block
+    pub utxos: Vec<(OutPoint, UtxoEntry)>,
+}
+
+impl UtxoSnapshot {
+    // the summary of the UTXO set, to compare with the known
+    // snapshots of the network
+    pub fn summary(&self) -> Option<UtxoSetSummary> {
+        let tip = self.headers.last()?;
+        let mut commitment = UtxoCommitment::new();
+        for (outpoint, entry) in &self.utxos {
+            commitment.add(outpoint, entry);
+        }
+
+        Some(UtxoSetSummary {
+            height: self.headers.len() as u64 - 1,
+            tip: tip.hash(),
+            count: commitment.count(),
+            total_value: commitment.total_value(),
+            commitment: commitment.hash(),
+        })
+    }
+}
+
+// headers, unspent outputs
+impl Encodable for UtxoSnapshot {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.headers.encode(writer)?;
+        self.utxos.encode(writer)
+    }
+}
+
+impl Decodable for UtxoSnapshot {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(UtxoSnapshot {
+            headers: Vec::decode(reader)?,
+            utxos: Vec::decode(reader)?,
+        })
+    }
+}
+
+// save and load in the canonical encoding, see
+// crate::encoding
+impl Saveable for UtxoSnapshot {
+    fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+        UtxoSnapshot::decode(&mut reader)
+    }
+
+    fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
+        self.encode(&mut writer)
+    }
+}
+
+/// Elliptic curve multiset hash of a UTXO set: every unspent
+/// output is hashed to a point on secp256k1, and the set is
+/// represented by the sum of its points. Adding and spending
+/// an output is a single point addition or subtraction, and
+/// the result does not depend on the order of the updates.
+/// Unlike a plain sum of hashes, finding a different set with
+/// the same sum is as hard as the discrete logarithm problem
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct UtxoCommitment {
+    point: ProjectivePoint,
+    count: u64,
+    // in satoshis, wrapping around so that an invalid set
+    // cannot overflow it, a valid one never exceeds the money
+    // supply
+    total_value: u64,
+}
+
+impl UtxoCommitment {
+    // commitment of the empty set
+    pub fn new() -> Self {
+        UtxoCommitment {
+            point: ProjectivePoint::IDENTITY,
+            count: 0,
+            total_value: 0,
+        }
+    }
+
+    // commitment of a whole UTXO set
+    pub fn from_utxos(
+        utxos: &HashMap<OutPoint, UtxoEntry>,
+    ) -> Self {
+        let mut commitment = Self::new();
+        for (outpoint, entry) in utxos {
+            commitment.add(outpoint, entry);
+        }
+        commitment
+    }
+
+    pub fn add(
+        &mut self,
+        outpoint: &OutPoint,
+        entry: &UtxoEntry,
+    ) {
+        self.point += element_point(outpoint, entry);
+        self.count += 1;
+        self.total_value = self
+            .total_value
+            .wrapping_add(entry.output.value.to_sat());
+    }
+
+    // remove an output that has been added before
+    pub fn remove(
+        &mut self,
+        outpoint: &OutPoint,
+        entry: &UtxoEntry,
+    ) {
+        self.point -= element_point(outpoint, entry);
+        self.count -= 1;
+        self.total_value = self
+            .total_value
+            .wrapping_sub(entry.output.value.to_sat());
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn total_value(&self) -> Amount {
+        Amount::from_sat(self.total_value)
+    }
+
+    // hash of the compressed encoding of the sum, a single
+    // zero byte for the empty set
+    pub fn hash(&self) -> Hash {
+        let encoded =
+            self.point.to_affine().to_encoded_point(true);
+        let mut hasher = Hasher::new();
+        hasher.update(encoded.as_bytes());
+        hasher.finalize()
+    }
+}
+
+impl Default for UtxoCommitment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for UtxoCommitment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UtxoCommitment")
+            .field("count", &self.count)
+            .field("total_value", &self.total_value)
+            .field("hash", &self.hash())
+            .finish()
+    }
+}
+
+// map an unspent output to a curve point by try-and-increment:
+// hash its encoding together with a counter until the hash is
+// the x coordinate of a point, and take the point with even y
+fn element_point(
+    outpoint: &OutPoint,
+    entry: &UtxoEntry,
+) -> ProjectivePoint {
+    let mut hasher = Hasher::new();
+    outpoint
+        .encode(&mut hasher)
+        .and_then(|_| entry.encode(&mut hasher))
+        .expect("BUG: writing to a hasher cannot fail");
+    let element = hasher.finalize();
+
+    let mut counter = 0u32;
+    loop {
+        let mut hasher = Hasher::new();
+        hasher.update(&element.as_bytes());
+        hasher.update(&counter.to_le_bytes());
+        let x = hasher.finalize();
+
+        // about half of all x coordinates are on the curve
+        let mut sec1 = [0x02; 33];
+        sec1[1..].copy_from_slice(&x.as_bytes());
+        if let Ok(key) = k256::PublicKey::from_sec1_bytes(&sec1)
+        {
+            return key.to_projective();
+        }
+        counter += 1;
+    }
+}
@@ -4,8 +4,9 @@ use std::fs::File;
 use std::io::{Read, Result as IoResult, Write};
 use std::path::Path;
 
+use crate::encoding::{Decodable, Encodable};
 use crate::sha256::Hash;
-use crate::types::Transaction;
+use crate::types::{BlockHeader, Transaction};
 
 #[derive(
     Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq,
@@ -18,26 +19,193 @@ impl MerkleRoot {
     pub fn calculate(
         transactions: &[Transaction],
     ) -> MerkleRoot {
-        let mut layer: Vec<Hash> = vec![];
+        let layers = merkle_layers(transactions);
+        MerkleRoot(merkle_top(&layers))
+    }
+
+    // calculate the merkle root, unless the tree is mutated:
+    // pairing the last hash of a layer with itself means that
+    // repeating the transactions it covers gives the same
+    // root, so two identical hashes paired with each other
+    // are only valid as that padding
+    pub fn calculate_checked(
+        transactions: &[Transaction],
+    ) -> Option<MerkleRoot> {
+        let layers = merkle_layers(transactions);
+        let mutated = layers.iter().any(|layer| {
+            layer.chunks_exact(2).any(|pair| pair[0] == pair[1])
+        });
+
+        if mutated {
+            None
+        } else {
+            Some(MerkleRoot(merkle_top(&layers)))
+        }
+    }
+}
+
+// the hashes of the tree are tagged as either a transaction
+// or a pair of hashes, so that one can never be passed off as
+// the other, whatever the size of the transaction
+const LEAF_TAG: u8 = 0;
+const NODE_TAG: u8 = 1;
+
+fn leaf_hash(txid: Hash) -> Hash {
+    Hash::hash(&(LEAF_TAG, txid))
+}
+
+fn node_hash(left: Hash, right: Hash) -> Hash {
+    Hash::hash(&(NODE_TAG, [left, right]))
+}
+
+// number of layers above the transactions in the merkle tree
+// of the given number of transactions
+fn merkle_depth(transaction_count: u32) -> usize {
+    match transaction_count.checked_sub(1) {
+        Some(last) => {
+            (u32::BITS - last.leading_zeros()) as usize
+        }
+        None => 0,
+    }
+}
+
+// hash every layer of the merkle tree of the transactions,
+// from the tagged transaction ids up to the root
+fn merkle_layers(
+    transactions: &[Transaction],
+) -> Vec<Vec<Hash>> {
+    let mut layer: Vec<Hash> = vec![];
+
+    for transaction in transactions {
+        layer.push(leaf_hash(transaction.hash()));
+    }
+
+    let mut layers = vec![];
+    while layer.len() > 1 {
+        let mut new_layer = vec![];
+
+        for pair in layer.chunks(2) {
+            let left = pair[0];
+            // if there is no right, use the left hash again
+            let right = pair.get(1).unwrap_or(&pair[0]);
+            new_layer.push(node_hash(left, *right));
+        }
 
-        for transaction in transactions {
-            layer.push(Hash::hash(transaction));
+        layers.push(layer);
+        layer = new_layer;
+    }
+    layers.push(layer);
+
+    layers
+}
+
+// root of the layers, the zero hash if there are no
+// transactions
+fn merkle_top(layers: &[Vec<Hash>]) -> Hash {
+    layers
+        .last()
+        .and_then(|layer| layer.first())
+        .copied()
+        .unwrap_or(Hash::zero())
+}
+
+/// Proof that a transaction is part of a block, which can be
+/// checked against the block's header alone
+#[derive(
+    Serialize, Deserialize, Clone, Debug, PartialEq, Eq,
+)]
+pub struct MerkleProof {
+    /// Id of the proven transaction
+    pub txid: Hash,
+    /// Position of the transaction in the block
+    pub index: u32,
+    /// Number of transactions in the block
+    pub transaction_count: u32,
+    /// Hashes paired with the transaction's branch on the way
+    /// up to the root, starting at the layer of the
+    /// transactions
+    pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+    // prove that the transaction at the given index is part
+    // of the transactions' merkle tree
+    pub fn generate(
+        transactions: &[Transaction],
+        index: usize,
+    ) -> Option<Self> {
+        if index >= transactions.len() {
+            return None;
         }
 
-        while layer.len() > 1 {
-            let mut new_layer = vec![];
+        let layers = merkle_layers(transactions);
+        let mut siblings = vec![];
+        let mut position = index;
+        for layer in &layers[..layers.len() - 1] {
+            let sibling = layer
+                .get(position ^ 1)
+                .unwrap_or(&layer[position]);
+            siblings.push(*sibling);
+            position /= 2;
+        }
+
+        Some(MerkleProof {
+            txid: transactions[index].hash(),
+            index: index as u32,
+            transaction_count: transactions.len() as u32,
+            siblings,
+        })
+    }
 
-            for pair in layer.chunks(2) {
-                let left = pair[0];
-                // if there is no right, use the left hash again
-                let right = pair.get(1).unwrap_or(&pair[0]);
-                new_layer.push(Hash::hash(&[left, *right]));
+    // check that the proof leads to the header's merkle root.
+    // A hash can only be paired with itself as the padding at
+    // the end of an odd layer, see
+    // MerkleRoot::calculate_checked. The root does not commit
+    // to the transaction count, but as the transactions are
+    // tagged, the path has to go all the way down to them, so
+    // its length has to match the depth of the tree
+    pub fn verify(&self, header: &BlockHeader) -> bool {
+        if self.index >= self.transaction_count
+            || self.siblings.len()
+                != merkle_depth(self.transaction_count)
+        {
+            return false;
+        }
+
+        let mut hash = leaf_hash(self.txid);
+        let mut position = self.index;
+        let mut layer_len = self.transaction_count;
+        for sibling in &self.siblings {
+            let is_left = position & 1 == 0;
+            let is_padding =
+                is_left && position == layer_len - 1;
+            if (*sibling == hash) != is_padding {
+                return false;
             }
 
-            layer = new_layer;
+            hash = if is_left {
+                node_hash(hash, *sibling)
+            } else {
+                node_hash(*sibling, hash)
+            };
+            position /= 2;
+            layer_len = layer_len.div_ceil(2);
         }
 
-        MerkleRoot(layer[0])
+        MerkleRoot(hash) == header.merkle_root
+    }
+}
+
+// the root hash
+impl Encodable for MerkleRoot {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.0.encode(writer)
+    }
+}
+
+impl Decodable for MerkleRoot {
+    fn decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Hash::decode(reader).map(MerkleRoot)
     }
 }
 
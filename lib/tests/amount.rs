@@ -0,0 +1,102 @@
+use btclib::amount::{Amount, SATS_PER_BTC};
+use btclib::error::BtcError;
+
+fn parse(s: &str) -> Option<Amount> {
+    s.parse().ok()
+}
+
+#[test]
+fn parse_btc() {
+    let expected = [
+        ("1", 100_000_000),
+        ("1.5", 150_000_000),
+        ("0.00000001", 1),
+        (".5", 50_000_000),
+        ("2.", 200_000_000),
+        ("21000000", 21_000_000 * SATS_PER_BTC),
+        ("0.001 BTC", 100_000),
+        (" 3BTC ", 300_000_000),
+    ];
+    for (s, sats) in expected {
+        assert_eq!(
+            parse(s),
+            Some(Amount::from_sat(sats)),
+            "{s}"
+        );
+    }
+
+    // at most 8 decimal places
+    assert!(matches!(
+        Amount::from_btc_str("0.000000001"),
+        Err(BtcError::InvalidAmount)
+    ));
+    assert_eq!(parse("0.123456789"), None);
+
+    for s in ["", ".", "-1", "+1", "1.2.3", "1e3", "1,5", "one"]
+    {
+        assert_eq!(parse(s), None, "{s}");
+    }
+
+    // more than fits in a u64
+    assert_eq!(parse("184467440738"), None);
+}
+
+#[test]
+fn parse_sats() {
+    assert_eq!(parse("1 sat"), Some(Amount::ONE_SAT));
+    assert_eq!(parse("1500sats"), Some(Amount::from_sat(1500)));
+    assert_eq!(
+        Amount::from_sat_str("18446744073709551615").unwrap(),
+        Amount::from_sat(u64::MAX)
+    );
+
+    // only whole numbers of satoshis that fit in a u64
+    for s in
+        ["1.5 sats", "sats", "-1 sat", "18446744073709551616"]
+    {
+        assert_eq!(parse(s), None, "{s}");
+    }
+}
+
+#[test]
+fn max_money() {
+    let max = Amount::MAX_MONEY;
+    assert_eq!(max, Amount::from_btc(21_000_000).unwrap());
+    assert!(max.is_valid());
+
+    // an amount can be parsed or computed above the money
+    // supply, but it is not valid
+    let above = parse("21000000.00000001").unwrap();
+    assert_eq!(above, max.checked_add(Amount::ONE_SAT).unwrap());
+    assert!(!above.is_valid());
+
+    // and neither is a sum above it
+    let half = max.checked_div(2).unwrap();
+    assert_eq!(Amount::checked_sum([half, half]), Some(max));
+    assert_eq!(
+        Amount::checked_sum([half, half, Amount::ONE_SAT]),
+        None
+    );
+    assert_eq!(Amount::checked_sum([above]), None);
+    assert_eq!(
+        Amount::checked_sum([Amount::from_sat(u64::MAX), max]),
+        None
+    );
+    assert_eq!(Amount::checked_sum([]), Some(Amount::ZERO));
+}
+
+#[test]
+fn display() {
+    let expected = [
+        (0, "0 BTC"),
+        (1, "0.00000001 BTC"),
+        (100_000, "0.001 BTC"),
+        (150_000_000, "1.5 BTC"),
+        (21_000_000 * SATS_PER_BTC, "21000000 BTC"),
+    ];
+    for (sats, s) in expected {
+        let amount = Amount::from_sat(sats);
+        assert_eq!(amount.to_string(), s);
+        assert_eq!(parse(s), Some(amount));
+    }
+}
@@ -0,0 +1,764 @@
+use btclib::amount::Amount;
+use btclib::crypto::PrivateKey;
+use btclib::error::RejectReason;
+use btclib::params::Network;
+use btclib::script::{
+    Opcode, Script, PARALLEL_VERIFY_THRESHOLD,
+};
+use btclib::types::{
+    Block, Blockchain, OutPoint, SpendInfo, Transaction,
+    TransactionOutput, TxLocation,
+};
+use btclib::util::MerkleRoot;
+use btclib::MAX_BLOCK_SIZE;
+use chrono::{Duration, Utc};
+
+mod common;
+use common::{
+    coinbase, coinbase_output, mine_block, mine_blocks,
+    mine_child, next_block, padding, regtest_target,
+    reject_reason, spend, spend_to,
+};
+
+// a regtest blockchain paying to the given script, long
+// enough for the coinbase of block 1 to be spendable in the
+// next block
+fn mature_chain(payee: &Script) -> Blockchain {
+    let maturity =
+        Network::Regtest.params().coinbase_maturity;
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    mine_blocks(&mut blockchain, maturity + 1, payee);
+    blockchain
+}
+
+// a block on top of the active chain of a regtest
+// blockchain, whose coinbase is padded to make the block
+// exactly the given size
+fn block_of_size(
+    blockchain: &Blockchain,
+    size: usize,
+) -> Block {
+    let tip =
+        blockchain.blocks().last().unwrap().header.clone();
+    let height = blockchain.block_height();
+    let empty = next_block(
+        blockchain,
+        &Script::unspendable(),
+        vec![],
+    );
+
+    // most of it at once, then in small outputs until the
+    // last one can make up the difference
+    let mut coinbase = empty.transactions[0].clone();
+    coinbase.outputs.extend(padding(
+        size - empty.serialized_size() - 30_000,
+    ));
+    let filler = |len| TransactionOutput {
+        value: Amount::ZERO,
+        locking_script: Script::new(vec![
+            Opcode::Return,
+            Opcode::PushBytes(vec![0; len]),
+        ]),
+    };
+    let size_of = |coinbase: &Transaction| {
+        Block::new(
+            empty.header.clone(),
+            vec![coinbase.clone()],
+        )
+        .serialized_size()
+    };
+    while size - size_of(&coinbase) > 534 {
+        coinbase.outputs.push(filler(250));
+    }
+    coinbase.outputs.push(filler(0));
+    // pushes of 253 bytes and more have a length prefix two
+    // bytes longer than an empty one
+    let len = size - size_of(&coinbase) - 2;
+    assert!(len >= 253);
+    *coinbase.outputs.last_mut().unwrap() = filler(len);
+
+    let block = mine_block(
+        &tip,
+        tip.timestamp + Duration::seconds(10),
+        regtest_target(&tip, height),
+        vec![coinbase],
+    );
+    assert_eq!(block.serialized_size(), size);
+    block
+}
+
+#[test]
+fn heavier_branch_reorganizes() {
+    let key = PrivateKey::new_key();
+    let payee = Script::p2pkh(&key.public_key());
+    let mut blockchain = mature_chain(&payee);
+    let fork_point =
+        blockchain.blocks().last().unwrap().clone();
+    let height = blockchain.block_height();
+
+    // the active chain confirms a transaction
+    let spent = coinbase_output(
+        blockchain.blocks().nth(1).unwrap(),
+    );
+    let transaction = spend(
+        &blockchain,
+        spent,
+        &key,
+        Script::unspendable(),
+    );
+    let active = next_block(
+        &blockchain,
+        &payee,
+        vec![transaction.clone()],
+    );
+
+    // a node which has only ever seen the other branch
+    let mut reference = blockchain.clone();
+    blockchain.add_block(active.clone()).unwrap();
+
+    let other = Script::unspendable();
+    let fork1 = mine_child(
+        &fork_point.header,
+        height,
+        &other,
+        vec![],
+    );
+    let fork2 = mine_child(
+        &fork1.header,
+        height + 1,
+        &other,
+        vec![],
+    );
+    reference.add_block(fork1.clone()).unwrap();
+    reference.add_block(fork2.clone()).unwrap();
+
+    blockchain.add_block(fork1.clone()).unwrap();
+    assert_eq!(
+        blockchain.blocks().last().unwrap().hash(),
+        active.hash()
+    );
+    blockchain.add_block(fork2.clone()).unwrap();
+
+    // the branch is the active chain now, with its UTXO set
+    // and work
+    assert_eq!(blockchain.block_height(), height + 2);
+    assert_eq!(
+        blockchain.blocks().last().unwrap().hash(),
+        fork2.hash()
+    );
+    assert_eq!(
+        blockchain.chainwork(),
+        blockchain.chainwork_at(height - 1).unwrap()
+            + fork1.header.work()
+            + fork2.header.work()
+    );
+    assert_eq!(
+        blockchain.chainwork(),
+        reference.chainwork()
+    );
+    assert_eq!(
+        blockchain.utxo_set_summary(),
+        reference.utxo_set_summary()
+    );
+    assert!(blockchain
+        .forks()
+        .any(|block| block.hash() == active.hash()));
+
+    // and the transaction is waiting to be mined again
+    let mempool: Vec<_> = blockchain
+        .mempool()
+        .iter()
+        .map(|(_, tx)| tx.hash())
+        .collect();
+    assert_eq!(mempool, vec![transaction.hash()]);
+    assert!(blockchain.utxos()[&spent].marked);
+}
+
+#[test]
+fn equal_work_keeps_the_first_chain() {
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    mine_blocks(&mut blockchain, 2, &Script::unspendable());
+    let parent =
+        blockchain.blocks().nth(1).unwrap().clone();
+    let tip = blockchain.blocks().last().unwrap().hash();
+    let chainwork = blockchain.chainwork();
+    let summary = blockchain.utxo_set_summary();
+
+    let payee =
+        Script::p2pkh(&PrivateKey::new_key().public_key());
+    let fork =
+        mine_child(&parent.header, 2, &payee, vec![]);
+    blockchain.add_block(fork.clone()).unwrap();
+
+    assert_eq!(
+        blockchain.blocks().last().unwrap().hash(),
+        tip
+    );
+    assert_eq!(blockchain.chainwork(), chainwork);
+    assert_eq!(blockchain.utxo_set_summary(), summary);
+    let forks: Vec<_> = blockchain
+        .forks()
+        .map(|block| block.hash())
+        .collect();
+    assert_eq!(forks, vec![fork.hash()]);
+
+    // the first chain keeps growing as usual
+    mine_blocks(&mut blockchain, 1, &Script::unspendable());
+    assert_eq!(blockchain.block_height(), 4);
+    assert_eq!(blockchain.forks().count(), 1);
+}
+
+#[test]
+fn invalid_branch_restores_the_active_chain() {
+    let params = Network::Regtest.params();
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    mine_blocks(&mut blockchain, 1, &Script::unspendable());
+    let fork_point =
+        blockchain.blocks().last().unwrap().clone();
+    mine_blocks(&mut blockchain, 3, &Script::unspendable());
+    let tip = blockchain.blocks().last().unwrap().hash();
+    let chainwork = blockchain.chainwork();
+    let summary = blockchain.utxo_set_summary();
+
+    // a branch whose second block pays its miner more than
+    // the block reward, which only shows once it is connected
+    let payee =
+        Script::p2pkh(&PrivateKey::new_key().public_key());
+    let fork2 =
+        mine_child(&fork_point.header, 2, &payee, vec![]);
+    let mut greedy = coinbase(3, payee.clone());
+    greedy.outputs[0].value = params
+        .block_reward(3)
+        .checked_add(Amount::from_sat(1))
+        .unwrap();
+    let fork3 = mine_block(
+        &fork2.header,
+        fork2.header.timestamp + Duration::seconds(10),
+        regtest_target(&fork2.header, 3),
+        vec![greedy],
+    );
+    let fork4 =
+        mine_child(&fork3.header, 4, &payee, vec![]);
+    let fork5 =
+        mine_child(&fork4.header, 5, &payee, vec![]);
+
+    for block in [&fork2, &fork3, &fork4] {
+        blockchain.add_block(block.clone()).unwrap();
+    }
+    assert_eq!(
+        reject_reason(blockchain.add_block(fork5)),
+        RejectReason::BadCoinbaseAmount
+    );
+
+    // the active chain is back as it was
+    assert_eq!(
+        blockchain.blocks().last().unwrap().hash(),
+        tip
+    );
+    assert_eq!(blockchain.chainwork(), chainwork);
+    assert_eq!(blockchain.utxo_set_summary(), summary);
+
+    // only the valid block of the branch is kept, the invalid
+    // one and everything built on it is gone
+    let forks: Vec<_> = blockchain
+        .forks()
+        .map(|block| block.hash())
+        .collect();
+    assert_eq!(forks, vec![fork2.hash()]);
+    assert_eq!(
+        reject_reason(blockchain.add_block(fork4)),
+        RejectReason::PrevBlockNotFound
+    );
+}
+
+#[test]
+fn disconnect_tip_restores_the_utxo_set() {
+    // the genesis block stays
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    assert!(blockchain.disconnect_tip().is_none());
+    assert_eq!(blockchain.block_height(), 1);
+
+    let key = PrivateKey::new_key();
+    let payee = Script::p2pkh(&key.public_key());
+    let mut blockchain = mature_chain(&payee);
+    let summary = blockchain.utxo_set_summary();
+
+    // a block with a transaction whose output is spent in
+    // the mempool
+    let spent = coinbase_output(
+        blockchain.blocks().nth(1).unwrap(),
+    );
+    let transaction =
+        spend(&blockchain, spent, &key, payee.clone());
+    let block = next_block(
+        &blockchain,
+        &payee,
+        vec![transaction.clone()],
+    );
+    blockchain.add_block(block.clone()).unwrap();
+    let output = OutPoint::new(transaction.hash(), 0);
+    let child = spend(
+        &blockchain,
+        output,
+        &key,
+        Script::unspendable(),
+    );
+    blockchain.add_to_mempool(child).unwrap();
+    assert_ne!(blockchain.utxo_set_summary(), summary);
+
+    let disconnected = blockchain.disconnect_tip().unwrap();
+    assert_eq!(disconnected.hash(), block.hash());
+    assert_eq!(blockchain.utxo_set_summary(), summary);
+    assert!(!blockchain.utxos().contains_key(&output));
+    assert!(!blockchain.utxos()[&spent].marked);
+
+    // the spend of the removed output is gone with it
+    assert!(blockchain.mempool().is_empty());
+}
+
+#[test]
+fn signatures_only_unlock_their_transaction() {
+    let key = PrivateKey::new_key();
+    let payee = Script::p2pkh(&key.public_key());
+    let blockchain = mature_chain(&payee);
+    let spent = coinbase_output(
+        blockchain.blocks().nth(1).unwrap(),
+    );
+    let transaction =
+        spend(&blockchain, spent, &key, payee.clone());
+
+    // the same input, signature and all, paying someone else
+    let thief =
+        Script::p2pkh(&PrivateKey::new_key().public_key());
+    let mut stolen = transaction.clone();
+    stolen.outputs[0].locking_script = thief.clone();
+
+    let mut mempool = blockchain.clone();
+    assert_eq!(
+        reject_reason(
+            mempool.add_to_mempool(stolen.clone())
+        ),
+        RejectReason::BadSignature
+    );
+    mempool.add_to_mempool(transaction).unwrap();
+
+    let block =
+        next_block(&blockchain, &thief, vec![stolen]);
+    assert_eq!(
+        reject_reason(block.verify_transactions(
+            blockchain.block_height(),
+            blockchain.utxos(),
+            blockchain.params(),
+        )),
+        RejectReason::BadSignature
+    );
+}
+
+#[test]
+fn one_bad_signature_fails_a_large_block() {
+    // enough mature coinbases for the block's scripts to be
+    // verified in parallel
+    let count = PARALLEL_VERIFY_THRESHOLD as u64 + 4;
+    let key = PrivateKey::new_key();
+    let payee = Script::p2pkh(&key.public_key());
+    let mut blockchain = mature_chain(&payee);
+    mine_blocks(&mut blockchain, count - 1, &payee);
+
+    let mut transactions: Vec<_> = (1..=count)
+        .map(|height| {
+            let block = blockchain
+                .blocks()
+                .nth(height as usize)
+                .unwrap();
+            let spent = coinbase_output(block);
+            spend(&blockchain, spent, &key, payee.clone())
+        })
+        .collect();
+    let block = next_block(
+        &blockchain,
+        &payee,
+        transactions.clone(),
+    );
+    block
+        .verify_transactions(
+            blockchain.block_height(),
+            blockchain.utxos(),
+            blockchain.params(),
+        )
+        .unwrap();
+
+    // the last signature no longer matches its transaction
+    transactions.last_mut().unwrap().outputs[0]
+        .locking_script = Script::unspendable();
+    let block =
+        next_block(&blockchain, &payee, transactions);
+    assert_eq!(
+        reject_reason(blockchain.add_block(block)),
+        RejectReason::BadSignature
+    );
+}
+
+#[test]
+fn coinbase_matures_after_coinbase_maturity_blocks() {
+    let maturity =
+        Network::Regtest.params().coinbase_maturity;
+    let key = PrivateKey::new_key();
+    let payee = Script::p2pkh(&key.public_key());
+
+    // the next block is at height coinbase_maturity, one
+    // short of burying the coinbase of block 1 deep enough
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    mine_blocks(&mut blockchain, maturity - 1, &payee);
+    assert_eq!(blockchain.block_height(), maturity);
+    let spent = coinbase_output(
+        blockchain.blocks().nth(1).unwrap(),
+    );
+    let transaction =
+        spend(&blockchain, spent, &key, payee.clone());
+
+    let mut mempool = blockchain.clone();
+    assert_eq!(
+        reject_reason(
+            mempool.add_to_mempool(transaction.clone())
+        ),
+        RejectReason::PrematureCoinbaseSpend
+    );
+    let block = next_block(
+        &blockchain,
+        &payee,
+        vec![transaction.clone()],
+    );
+    assert_eq!(
+        reject_reason(blockchain.add_block(block)),
+        RejectReason::PrematureCoinbaseSpend
+    );
+
+    // one block later it can be spent
+    mine_blocks(&mut blockchain, 1, &payee);
+    assert_eq!(blockchain.block_height(), 1 + maturity);
+    let mut mempool = blockchain.clone();
+    mempool.add_to_mempool(transaction.clone()).unwrap();
+    let block =
+        next_block(&blockchain, &payee, vec![transaction]);
+    blockchain.add_block(block).unwrap();
+    assert!(!blockchain.utxos().contains_key(&spent));
+}
+
+#[test]
+fn timestamps_must_be_within_bounds() {
+    let params = Network::Regtest.params();
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    mine_blocks(&mut blockchain, 5, &Script::unspendable());
+    let tip = blockchain.blocks().last().unwrap().clone();
+    let height = blockchain.block_height();
+    let median_time_past = blockchain.median_time_past();
+    let mine_at = |timestamp| {
+        mine_block(
+            &tip.header,
+            timestamp,
+            regtest_target(&tip.header, height),
+            vec![coinbase(height, Script::unspendable())],
+        )
+    };
+
+    // not after the median time past
+    assert_eq!(
+        reject_reason(
+            blockchain.add_block(mine_at(median_time_past))
+        ),
+        RejectReason::TimeTooOld
+    );
+
+    // too far ahead of our clock
+    let max_future_time = Utc::now()
+        + Duration::seconds(
+            params.max_future_block_time as i64 + 1,
+        );
+    assert_eq!(
+        reject_reason(
+            blockchain.add_block(mine_at(max_future_time))
+        ),
+        RejectReason::TimeTooNew
+    );
+
+    // a second after the median time past, which is what
+    // block templates use when our clock is behind
+    let earliest =
+        mine_at(median_time_past + Duration::seconds(1));
+    blockchain.add_block(earliest.clone()).unwrap();
+    assert_eq!(
+        blockchain.blocks().last().unwrap().hash(),
+        earliest.hash()
+    );
+}
+
+#[test]
+fn indexes_follow_the_active_chain() {
+    let key = PrivateKey::new_key();
+    let payee = Script::p2pkh(&key.public_key());
+    let mut blockchain = mature_chain(&payee);
+    blockchain.enable_tx_index();
+    blockchain.enable_address_index();
+    let fork_point =
+        blockchain.blocks().last().unwrap().clone();
+    let height = blockchain.block_height();
+
+    // a block paying from the key to another one
+    let recipient = PrivateKey::new_key().public_key();
+    let spent = coinbase_output(
+        blockchain.blocks().nth(1).unwrap(),
+    );
+    let transaction = spend(
+        &blockchain,
+        spent,
+        &key,
+        Script::p2pkh(&recipient),
+    );
+    let txid = transaction.hash();
+    let block = next_block(
+        &blockchain,
+        &Script::unspendable(),
+        vec![transaction],
+    );
+    let spent_by = |blockchain: &Blockchain| {
+        blockchain
+            .history(&key.public_key())
+            .unwrap()
+            .into_iter()
+            .find(|entry| entry.outpoint == spent)
+            .unwrap()
+            .spent_by
+    };
+    // nothing of the block is indexed once it is no longer
+    // part of the active chain
+    let assert_forgotten = |blockchain: &Blockchain| {
+        assert!(blockchain
+            .find_transaction(&txid)
+            .is_none());
+        assert!(blockchain
+            .find_transaction(&block.transactions[0].hash())
+            .is_none());
+        assert!(blockchain
+            .history(&recipient)
+            .unwrap()
+            .is_empty());
+        assert_eq!(spent_by(blockchain), None);
+    };
+
+    blockchain.add_block(block.clone()).unwrap();
+    let (location, _) =
+        blockchain.find_transaction(&txid).unwrap();
+    assert_eq!(
+        location,
+        TxLocation {
+            height,
+            position: 1
+        }
+    );
+    let history = blockchain.history(&recipient).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].outpoint, OutPoint::new(txid, 0));
+    assert_eq!(
+        spent_by(&blockchain),
+        Some(SpendInfo {
+            txid,
+            input: 0,
+            height,
+        })
+    );
+
+    blockchain.disconnect_tip().unwrap();
+    assert_forgotten(&blockchain);
+
+    // the same happens when a reorganization disconnects it
+    blockchain.add_block(block.clone()).unwrap();
+    let other =
+        Script::p2pkh(&PrivateKey::new_key().public_key());
+    let fork1 = mine_child(
+        &fork_point.header,
+        height,
+        &other,
+        vec![],
+    );
+    let fork2 = mine_child(
+        &fork1.header,
+        height + 1,
+        &other,
+        vec![],
+    );
+    blockchain.add_block(fork1).unwrap();
+    blockchain.add_block(fork2.clone()).unwrap();
+    assert_eq!(
+        blockchain.blocks().last().unwrap().hash(),
+        fork2.hash()
+    );
+    assert_forgotten(&blockchain);
+    assert_eq!(
+        blockchain
+            .find_transaction(&fork2.transactions[0].hash())
+            .unwrap()
+            .0,
+        TxLocation {
+            height: height + 1,
+            position: 0,
+        }
+    );
+}
+
+#[test]
+fn blocks_are_limited_in_size() {
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    mine_blocks(&mut blockchain, 1, &Script::unspendable());
+    let tip = blockchain.blocks().last().unwrap().hash();
+
+    let too_large =
+        block_of_size(&blockchain, MAX_BLOCK_SIZE + 1);
+    assert_eq!(
+        reject_reason(blockchain.add_block(too_large)),
+        RejectReason::BlockTooLarge
+    );
+    assert_eq!(
+        blockchain.blocks().last().unwrap().hash(),
+        tip
+    );
+
+    let largest =
+        block_of_size(&blockchain, MAX_BLOCK_SIZE);
+    blockchain.add_block(largest.clone()).unwrap();
+    assert_eq!(
+        blockchain.blocks().last().unwrap().hash(),
+        largest.hash()
+    );
+}
+
+#[test]
+fn transactions_must_fit_in_a_block() {
+    let key = PrivateKey::new_key();
+    let payee = Script::p2pkh(&key.public_key());
+    let mut blockchain = mature_chain(&payee);
+    let spent = coinbase_output(
+        blockchain.blocks().nth(1).unwrap(),
+    );
+
+    let transaction = spend_to(
+        &blockchain,
+        spent,
+        &key,
+        padding(MAX_BLOCK_SIZE),
+    );
+    assert!(
+        transaction.serialized_size() >= MAX_BLOCK_SIZE
+    );
+    assert_eq!(
+        reject_reason(
+            blockchain.add_to_mempool(transaction)
+        ),
+        RejectReason::TransactionTooLarge
+    );
+}
+
+#[test]
+fn templates_keep_the_best_fee_rates_that_fit() {
+    let key = PrivateKey::new_key();
+    let payee = Script::p2pkh(&key.public_key());
+    let mut blockchain = mature_chain(&payee);
+    mine_blocks(&mut blockchain, 1, &payee);
+
+    // three transactions of 400 kB, any two of which fit in
+    // a block, paying the given fees
+    let fees =
+        [30_000, 1_000, 10_000].map(Amount::from_sat);
+    let transactions: Vec<_> = fees
+        .iter()
+        .enumerate()
+        .map(|(index, fee)| {
+            let spent = coinbase_output(
+                blockchain.blocks().nth(index + 1).unwrap(),
+            );
+            let value =
+                blockchain.utxos()[&spent].output.value;
+            let mut outputs = vec![TransactionOutput {
+                value: value.checked_sub(*fee).unwrap(),
+                locking_script: payee.clone(),
+            }];
+            outputs.extend(padding(400_000));
+            spend_to(&blockchain, spent, &key, outputs)
+        })
+        .collect();
+    for transaction in &transactions {
+        blockchain
+            .add_to_mempool(transaction.clone())
+            .unwrap();
+    }
+
+    let mut template = blockchain
+        .block_template(Script::unspendable(), Utc::now())
+        .unwrap();
+    assert!(template.serialized_size() <= MAX_BLOCK_SIZE);
+    let included: Vec<_> = template
+        .transactions
+        .iter()
+        .skip(1)
+        .map(|transaction| transaction.hash())
+        .collect();
+    assert_eq!(
+        included,
+        vec![
+            transactions[0].hash(),
+            transactions[2].hash()
+        ]
+    );
+    assert_eq!(
+        template.transactions[0].outputs[0].value,
+        Amount::checked_sum([
+            blockchain.calculate_block_reward(),
+            fees[0],
+            fees[2],
+        ])
+        .unwrap()
+    );
+
+    // and it makes a valid block once mined
+    while !template.header.mine(100_000) {}
+    blockchain.add_block(template).unwrap();
+    assert_eq!(blockchain.mempool().len(), 1);
+}
+
+#[test]
+fn only_the_genesis_block_has_no_parent() {
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    let genesis =
+        Network::Regtest.params().genesis_block.clone();
+
+    // the genesis block of another network
+    let main = Network::Main.params().genesis_block.clone();
+    assert_eq!(
+        reject_reason(blockchain.add_block(main)),
+        RejectReason::BadGenesis
+    );
+
+    // or this one's with another nonce or transactions
+    let mut nonce = genesis.clone();
+    nonce.header.nonce += 1;
+    assert_eq!(
+        reject_reason(blockchain.add_block(nonce)),
+        RejectReason::BadGenesis
+    );
+    let mut transactions = genesis.clone();
+    transactions.transactions[0].outputs[0].value =
+        Amount::ZERO;
+    transactions.header.merkle_root =
+        MerkleRoot::calculate(&transactions.transactions);
+    assert_eq!(
+        reject_reason(blockchain.add_block(transactions)),
+        RejectReason::BadGenesis
+    );
+
+    // the genesis block itself is known already
+    assert_eq!(
+        reject_reason(blockchain.add_block(genesis)),
+        RejectReason::DuplicateBlock
+    );
+    assert_eq!(blockchain.block_height(), 1);
+    assert_eq!(blockchain.forks().count(), 0);
+}
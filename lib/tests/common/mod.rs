@@ -0,0 +1,208 @@
+// fixtures shared by the integration tests, each of which
+// only uses some of them
+#![allow(dead_code)]
+
+use btclib::amount::Amount;
+use btclib::crypto::{PrivateKey, Signature};
+use btclib::error::{RejectReason, Result};
+use btclib::params::Network;
+use btclib::script::{Opcode, Script};
+use btclib::types::{
+    Block, BlockHeader, Blockchain, OutPoint, SigHashType,
+    Transaction, TransactionInput, TransactionOutput,
+};
+use btclib::util::MerkleRoot;
+use btclib::U256;
+use chrono::{DateTime, Duration, Utc};
+
+// target of the block after the given one on the regtest
+// network, whose ASERT only looks at the parent
+pub fn regtest_target(
+    parent: &BlockHeader,
+    height: u64,
+) -> U256 {
+    let params = Network::Regtest.params();
+    let target = params
+        .difficulty_algorithm
+        .next_target(
+            height,
+            std::slice::from_ref(parent),
+            params,
+        )
+        .min(params.min_target);
+    U256::from_compact(target.to_compact()).unwrap()
+}
+
+// a regtest coinbase paying the block reward of the given
+// height to the given script
+pub fn coinbase(
+    height: u64,
+    locking_script: Script,
+) -> Transaction {
+    let params = Network::Regtest.params();
+    Transaction::coinbase(
+        height,
+        vec![TransactionOutput {
+            value: params.block_reward(height),
+            locking_script,
+        }],
+    )
+}
+
+// mine a block of the given transactions, coinbase first,
+// on top of the given parent, declaring the given target
+pub fn mine_block(
+    parent: &BlockHeader,
+    timestamp: DateTime<Utc>,
+    target: U256,
+    transactions: Vec<Transaction>,
+) -> Block {
+    let mut header = BlockHeader::new(
+        timestamp,
+        0,
+        parent.hash(),
+        MerkleRoot::calculate(&transactions),
+        target.to_compact(),
+    );
+    while !header.mine(100_000) {}
+
+    Block::new(header, transactions)
+}
+
+// mine a block on top of the given one, wherever it is,
+// ten seconds after it and with the target it requires. The
+// given transactions follow a coinbase paying the block
+// reward to the given script, so they cannot pay any fees
+pub fn mine_child(
+    parent: &BlockHeader,
+    height: u64,
+    locking_script: &Script,
+    transactions: Vec<Transaction>,
+) -> Block {
+    let mut block_transactions =
+        vec![coinbase(height, locking_script.clone())];
+    block_transactions.extend(transactions);
+
+    mine_block(
+        parent,
+        parent.timestamp + Duration::seconds(10),
+        regtest_target(parent, height),
+        block_transactions,
+    )
+}
+
+// mine a block on top of the active chain of a regtest
+// blockchain, see mine_child
+pub fn next_block(
+    blockchain: &Blockchain,
+    locking_script: &Script,
+    transactions: Vec<Transaction>,
+) -> Block {
+    mine_child(
+        &blockchain.blocks().last().unwrap().header,
+        blockchain.block_height(),
+        locking_script,
+        transactions,
+    )
+}
+
+// extend the active chain of a regtest blockchain by the
+// given number of blocks, paying to the given script
+pub fn mine_blocks(
+    blockchain: &mut Blockchain,
+    count: u64,
+    locking_script: &Script,
+) {
+    for _ in 0..count {
+        let block =
+            next_block(blockchain, locking_script, vec![]);
+        blockchain.add_block(block).unwrap();
+    }
+}
+
+// a transaction spending a P2PKH output of the given key to
+// the given script, without paying any fee
+pub fn spend(
+    blockchain: &Blockchain,
+    outpoint: OutPoint,
+    key: &PrivateKey,
+    locking_script: Script,
+) -> Transaction {
+    let value = blockchain.utxos()[&outpoint].output.value;
+    spend_to(
+        blockchain,
+        outpoint,
+        key,
+        vec![TransactionOutput {
+            value,
+            locking_script,
+        }],
+    )
+}
+
+// a transaction spending a P2PKH output of the given key to
+// the given outputs
+pub fn spend_to(
+    blockchain: &Blockchain,
+    outpoint: OutPoint,
+    key: &PrivateKey,
+    outputs: Vec<TransactionOutput>,
+) -> Transaction {
+    let spent = &blockchain.utxos()[&outpoint].output;
+    let mut transaction = Transaction::new(
+        vec![TransactionInput {
+            prev_output: outpoint,
+            unlocking_script: Script::default(),
+            sequence: 0,
+        }],
+        outputs,
+    );
+
+    let sighash =
+        transaction.sighash(0, SigHashType::All).unwrap();
+    let signature = Signature::sign(&sighash, key);
+    transaction.inputs[0].unlocking_script = spent
+        .locking_script
+        .unlock_single_key(
+            &signature,
+            SigHashType::All,
+            &key.public_key(),
+        )
+        .unwrap();
+    transaction
+}
+
+// worthless outputs adding the given number of bytes of
+// data, in pushes of at most 400 bytes
+pub fn padding(bytes: usize) -> Vec<TransactionOutput> {
+    let mut pushes = vec![];
+    let mut left = bytes;
+    while left > 0 {
+        let len = left.min(400);
+        pushes.push(Opcode::PushBytes(vec![0; len]));
+        left -= len;
+    }
+
+    pushes
+        .chunks(100)
+        .map(|pushes| TransactionOutput {
+            value: Amount::ZERO,
+            locking_script: Script::new(
+                [&[Opcode::Return], pushes].concat(),
+            ),
+        })
+        .collect()
+}
+
+// the output of a block's coinbase
+pub fn coinbase_output(block: &Block) -> OutPoint {
+    OutPoint::new(block.transactions[0].hash(), 0)
+}
+
+// the reason a block, header or transaction was rejected for
+pub fn reject_reason<T>(result: Result<T>) -> RejectReason {
+    match result {
+        Ok(_) => panic!("should be rejected"),
+        Err(e) => e.reject_reason(),
+    }
+}
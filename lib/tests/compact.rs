@@ -0,0 +1,102 @@
+use btclib::U256;
+
+#[test]
+fn known_targets() {
+    // the genesis target of Bitcoin and our main network
+    assert_eq!(
+        U256::from_compact(0x1D00_FFFF),
+        Some(U256([0, 0, 0, 0x0000_0000_FFFF_0000]))
+    );
+    assert_eq!(
+        U256::from_compact(0x1B04_04CB),
+        Some(U256::from(0x0404CB) << (8 * (0x1B - 3)))
+    );
+
+    // up to three bytes, the mantissa is shifted down
+    assert_eq!(
+        U256::from_compact(0x0112_3456),
+        Some(U256::from(0x12))
+    );
+    assert_eq!(
+        U256::from_compact(0x0212_3456),
+        Some(U256::from(0x1234))
+    );
+    assert_eq!(
+        U256::from_compact(0x0312_3456),
+        Some(U256::from(0x12_3456))
+    );
+    assert_eq!(
+        U256::from_compact(0x0412_3456),
+        Some(U256::from(0x1234_5600u64))
+    );
+    assert_eq!(
+        U256::from_compact(0x0100_3456),
+        Some(U256::zero())
+    );
+    assert_eq!(U256::from_compact(0), Some(U256::zero()));
+}
+
+#[test]
+fn invalid_targets() {
+    // the sign bit is set
+    assert_eq!(U256::from_compact(0x0492_3456), None);
+    assert_eq!(U256::from_compact(0x01FE_DCBA), None);
+
+    // larger than 256 bits
+    assert_eq!(U256::from_compact(0x2101_0000), None);
+    assert_eq!(U256::from_compact(0xFF12_3456), None);
+    // but the largest regtest target just fits
+    assert_eq!(
+        U256::from_compact(0x207F_FFFF),
+        Some(U256::from(0x7F_FFFF) << 232)
+    );
+}
+
+#[test]
+fn encoding_targets() {
+    assert_eq!(U256::zero().to_compact(), 0);
+    assert_eq!(U256::from(0x12).to_compact(), 0x0112_0000);
+    assert_eq!(
+        U256::from(0x1234).to_compact(),
+        0x0212_3400
+    );
+    assert_eq!(
+        U256::from(0x1234_5600u64).to_compact(),
+        0x0412_3456
+    );
+
+    // a mantissa with the sign bit set moves a byte down
+    assert_eq!(U256::from(0x80).to_compact(), 0x0200_8000);
+    assert_eq!(
+        U256::from(0x80_0000).to_compact(),
+        0x0400_8000
+    );
+
+    // and only three bytes are kept
+    assert_eq!(
+        U256::from(0x1234_5678u64).to_compact(),
+        0x0412_3456
+    );
+}
+
+#[test]
+fn canonical_encodings_round_trip() {
+    for bits in [
+        0x1D00_FFFF,
+        0x1B04_04CB,
+        0x207F_FFFF,
+        0x0112_0000,
+        0x0212_3400,
+        0x0312_3456,
+        0x0200_8000,
+        0x0400_8000,
+        0x0412_3456,
+    ] {
+        let target = U256::from_compact(bits).unwrap();
+        assert_eq!(
+            target.to_compact(),
+            bits,
+            "{bits:#010x}"
+        );
+    }
+}
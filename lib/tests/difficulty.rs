@@ -0,0 +1,306 @@
+use btclib::difficulty::{
+    Asert, ClassicRetarget, DifficultyAlgorithm, Lwma,
+};
+use btclib::error::RejectReason;
+use btclib::params::{ChainParams, Network};
+use btclib::script::Script;
+use btclib::sha256::Hash;
+use btclib::types::{BlockHeader, Blockchain};
+use btclib::util::MerkleRoot;
+use btclib::U256;
+use chrono::{DateTime, Duration, Utc};
+
+mod common;
+use common::{
+    coinbase, mine_block, regtest_target, reject_reason,
+};
+
+// seconds after the genesis block of the regtest network
+fn regtest_time(seconds: i64) -> DateTime<Utc> {
+    Network::Regtest.params().genesis_block.header.timestamp
+        + Duration::seconds(seconds)
+}
+
+// headers spaced the given number of seconds apart, all
+// declaring the same target
+fn headers(
+    count: i64,
+    spacing: i64,
+    target: U256,
+) -> Vec<BlockHeader> {
+    let start = DateTime::from_timestamp(1_700_000_000, 0)
+        .expect("valid timestamp");
+    (0..count)
+        .map(|i| {
+            BlockHeader::new(
+                start + Duration::seconds(i * spacing),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&[]),
+                target.to_compact(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn easy_target_on_active_chain_is_rejected() {
+    let params = Network::Regtest.params();
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    let genesis = blockchain.blocks().next().unwrap().clone();
+
+    // mined far ahead of schedule, so the target drops below
+    // the minimum target right away
+    let block1 = mine_block(
+        &genesis.header,
+        regtest_time(1),
+        blockchain.target(),
+        vec![coinbase(1, Script::unspendable())],
+    );
+    blockchain.add_block(block1.clone()).unwrap();
+    assert!(blockchain.target() < params.min_target);
+
+    // a hash meeting the minimum target is not enough
+    let easy = mine_block(
+        &block1.header,
+        regtest_time(2),
+        params.min_target,
+        vec![coinbase(2, Script::unspendable())],
+    );
+    assert_eq!(
+        reject_reason(blockchain.add_block(easy)),
+        RejectReason::BadDifficultyBits
+    );
+
+    // neither is a target that is too hard
+    let hard = mine_block(
+        &block1.header,
+        regtest_time(2),
+        blockchain.target() / 2,
+        vec![coinbase(2, Script::unspendable())],
+    );
+    assert_eq!(
+        reject_reason(blockchain.add_block(hard)),
+        RejectReason::BadDifficultyBits
+    );
+
+    let block2 = mine_block(
+        &block1.header,
+        regtest_time(2),
+        blockchain.target(),
+        vec![coinbase(2, Script::unspendable())],
+    );
+    blockchain.add_block(block2).unwrap();
+    assert_eq!(blockchain.block_height(), 3);
+}
+
+#[test]
+fn easy_target_on_side_branch_is_rejected() {
+    let params = Network::Regtest.params();
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    let mut tip = blockchain.blocks().next().unwrap().clone();
+    let mut active = vec![tip.clone()];
+    for height in 1..=3 {
+        tip = mine_block(
+            &tip.header,
+            regtest_time(height as i64),
+            blockchain.target(),
+            vec![coinbase(height, Script::unspendable())],
+        );
+        blockchain.add_block(tip.clone()).unwrap();
+        active.push(tip.clone());
+    }
+
+    // a competing branch with as much work as the active
+    // chain stays on the side
+    let fork2 = mine_block(
+        &active[1].header,
+        regtest_time(10),
+        regtest_target(&active[1].header, 2),
+        vec![coinbase(2, Script::unspendable())],
+    );
+    blockchain.add_block(fork2.clone()).unwrap();
+
+    // extending it with an easy target costs next to no work
+    let easy = mine_block(
+        &fork2.header,
+        regtest_time(11),
+        params.min_target,
+        vec![coinbase(3, Script::unspendable())],
+    );
+    let easy_hash = easy.hash();
+    assert_eq!(
+        reject_reason(blockchain.add_block(easy)),
+        RejectReason::BadDifficultyBits
+    );
+    assert!(blockchain
+        .forks()
+        .all(|block| block.hash() != easy_hash));
+
+    // with the targets the branch requires, it is kept and
+    // eventually takes over
+    let fork3 = mine_block(
+        &fork2.header,
+        regtest_time(11),
+        regtest_target(&fork2.header, 3),
+        vec![coinbase(3, Script::unspendable())],
+    );
+    blockchain.add_block(fork3.clone()).unwrap();
+    assert_eq!(blockchain.forks().count(), 2);
+    assert_eq!(
+        blockchain.blocks().last().unwrap().hash(),
+        tip.hash()
+    );
+
+    let fork4 = mine_block(
+        &fork3.header,
+        regtest_time(12),
+        regtest_target(&fork3.header, 4),
+        vec![coinbase(4, Script::unspendable())],
+    );
+    blockchain.add_block(fork4.clone()).unwrap();
+    assert_eq!(
+        blockchain.blocks().last().unwrap().hash(),
+        fork4.hash()
+    );
+}
+
+#[test]
+fn target_follows_the_active_chain() {
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    let genesis = blockchain.blocks().next().unwrap().clone();
+    let initial_target = blockchain.target();
+
+    let block1 = mine_block(
+        &genesis.header,
+        regtest_time(1),
+        blockchain.target(),
+        vec![coinbase(1, Script::unspendable())],
+    );
+    blockchain.add_block(block1).unwrap();
+    assert_ne!(blockchain.target(), initial_target);
+
+    blockchain.disconnect_tip().unwrap();
+    assert_eq!(blockchain.target(), initial_target);
+}
+
+#[test]
+fn classic_retarget_counts_every_solve_time() {
+    let params = ChainParams::main();
+    let interval = params.difficulty_update_interval as i64;
+    let target = params.min_target >> 8;
+
+    // the window reaches back to the last block of the
+    // previous period
+    assert_eq!(
+        ClassicRetarget.window(&params),
+        interval as usize + 1
+    );
+
+    // twice the ideal block time, measured over all of the
+    // interval's solve times
+    let window = headers(interval + 1, 20, target);
+    assert_eq!(
+        ClassicRetarget.next_target(
+            2 * interval as u64,
+            &window,
+            &params
+        ),
+        target * 2
+    );
+
+    // between the boundaries, the target stays as it is
+    assert_eq!(
+        ClassicRetarget.next_target(
+            2 * interval as u64 + 1,
+            &window,
+            &params
+        ),
+        target
+    );
+
+    // and it changes by at most 4x
+    let window = headers(interval + 1, 1, target);
+    assert_eq!(
+        ClassicRetarget.next_target(
+            2 * interval as u64,
+            &window,
+            &params
+        ),
+        target / 4
+    );
+}
+
+#[test]
+fn classic_retarget_rejects_timewarp() {
+    let params = ChainParams::main();
+    let interval = params.difficulty_update_interval;
+    let window =
+        headers(interval as i64 + 1, 10, params.min_target);
+    let last = window.last().unwrap().timestamp;
+    let max_timewarp =
+        Duration::seconds(params.max_timewarp as i64);
+
+    // the first block of a period cannot go back too far
+    assert!(ClassicRetarget.check_timestamp(
+        2 * interval,
+        &window,
+        last - max_timewarp,
+        &params
+    ));
+    assert!(!ClassicRetarget.check_timestamp(
+        2 * interval,
+        &window,
+        last - max_timewarp - Duration::seconds(1),
+        &params
+    ));
+
+    // other blocks are only bound by the median time past
+    assert!(ClassicRetarget.check_timestamp(
+        2 * interval + 1,
+        &window,
+        last - max_timewarp * 10,
+        &params
+    ));
+}
+
+#[test]
+fn per_block_algorithms_keep_the_target_on_schedule() {
+    let params = ChainParams::regtest();
+    let target = params.min_target >> 16;
+    let ideal = params.ideal_block_time as i64;
+
+    let lwma = Lwma { window: 30 };
+    let window = headers(31, ideal, target);
+    let next = lwma.next_target(100, &window, &params);
+    assert!(next <= target && next > target - target / 1000);
+
+    // blocks coming twice as slow double the target
+    let window = headers(31, 2 * ideal, target);
+    let next = lwma.next_target(100, &window, &params);
+    assert!(next <= target * 2 && next > target * 19 / 10);
+
+    // ASERT is anchored at the genesis block, whose target
+    // the parent of a block on schedule keeps
+    let mut params = params;
+    params.genesis_block.header.bits = target.to_compact();
+    let asert = Asert { half_life: 3600 };
+    let mut parent = params.genesis_block.header.clone();
+    parent.timestamp += Duration::seconds(9 * ideal);
+    assert_eq!(
+        asert.next_target(
+            10,
+            std::slice::from_ref(&parent),
+            &params
+        ),
+        target
+    );
+
+    // and one half life behind schedule doubles the target
+    parent.timestamp += Duration::seconds(3600);
+    let next = asert.next_target(10, &[parent], &params);
+    assert!(
+        next > target * 1999 / 1000
+            && next < target * 2001 / 1000
+    );
+}
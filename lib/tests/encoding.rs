@@ -0,0 +1,241 @@
+use btclib::amount::Amount;
+use btclib::encoding::{CompactSize, Decodable, Encodable};
+use btclib::error::RejectReason;
+use btclib::network::Message;
+use btclib::params::Network;
+use btclib::script::{Opcode, Script};
+use btclib::sha256::Hash;
+use btclib::types::{
+    BlockHeader, OutPoint, Transaction, TransactionInput,
+    TransactionOutput,
+};
+
+// encode a value and compare it to the expected hex, then
+// check that decoding gives back the same encoding
+fn assert_encoding<T: Encodable + Decodable>(
+    value: &T,
+    expected: &str,
+) {
+    let bytes = value.encode_to_vec();
+    assert_eq!(hex::encode(&bytes), expected);
+    assert_eq!(value.encoded_size(), bytes.len());
+
+    let decoded = T::decode_from_slice(&bytes).unwrap();
+    assert_eq!(hex::encode(decoded.encode_to_vec()), expected);
+}
+
+fn sample_transaction() -> Transaction {
+    let mut transaction = Transaction::new(
+        vec![TransactionInput {
+            prev_output: OutPoint::new(Hash::zero(), 1),
+            unlocking_script: Script::new(vec![
+                Opcode::PushBytes(vec![0xAB, 0xCD]),
+                Opcode::PushNumber(-1),
+            ]),
+            sequence: 0xFFFF_FFFE,
+        }],
+        vec![TransactionOutput {
+            value: Amount::from_sat(5_000_000_000),
+            locking_script: Script::new(vec![
+                Opcode::Dup,
+                Opcode::Sha256,
+                Opcode::PushBytes(vec![0x11, 0x11]),
+                Opcode::EqualVerify,
+                Opcode::CheckSig,
+            ]),
+        }],
+    );
+    transaction.lock_time = 100;
+    transaction
+}
+
+#[test]
+fn compact_size() {
+    assert_encoding(&CompactSize(0), "00");
+    assert_encoding(&CompactSize(0xFC), "fc");
+    assert_encoding(&CompactSize(0xFD), "fdfd00");
+    assert_encoding(&CompactSize(0xFFFF), "fdffff");
+    assert_encoding(&CompactSize(0x1_0000), "fe00000100");
+    assert_encoding(
+        &CompactSize(0x1_0000_0000),
+        "ff0000000001000000",
+    );
+}
+
+#[test]
+fn non_canonical_compact_size() {
+    for bytes in ["fd0100", "fefc000000", "ffffff000000000000"] {
+        let bytes = hex::decode(bytes).unwrap();
+        assert!(CompactSize::decode_from_slice(&bytes).is_err());
+    }
+}
+
+#[test]
+fn transaction() {
+    let transaction = sample_transaction();
+    assert_encoding(
+        &transaction,
+        concat!(
+            // one input spending output 1 of the zero txid
+            "01",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "01000000",
+            // PushBytes abcd, PushNumber -1
+            "02",
+            "0002abcd",
+            "01ffffffffffffffff",
+            // sequence
+            "feffffff",
+            // one output of 50 BTC
+            "01",
+            "00f2052a01000000",
+            // Dup Sha256 PushBytes 1111 EqualVerify CheckSig
+            "05",
+            "02",
+            "07",
+            "00021111",
+            "05",
+            "08",
+            // coinbase height, lock time
+            "0000000000000000",
+            "6400000000000000",
+        ),
+    );
+    assert_eq!(
+        transaction.hash().to_string(),
+        "7898eaf58258924af68ee0e7755df7641610a3018074f351032fd9adf65cb11e"
+    );
+}
+
+#[test]
+fn block_header() {
+    let encoded = concat!(
+        // 2024-06-15 00:00:00 UTC, no nanoseconds
+        "80d96c6600000000",
+        "00000000",
+        // nonce
+        "2a00000000000000",
+        // previous block hash
+        "0000000000000000000000000000000000000000000000000000000000000000",
+        // merkle root
+        "2222222222222222222222222222222222222222222222222222222222222222",
+        // bits
+        "ffff001f",
+    );
+    let header = BlockHeader::decode_from_slice(
+        &hex::decode(encoded).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(header.timestamp.timestamp(), 1_718_409_600);
+    assert_eq!(header.nonce, 42);
+    assert_eq!(header.prev_block_hash, Hash::zero());
+    assert_eq!(header.bits, 0x1F00_FFFF);
+
+    assert_encoding(&header, encoded);
+    assert_eq!(header.encoded_size(), 88);
+    assert_eq!(
+        header.hash().to_string(),
+        "f1c099de601c8be0473ab737742073916230cda3a18d24c91ebc579737862b0d"
+    );
+}
+
+#[test]
+fn genesis_blocks() {
+    let expected = [
+        (
+            Network::Main,
+            "00004ed0f5d22561b9b0decb9755b80f056fe1f880b6026f912b1de043446475",
+        ),
+        (
+            Network::Test,
+            "00004bdf01d2c83b634a76b91c0fd2e890211a7a5664160254ec847c77dd40f9",
+        ),
+        (
+            Network::Regtest,
+            "1d457adf9fbae4cd97253811a35b065c8f245e809954c8c2cd7290ee6cc6abdf",
+        ),
+    ];
+
+    for (network, hash) in expected {
+        let genesis = &network.params().genesis_block;
+        assert_eq!(genesis.hash().to_string(), hash);
+        assert_eq!(
+            hash.parse::<Hash>().unwrap(),
+            genesis.hash()
+        );
+        assert_encoding(
+            genesis,
+            &hex::encode(genesis.encode_to_vec()),
+        );
+    }
+}
+
+#[test]
+fn message() {
+    assert_encoding(
+        &Message::FetchBlock(7),
+        "0d0700000000000000",
+    );
+    assert_encoding(&Message::DiscoverNodes, "09");
+    assert_encoding(
+        &Message::NodeList(vec!["a:1".to_string()]),
+        "0a0103613a31",
+    );
+    assert_encoding(
+        &Message::Reject {
+            hash: Hash::zero(),
+            reason: RejectReason::HighHash,
+        },
+        concat!(
+            "0f",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "05",
+        ),
+    );
+    assert_encoding(&Message::IndexedTransaction(None), "1200");
+    assert_encoding(&Message::History(Some(vec![])), "140100");
+    assert_encoding(&Message::GetHeaders(vec![]), "1500");
+    assert_encoding(&Message::FetchUtxoSetSummary, "17");
+}
+
+#[test]
+fn reject_reasons() {
+    for (index, reason) in RejectReason::ALL.iter().enumerate() {
+        assert_eq!(*reason as usize, index);
+        assert_encoding(reason, &format!("{index:02x}"));
+        assert_eq!(
+            RejectReason::from_code(reason.code()),
+            Some(*reason)
+        );
+    }
+
+    let unknown = RejectReason::ALL.len() as u8;
+    assert!(RejectReason::decode_from_slice(&[unknown]).is_err());
+    assert_eq!(RejectReason::from_code("unknown"), None);
+}
+
+#[test]
+fn invalid_data() {
+    let transaction = sample_transaction().encode_to_vec();
+
+    // trailing bytes
+    let mut bytes = transaction.clone();
+    bytes.push(0);
+    assert!(Transaction::decode_from_slice(&bytes).is_err());
+
+    // truncated
+    let bytes = &transaction[..transaction.len() - 1];
+    assert!(Transaction::decode_from_slice(bytes).is_err());
+
+    // unknown opcode in place of Dup
+    let mut bytes = transaction.clone();
+    assert_eq!(bytes[65], 0x02);
+    bytes[65] = 0xFF;
+    assert!(Transaction::decode_from_slice(&bytes).is_err());
+
+    // bools are 0 or 1
+    assert!(bool::decode_from_slice(&[2]).is_err());
+
+    // unknown message
+    assert!(Message::decode_from_slice(&[0xFF]).is_err());
+}
@@ -0,0 +1,152 @@
+use btclib::error::RejectReason;
+use btclib::params::Network;
+use btclib::sha256::Hash;
+use btclib::types::{BlockHeader, HeaderChain};
+use btclib::U256;
+use chrono::Duration;
+
+mod common;
+use common::{mine_block, regtest_target, reject_reason};
+
+// mine an empty regtest header on top of the given one,
+// the given number of seconds after it
+fn mine_header(
+    parent: &BlockHeader,
+    height: u64,
+    seconds: i64,
+) -> BlockHeader {
+    mine_block(
+        parent,
+        parent.timestamp + Duration::seconds(seconds),
+        regtest_target(parent, height),
+        vec![],
+    )
+    .header
+}
+
+// extend the best chain by the given number of headers
+fn extend(headers: &mut HeaderChain, count: u64) {
+    for _ in 0..count {
+        let height = headers.height();
+        let header = mine_header(headers.tip(), height, 10);
+        headers.add_header(header).unwrap();
+    }
+}
+
+#[test]
+fn add_header() {
+    let mut headers = HeaderChain::new(Network::Regtest);
+    let genesis = headers.tip().clone();
+    assert_eq!(headers.height(), 1);
+
+    let header1 = mine_header(&genesis, 1, 10);
+    headers.add_header(header1.clone()).unwrap();
+    assert_eq!(headers.height(), 2);
+    assert_eq!(headers.tip().hash(), header1.hash());
+    assert_eq!(headers.hash_at(1), Some(header1.hash()));
+    assert_eq!(headers.height_of(&header1.hash()), Some(1));
+    assert_eq!(
+        headers.chainwork(),
+        genesis.work() + header1.work()
+    );
+
+    assert_eq!(
+        reject_reason(headers.add_header(header1.clone())),
+        RejectReason::DuplicateBlock
+    );
+
+    let mut orphan = mine_header(&header1, 2, 10);
+    orphan.prev_block_hash = Hash::zero();
+    assert_eq!(
+        reject_reason(headers.add_header(orphan)),
+        RejectReason::PrevBlockNotFound
+    );
+
+    // not later than the median time past
+    let mut old = mine_header(&header1, 2, 0);
+    old.timestamp = genesis.timestamp;
+    while !old.mine(100_000) {}
+    assert_eq!(
+        reject_reason(headers.add_header(old)),
+        RejectReason::TimeTooOld
+    );
+}
+
+#[test]
+fn invalid_bits_are_rejected() {
+    let mut headers = HeaderChain::new(Network::Regtest);
+    let genesis = headers.tip().clone();
+    let chainwork = headers.chainwork();
+
+    // bits that decode to a zero target, or do not decode at
+    // all, cannot be met and do not count for any work
+    for bits in [0, 0xFF80_0000, 0x2180_0000] {
+        let mut header = mine_header(&genesis, 1, 10);
+        header.bits = bits;
+        assert_eq!(header.work(), U256::zero());
+        assert_eq!(
+            reject_reason(headers.add_header(header)),
+            RejectReason::BadDifficultyBits
+        );
+    }
+    assert_eq!(headers.height(), 1);
+    assert_eq!(headers.chainwork(), chainwork);
+}
+
+#[test]
+fn most_work_wins() {
+    let mut headers = HeaderChain::new(Network::Regtest);
+    extend(&mut headers, 3);
+    let active = headers.tip().hash();
+    let header1 = headers.header_at(1).unwrap().clone();
+
+    // a branch with as much work as the best chain stays on
+    // the side
+    let fork2 = mine_header(&header1, 2, 11);
+    let fork3 = mine_header(&fork2, 3, 10);
+    headers.add_headers([fork2.clone(), fork3.clone()]).unwrap();
+    assert_eq!(headers.tip().hash(), active);
+    assert_eq!(headers.height_of(&fork3.hash()), None);
+
+    // and takes over once it has more
+    let fork4 = mine_header(&fork3, 4, 10);
+    headers.add_header(fork4.clone()).unwrap();
+    assert_eq!(headers.height(), 5);
+    assert_eq!(headers.tip().hash(), fork4.hash());
+    assert_eq!(headers.hash_at(2), Some(fork2.hash()));
+    assert_eq!(headers.height_of(&active), None);
+}
+
+#[test]
+fn locator() {
+    let mut headers = HeaderChain::new(Network::Regtest);
+    extend(&mut headers, 20);
+    let tip = headers.height() - 1;
+
+    // the last ten, then exponentially further apart, down
+    // to the genesis header
+    let heights: Vec<u64> = headers
+        .locator()
+        .iter()
+        .map(|hash| headers.height_of(hash).unwrap())
+        .collect();
+    let mut expected: Vec<u64> = (tip - 9..=tip).rev().collect();
+    expected.extend([tip - 11, tip - 15, 0]);
+    assert_eq!(heights, expected);
+
+    // a peer sharing the first half of the chain gets the
+    // rest of it
+    let mut peer = HeaderChain::new(Network::Regtest);
+    peer.add_headers((1..=10).map(|height| {
+        headers.header_at(height).unwrap().clone()
+    }))
+    .unwrap();
+    let missing = headers.headers_after(&peer.locator(), 2000);
+    assert_eq!(missing.len(), 10);
+    assert_eq!(missing[0].hash(), headers.hash_at(11).unwrap());
+    peer.add_headers(missing).unwrap();
+    assert_eq!(peer.tip().hash(), headers.tip().hash());
+
+    // and at most as many as asked for
+    assert_eq!(headers.headers_after(&[], 5).len(), 5);
+}
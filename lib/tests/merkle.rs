@@ -0,0 +1,158 @@
+use btclib::error::BtcError;
+use btclib::params::Network;
+use btclib::script::Script;
+use btclib::sha256::Hash;
+use btclib::types::{
+    Block, BlockHeader, Transaction, TransactionOutput,
+};
+use btclib::util::{MerkleProof, MerkleRoot};
+use btclib::U256;
+
+// the given number of distinct transactions
+fn sample_transactions(count: u64) -> Vec<Transaction> {
+    (1..=count)
+        .map(|height| {
+            Transaction::coinbase(
+                height,
+                vec![TransactionOutput {
+                    value: Network::Regtest
+                        .params()
+                        .block_reward(height),
+                    locking_script: Script::unspendable(),
+                }],
+            )
+        })
+        .collect()
+}
+
+// an unmined block committing to the transactions
+fn sample_block(transactions: Vec<Transaction>) -> Block {
+    let header = BlockHeader::new(
+        Network::Regtest.params().genesis_block.header.timestamp,
+        0,
+        Hash::zero(),
+        MerkleRoot::calculate(&transactions),
+        U256::MAX.to_compact(),
+    );
+    Block::new(header, transactions)
+}
+
+#[test]
+fn proofs() {
+    for count in 1..=9 {
+        let block = sample_block(sample_transactions(count));
+        block.verify_merkle_root().unwrap();
+
+        for (index, transaction) in
+            block.transactions.iter().enumerate()
+        {
+            let proof =
+                block.merkle_proof(&transaction.hash()).unwrap();
+            assert_eq!(proof.txid, transaction.hash());
+            assert_eq!(proof.index, index as u32);
+            assert_eq!(proof.transaction_count, count as u32);
+            assert!(proof.verify(&block.header));
+        }
+    }
+
+    assert!(MerkleProof::generate(&sample_transactions(3), 3)
+        .is_none());
+    assert!(sample_block(vec![])
+        .merkle_proof(&Hash::zero())
+        .is_none());
+}
+
+#[test]
+fn invalid_proofs() {
+    let block = sample_block(sample_transactions(5));
+    let proof =
+        MerkleProof::generate(&block.transactions, 2).unwrap();
+    assert!(proof.verify(&block.header));
+
+    let other = sample_transactions(6).pop().unwrap();
+    let invalid = [
+        MerkleProof {
+            txid: other.hash(),
+            ..proof.clone()
+        },
+        MerkleProof {
+            index: 3,
+            ..proof.clone()
+        },
+        MerkleProof {
+            index: 5,
+            ..proof.clone()
+        },
+        // a count with a shallower or deeper tree
+        MerkleProof {
+            transaction_count: 4,
+            ..proof.clone()
+        },
+        MerkleProof {
+            transaction_count: 9,
+            ..proof.clone()
+        },
+        MerkleProof {
+            siblings: proof.siblings[..2].to_vec(),
+            ..proof.clone()
+        },
+        MerkleProof {
+            siblings: [&proof.siblings[..], &[Hash::zero()]]
+                .concat(),
+            ..proof.clone()
+        },
+    ];
+    for proof in invalid {
+        assert!(!proof.verify(&block.header), "{proof:?}");
+    }
+
+    // a proof cannot stop at a pair of hashes and pass it off
+    // as a transaction: the hash of the last two transactions
+    // and the one of the first two would make a valid proof
+    // if the transactions were not tagged
+    let block = sample_block(sample_transactions(4));
+    let first =
+        MerkleProof::generate(&block.transactions, 0).unwrap();
+    let last =
+        MerkleProof::generate(&block.transactions, 2).unwrap();
+    let forged = MerkleProof {
+        txid: first.siblings[1],
+        index: 1,
+        transaction_count: 2,
+        siblings: vec![last.siblings[1]],
+    };
+    assert!(!forged.verify(&block.header));
+}
+
+#[test]
+fn duplicated_transactions() {
+    // repeating the last transaction of an odd layer gives
+    // the same root, as it is paired with itself anyway
+    let mut transactions = sample_transactions(3);
+    let root = MerkleRoot::calculate(&transactions);
+    assert!(
+        MerkleRoot::calculate_checked(&transactions).is_some()
+    );
+
+    transactions.push(transactions[2].clone());
+    assert_eq!(MerkleRoot::calculate(&transactions), root);
+    assert_eq!(
+        MerkleRoot::calculate_checked(&transactions),
+        None
+    );
+
+    let mutated = sample_block(transactions);
+    assert_eq!(mutated.header.merkle_root, root);
+    assert!(matches!(
+        mutated.verify_merkle_root(),
+        Err(BtcError::InvalidMerkleRoot)
+    ));
+
+    // and so does repeating a whole subtree
+    let transactions = sample_transactions(6);
+    let root = MerkleRoot::calculate(&transactions);
+    let repeated =
+        [&transactions[..], &transactions[4..]].concat();
+    assert_eq!(MerkleRoot::calculate(&repeated), root);
+    assert_eq!(MerkleRoot::calculate_checked(&repeated), None);
+}
@@ -0,0 +1,474 @@
+use btclib::amount::Amount;
+use btclib::crypto::{PrivateKey, Signature};
+use btclib::encoding::{Decodable, Encodable};
+use btclib::error::{BtcError, RejectReason, Result};
+use btclib::script::{
+    self, Opcode, Script, ScriptContext,
+    MAX_SCRIPT_ELEMENT_SIZE,
+};
+use btclib::sha256::Hash;
+use btclib::types::{
+    OutPoint, SigHashType, Transaction, TransactionInput,
+    TransactionOutput,
+};
+use btclib::{
+    LOCKTIME_THRESHOLD, SEQUENCE_LOCKTIME_DISABLE_FLAG,
+    SEQUENCE_LOCKTIME_TYPE_FLAG,
+};
+use chrono::DateTime;
+use sha2::{Digest, Sha256};
+
+// a transaction spending a single output, with the given
+// lock time and input sequence
+fn spending_transaction(
+    lock_time: u64,
+    sequence: u32,
+) -> Transaction {
+    let mut transaction = Transaction::new(
+        vec![TransactionInput {
+            prev_output: OutPoint::new(Hash::zero(), 0),
+            unlocking_script: Script::default(),
+            sequence,
+        }],
+        vec![TransactionOutput {
+            value: Amount::from_sat(1000),
+            locking_script: Script::unspendable(),
+        }],
+    );
+    transaction.lock_time = lock_time;
+    transaction
+}
+
+// signature of the transaction's first input, pushed with
+// its sighash type
+fn sign(transaction: &Transaction, key: &PrivateKey) -> Opcode {
+    sign_with(transaction, key, SigHashType::All)
+}
+
+fn sign_with(
+    transaction: &Transaction,
+    key: &PrivateKey,
+    sighash_type: SigHashType,
+) -> Opcode {
+    let sighash = transaction.sighash(0, sighash_type).unwrap();
+    let mut bytes = Signature::sign(&sighash, key).to_bytes();
+    bytes.push(sighash_type.to_byte());
+    Opcode::PushBytes(bytes)
+}
+
+fn verify(
+    transaction: &Transaction,
+    unlocking_script: &Script,
+    locking_script: &Script,
+) -> Result<()> {
+    script::verify(
+        unlocking_script,
+        locking_script,
+        &ScriptContext {
+            transaction,
+            input_index: 0,
+        },
+    )
+}
+
+#[test]
+fn p2pk() {
+    let key = PrivateKey::new_key();
+    let other = PrivateKey::new_key();
+    let transaction = spending_transaction(0, 0);
+    let locking_script = Script::p2pk(&key.public_key());
+
+    let unlocking_script =
+        Script::new(vec![sign(&transaction, &key)]);
+    verify(&transaction, &unlocking_script, &locking_script)
+        .unwrap();
+
+    // a signature by another key
+    let unlocking_script =
+        Script::new(vec![sign(&transaction, &other)]);
+    assert!(matches!(
+        verify(&transaction, &unlocking_script, &locking_script),
+        Err(BtcError::InvalidSignature)
+    ));
+
+    // or for another transaction
+    let unlocking_script = Script::new(vec![sign(
+        &spending_transaction(1, 0),
+        &key,
+    )]);
+    assert!(matches!(
+        verify(&transaction, &unlocking_script, &locking_script),
+        Err(BtcError::InvalidSignature)
+    ));
+
+    // or none at all
+    assert!(verify(
+        &transaction,
+        &Script::default(),
+        &locking_script
+    )
+    .is_err());
+}
+
+#[test]
+fn p2pkh() {
+    let key = PrivateKey::new_key();
+    let other = PrivateKey::new_key();
+    let transaction = spending_transaction(0, 0);
+    let locking_script = Script::p2pkh(&key.public_key());
+
+    let unlocking_script = Script::new(vec![
+        sign(&transaction, &key),
+        Opcode::PushBytes(key.public_key().to_bytes()),
+    ]);
+    verify(&transaction, &unlocking_script, &locking_script)
+        .unwrap();
+
+    // the key has to hash to the one of the script, even if
+    // the signature matches it
+    let unlocking_script = Script::new(vec![
+        sign(&transaction, &other),
+        Opcode::PushBytes(other.public_key().to_bytes()),
+    ]);
+    assert!(matches!(
+        verify(&transaction, &unlocking_script, &locking_script),
+        Err(BtcError::InvalidScript)
+    ));
+
+    // and the signature has to match the key
+    let unlocking_script = Script::new(vec![
+        sign(&transaction, &other),
+        Opcode::PushBytes(key.public_key().to_bytes()),
+    ]);
+    assert!(matches!(
+        verify(&transaction, &unlocking_script, &locking_script),
+        Err(BtcError::InvalidSignature)
+    ));
+}
+
+#[test]
+fn multisig() {
+    let keys: Vec<_> =
+        (0..3).map(|_| PrivateKey::new_key()).collect();
+    let public_keys: Vec<_> =
+        keys.iter().map(PrivateKey::public_key).collect();
+    let transaction = spending_transaction(0, 0);
+    let locking_script = Script::multisig(2, &public_keys);
+    let signatures = |indexes: &[usize]| {
+        Script::new(
+            indexes
+                .iter()
+                .map(|index| sign(&transaction, &keys[*index]))
+                .collect(),
+        )
+    };
+
+    for indexes in [[0, 1], [0, 2], [1, 2]] {
+        verify(
+            &transaction,
+            &signatures(&indexes),
+            &locking_script,
+        )
+        .unwrap();
+    }
+
+    // signatures out of the order of the keys, or twice by
+    // the same key
+    for indexes in [[1, 0], [2, 0], [1, 1]] {
+        assert!(matches!(
+            verify(
+                &transaction,
+                &signatures(&indexes),
+                &locking_script
+            ),
+            Err(BtcError::InvalidSignature)
+        ));
+    }
+
+    // too few signatures
+    assert!(matches!(
+        verify(&transaction, &signatures(&[0]), &locking_script),
+        Err(BtcError::InvalidScript)
+    ));
+}
+
+#[test]
+fn hashlock() {
+    let key = PrivateKey::new_key();
+    let transaction = spending_transaction(0, 0);
+    let preimage = b"secret".to_vec();
+    let hash = Sha256::digest(&preimage);
+    let locking_script =
+        Script::hashlock(&hash, &key.public_key());
+    let unlocking_script = |preimage: &[u8]| {
+        Script::new(vec![
+            sign(&transaction, &key),
+            Opcode::PushBytes(preimage.to_vec()),
+        ])
+    };
+
+    verify(
+        &transaction,
+        &unlocking_script(&preimage),
+        &locking_script,
+    )
+    .unwrap();
+    assert!(matches!(
+        verify(
+            &transaction,
+            &unlocking_script(b"guess"),
+            &locking_script
+        ),
+        Err(BtcError::InvalidScript)
+    ));
+
+    // the preimage alone is not enough
+    let unlocking_script =
+        Script::new(vec![Opcode::PushBytes(preimage)]);
+    assert!(verify(
+        &transaction,
+        &unlocking_script,
+        &locking_script
+    )
+    .is_err());
+}
+
+#[test]
+fn unlocking_scripts_are_push_only() {
+    let key = PrivateKey::new_key();
+    let transaction = spending_transaction(0, 0);
+    let locking_script = Script::p2pk(&key.public_key());
+
+    // would leave the same stack as the signature alone
+    let unlocking_script = Script::new(vec![
+        sign(&transaction, &key),
+        Opcode::PushNumber(1),
+        Opcode::Drop,
+    ]);
+    assert!(!unlocking_script.is_push_only());
+    assert!(matches!(
+        verify(&transaction, &unlocking_script, &locking_script),
+        Err(BtcError::InvalidScript)
+    ));
+}
+
+#[test]
+fn pushes_are_limited_in_size() {
+    let transaction = spending_transaction(0, 0);
+    let push = |len| {
+        Script::new(vec![Opcode::PushBytes(vec![1; len])])
+    };
+    let copies = Script::new(vec![Opcode::Dup; 100]);
+
+    // the largest push can be copied like any other
+    let largest = push(MAX_SCRIPT_ELEMENT_SIZE);
+    verify(&transaction, &largest, &copies).unwrap();
+    let bytes = largest.encode_to_vec();
+    assert_eq!(
+        Script::decode_from_slice(&bytes).unwrap(),
+        largest
+    );
+
+    // one byte more is rejected before anything is copied,
+    // and does not even decode
+    let too_large = push(MAX_SCRIPT_ELEMENT_SIZE + 1);
+    assert!(matches!(
+        verify(&transaction, &too_large, &copies),
+        Err(BtcError::Rejected(RejectReason::PushTooLarge))
+    ));
+    let bytes = too_large.encode_to_vec();
+    assert!(Script::decode_from_slice(&bytes).is_err());
+}
+
+#[test]
+fn sighash_types_commit_to_their_parts() {
+    let key = PrivateKey::new_key();
+    let locking_script = Script::p2pk(&key.public_key());
+    fn input(index: u32) -> TransactionInput {
+        TransactionInput {
+            prev_output: OutPoint::new(Hash::zero(), index),
+            unlocking_script: Script::default(),
+            sequence: 0,
+        }
+    }
+
+    fn output(value: u64) -> TransactionOutput {
+        TransactionOutput {
+            value: Amount::from_sat(value),
+            locking_script: Script::unspendable(),
+        }
+    }
+
+    let transaction = Transaction::new(
+        vec![input(0), input(1)],
+        vec![output(1000), output(2000)],
+    );
+
+    use SigHashType::*;
+    let single = [Single, SingleAnyoneCanPay];
+    let anyone_can_pay = [AllAnyoneCanPay, SingleAnyoneCanPay];
+    let every = [All, Single, AllAnyoneCanPay, SingleAnyoneCanPay];
+
+    // changes to the transaction, with the sighash types
+    // whose signatures of the first input survive them
+    type Change = fn(&mut Transaction);
+    let changes: [(&str, Change, &[SigHashType]); 8] = [
+        ("signed output", |tx| tx.outputs[0] = output(1), &[]),
+        ("other output", |tx| tx.outputs[1] = output(1), &single),
+        ("added output", |tx| tx.outputs.push(output(1)), &single),
+        ("signed sequence", |tx| tx.inputs[0].sequence = 1, &[]),
+        (
+            "other input",
+            |tx| tx.inputs[1] = input(2),
+            &anyone_can_pay,
+        ),
+        (
+            "added input",
+            |tx| tx.inputs.push(input(2)),
+            &anyone_can_pay,
+        ),
+        ("lock time", |tx| tx.lock_time = 1, &[]),
+        (
+            "other unlocking script",
+            |tx| {
+                tx.inputs[1].unlocking_script =
+                    Script::new(vec![Opcode::PushNumber(1)])
+            },
+            &every,
+        ),
+    ];
+
+    for sighash_type in every {
+        let unlocking_script = Script::new(vec![sign_with(
+            &transaction,
+            &key,
+            sighash_type,
+        )]);
+        verify(&transaction, &unlocking_script, &locking_script)
+            .unwrap();
+
+        for (name, change, kept_by) in &changes {
+            let mut changed = transaction.clone();
+            change(&mut changed);
+            assert_eq!(
+                verify(
+                    &changed,
+                    &unlocking_script,
+                    &locking_script
+                )
+                .is_ok(),
+                kept_by.contains(&sighash_type),
+                "{sighash_type:?} signature after changing the \
+                {name}"
+            );
+        }
+    }
+}
+
+#[test]
+fn check_lock_time_verify() {
+    let key = PrivateKey::new_key();
+    let check = |script_lock_time: u64, lock_time: u64| {
+        let transaction = spending_transaction(lock_time, 0);
+        let locking_script = Script::timelock(
+            script_lock_time,
+            Script::p2pk(&key.public_key()),
+        );
+        let unlocking_script =
+            Script::new(vec![sign(&transaction, &key)]);
+        verify(&transaction, &unlocking_script, &locking_script)
+            .is_ok()
+    };
+
+    // heights
+    assert!(!check(100, 99));
+    assert!(check(100, 100));
+    assert!(check(100, 101));
+
+    // timestamps
+    let time = LOCKTIME_THRESHOLD + 1000;
+    assert!(!check(time, time - 1));
+    assert!(check(time, time));
+
+    // a height and a timestamp cannot be compared
+    assert!(!check(100, LOCKTIME_THRESHOLD));
+    assert!(!check(LOCKTIME_THRESHOLD, LOCKTIME_THRESHOLD - 1));
+    assert!(check(LOCKTIME_THRESHOLD, LOCKTIME_THRESHOLD));
+
+    // a negative lock time never passes
+    let transaction = spending_transaction(100, 0);
+    let locking_script = Script::new(vec![
+        Opcode::PushNumber(-1),
+        Opcode::CheckLockTimeVerify,
+    ]);
+    assert!(matches!(
+        verify(
+            &transaction,
+            &Script::default(),
+            &locking_script
+        ),
+        Err(BtcError::InvalidScript)
+    ));
+}
+
+#[test]
+fn check_sequence_verify() {
+    let key = PrivateKey::new_key();
+    let check = |script_sequence: u32, sequence: u32| {
+        let transaction = spending_transaction(0, sequence);
+        let locking_script = Script::relative_timelock(
+            script_sequence,
+            Script::p2pk(&key.public_key()),
+        );
+        let unlocking_script =
+            Script::new(vec![sign(&transaction, &key)]);
+        verify(&transaction, &unlocking_script, &locking_script)
+            .is_ok()
+    };
+
+    // blocks
+    assert!(!check(10, 9));
+    assert!(check(10, 10));
+    assert!(check(10, 11));
+
+    // time intervals
+    let intervals = SEQUENCE_LOCKTIME_TYPE_FLAG | 10;
+    assert!(!check(intervals, intervals - 1));
+    assert!(check(intervals, intervals));
+
+    // blocks and time intervals cannot be compared
+    assert!(!check(10, intervals));
+    assert!(!check(intervals, 10));
+
+    // the input has to have a relative lock, unless the
+    // script's is disabled
+    assert!(!check(10, SEQUENCE_LOCKTIME_DISABLE_FLAG | 10));
+    assert!(check(SEQUENCE_LOCKTIME_DISABLE_FLAG, 0));
+}
+
+#[test]
+fn lock_time_finality() {
+    let time = |seconds: u64| {
+        DateTime::from_timestamp(seconds as i64, 0).unwrap()
+    };
+    let now = time(LOCKTIME_THRESHOLD + 1000);
+
+    assert!(spending_transaction(0, 0).is_final(0, now));
+
+    // locked until after the given height
+    let transaction = spending_transaction(100, 0);
+    assert!(!transaction.is_final(100, now));
+    assert!(transaction.is_final(101, now));
+
+    // or time
+    let transaction =
+        spending_transaction(LOCKTIME_THRESHOLD, 0);
+    assert!(!transaction.is_final(0, time(LOCKTIME_THRESHOLD)));
+    assert!(
+        transaction.is_final(0, time(LOCKTIME_THRESHOLD + 1))
+    );
+
+    // lock times beyond any timestamp do not wrap around
+    let transaction = spending_transaction(u64::MAX, 0);
+    assert!(!transaction.is_final(u64::MAX, now));
+}
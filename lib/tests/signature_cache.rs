@@ -0,0 +1,102 @@
+use btclib::crypto::{
+    PrivateKey, Signature, SignatureCache,
+};
+use btclib::params::Network;
+use btclib::script::Script;
+use btclib::sha256::Hash;
+use btclib::types::{Blockchain, SigHashType};
+
+mod common;
+use common::{
+    coinbase_output, mine_blocks, next_block, spend,
+};
+
+// the tests of this file share the global cache, so they
+// only ever look for their own keys in it, and only the
+// first one adds to it
+
+#[test]
+fn mempool_signatures_are_reused_by_blocks() {
+    let key = PrivateKey::new_key();
+    let payee = Script::p2pkh(&key.public_key());
+    let maturity =
+        Network::Regtest.params().coinbase_maturity;
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    mine_blocks(&mut blockchain, maturity + 1, &payee);
+
+    let spent = coinbase_output(
+        blockchain.blocks().nth(1).unwrap(),
+    );
+    let transaction =
+        spend(&blockchain, spent, &key, payee.clone());
+
+    // signatures are deterministic, so this is the one in
+    // the transaction
+    let sighash =
+        transaction.sighash(0, SigHashType::All).unwrap();
+    let cache_key = SignatureCache::key(
+        &Signature::sign(&sighash, &key),
+        &sighash,
+        &key.public_key(),
+    );
+    let cache = SignatureCache::global();
+    assert!(!cache.contains(&cache_key));
+
+    blockchain.add_to_mempool(transaction.clone()).unwrap();
+    assert!(cache.contains(&cache_key));
+
+    // validating the block finds the signature in the cache
+    // instead of adding it again
+    let len = cache.len();
+    let block =
+        next_block(&blockchain, &payee, vec![transaction]);
+    blockchain.add_block(block).unwrap();
+    assert!(cache.contains(&cache_key));
+    assert_eq!(cache.len(), len);
+}
+
+#[test]
+fn invalid_signatures_are_not_cached() {
+    let key = PrivateKey::new_key();
+    let sighash = Hash::hash(&1u32);
+    let signature = Signature::sign(&sighash, &key);
+    let other_key = PrivateKey::new_key().public_key();
+
+    assert!(!signature.verify_cached(&sighash, &other_key));
+    assert!(!SignatureCache::global().contains(
+        &SignatureCache::key(
+            &signature, &sighash, &other_key
+        )
+    ));
+}
+
+#[test]
+fn full_shards_forget_their_oldest_key() {
+    // 16 shards of two keys each
+    let cache = SignatureCache::new(32);
+
+    // keys of the first shard, and one of another shard
+    let keys: Vec<_> = (0u32..)
+        .map(|i| Hash::hash(&i))
+        .filter(|key| key.as_bytes()[0] % 16 == 0)
+        .take(3)
+        .collect();
+    let other = (0u32..)
+        .map(|i| Hash::hash(&i))
+        .find(|key| key.as_bytes()[0] % 16 == 1)
+        .unwrap();
+
+    cache.insert(other);
+    cache.insert(keys[0]);
+    cache.insert(keys[1]);
+    assert_eq!(cache.len(), 3);
+
+    // inserting a key again does not make it any younger
+    cache.insert(keys[0]);
+    cache.insert(keys[2]);
+    assert!(!cache.contains(&keys[0]));
+    assert!(cache.contains(&keys[1]));
+    assert!(cache.contains(&keys[2]));
+    assert!(cache.contains(&other));
+    assert_eq!(cache.len(), 3);
+}
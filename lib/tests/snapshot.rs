@@ -0,0 +1,307 @@
+use btclib::amount::Amount;
+use btclib::crypto::PrivateKey;
+use btclib::encoding::Encodable;
+use btclib::error::BtcError;
+use btclib::params::{AssumedUtxo, Network};
+use btclib::script::Script;
+use btclib::sha256::Hash;
+use btclib::types::{
+    Block, Blockchain, OutPoint, Transaction,
+    TransactionInput, UtxoEntry, UtxoSnapshot,
+};
+use btclib::util::{MerkleRoot, Saveable};
+
+mod common;
+use common::{coinbase, mine_blocks};
+
+// the known snapshot matching a snapshot
+fn assumed(snapshot: &UtxoSnapshot) -> AssumedUtxo {
+    let summary = snapshot.summary().unwrap();
+    AssumedUtxo {
+        height: summary.height,
+        block_hash: summary.tip,
+        commitment: summary.commitment,
+    }
+}
+
+#[test]
+fn commitment_follows_the_utxo_set() {
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    let genesis = blockchain.utxo_set_summary();
+    mine_blocks(&mut blockchain, 3, &Script::unspendable());
+
+    let summary = blockchain.utxo_set_summary();
+    assert_eq!(summary.height, 3);
+    assert_eq!(summary.count, 4);
+    assert_eq!(
+        summary.total_value.to_sat(),
+        blockchain
+            .utxos()
+            .values()
+            .map(|entry| entry.output.value.to_sat())
+            .sum::<u64>()
+    );
+
+    // the same UTXO set has the same commitment, however it
+    // was arrived at
+    let mut rebuilt = blockchain.clone();
+    rebuilt.rebuild_utxos();
+    assert_eq!(rebuilt.utxo_set_summary(), summary);
+
+    for _ in 0..3 {
+        blockchain.disconnect_tip().unwrap();
+    }
+    assert_eq!(blockchain.utxo_set_summary(), genesis);
+}
+
+#[test]
+fn start_from_snapshot() {
+    let key = PrivateKey::new_key();
+    let payee = Script::p2pkh(&key.public_key());
+    let mut full = Blockchain::new(Network::Regtest);
+    mine_blocks(&mut full, 6, &payee);
+
+    let snapshot = full.export_snapshot(4).unwrap();
+    assert_eq!(snapshot.headers.len(), 5);
+    assert_eq!(snapshot.utxos.len(), 5);
+    let summary = snapshot.summary().unwrap();
+    let known = assumed(&snapshot);
+
+    let mut started = Blockchain::from_snapshot(
+        Network::Regtest,
+        snapshot,
+        &known,
+    )
+    .unwrap();
+    assert_eq!(started.snapshot(), Some(summary));
+    assert_eq!(started.utxo_set_summary(), summary);
+    assert_eq!(
+        started.chainwork(),
+        full.chainwork_at(4).unwrap()
+    );
+
+    // the address index only covers the blocks after the
+    // snapshot, so it cannot tell which outputs a key has yet
+    started.enable_address_index();
+    assert_eq!(started.utxos_of(&key.public_key()).len(), 4);
+
+    // the blocks after the snapshot connect as usual
+    for block in full.blocks().skip(5) {
+        started.add_block(block.clone()).unwrap();
+    }
+    assert_eq!(
+        started.utxo_set_summary(),
+        full.utxo_set_summary()
+    );
+    assert_eq!(started.target(), full.target());
+
+    // rebuilding replays them on top of the snapshot
+    let mut rebuilt = started.clone();
+    rebuilt.rebuild_utxos();
+    assert_eq!(
+        rebuilt.utxo_set_summary(),
+        full.utxo_set_summary()
+    );
+
+    // but nothing before it can be disconnected or exported
+    assert!(started.export_snapshot(3).is_none());
+    assert!(started.disconnect_tip().is_some());
+    assert!(started.disconnect_tip().is_some());
+    assert!(started.disconnect_tip().is_none());
+    assert_eq!(started.utxo_set_summary(), summary);
+
+    // until the blocks before it have been validated
+    let mut validated = Blockchain::new(Network::Regtest);
+    for block in full.blocks().skip(1).take(4) {
+        validated.add_block(block.clone()).unwrap();
+    }
+    started.complete_snapshot(validated).unwrap();
+    assert_eq!(started.snapshot(), None);
+    assert_eq!(started.utxos_of(&key.public_key()).len(), 4);
+    assert_eq!(
+        started.export_snapshot(2).unwrap().summary(),
+        full.export_snapshot(2).unwrap().summary()
+    );
+}
+
+#[test]
+fn invalid_snapshots_are_rejected() {
+    let mut full = Blockchain::new(Network::Regtest);
+    mine_blocks(&mut full, 4, &Script::unspendable());
+    let snapshot = full.export_snapshot(3).unwrap();
+    let known = assumed(&snapshot);
+
+    let start = |snapshot: UtxoSnapshot,
+                 assumed: &AssumedUtxo| {
+        Blockchain::from_snapshot(
+            Network::Regtest,
+            snapshot,
+            assumed,
+        )
+    };
+
+    // a different UTXO set
+    let mut tampered = snapshot.clone();
+    tampered.utxos[0].1.is_coinbase = false;
+    assert!(matches!(
+        start(tampered, &known),
+        Err(BtcError::InvalidSnapshot)
+    ));
+
+    // or the same UTXO set claimed at another block
+    let mut truncated = snapshot.clone();
+    truncated.headers.pop();
+    assert!(matches!(
+        start(truncated, &known),
+        Err(BtcError::InvalidSnapshot)
+    ));
+
+    // or of another network
+    assert!(Blockchain::from_snapshot(
+        Network::Test,
+        snapshot.clone(),
+        &known
+    )
+    .is_err());
+
+    // a snapshot that passes, but whose blocks turn out to
+    // lead elsewhere
+    let mut started = start(snapshot, &known).unwrap();
+    let mut other = Blockchain::new(Network::Regtest);
+    let payee =
+        Script::p2pkh(&PrivateKey::new_key().public_key());
+    mine_blocks(&mut other, 3, &payee);
+    assert!(matches!(
+        started.complete_snapshot(other),
+        Err(BtcError::InvalidSnapshot)
+    ));
+    assert!(started.snapshot().is_some());
+}
+
+#[test]
+fn save_and_load() {
+    let save = |blockchain: &Blockchain| {
+        let mut bytes = vec![];
+        blockchain.save(&mut bytes).unwrap();
+        bytes
+    };
+
+    let mut full = Blockchain::new(Network::Regtest);
+    mine_blocks(&mut full, 5, &Script::unspendable());
+    let loaded = Blockchain::load(&save(&full)[..]).unwrap();
+    assert_eq!(loaded.network(), Network::Regtest);
+    assert_eq!(loaded.block_height(), full.block_height());
+    assert_eq!(loaded.chainwork(), full.chainwork());
+    assert_eq!(
+        loaded.utxo_set_summary(),
+        full.utxo_set_summary()
+    );
+    assert_eq!(save(&loaded), save(&full));
+
+    // a blockchain started from a snapshot keeps the
+    // snapshot's UTXO set, as its blocks cannot be replayed
+    let snapshot = full.export_snapshot(3).unwrap();
+    let known = assumed(&snapshot);
+    let mut started = Blockchain::from_snapshot(
+        Network::Regtest,
+        snapshot,
+        &known,
+    )
+    .unwrap();
+    started
+        .add_block(full.blocks().nth(4).unwrap().clone())
+        .unwrap();
+    let mut loaded =
+        Blockchain::load(&save(&started)[..]).unwrap();
+    assert_eq!(loaded.snapshot(), started.snapshot());
+    assert_eq!(
+        loaded.utxo_set_summary(),
+        started.utxo_set_summary()
+    );
+    assert_eq!(save(&loaded), save(&started));
+    assert!(loaded.disconnect_tip().is_some());
+    assert!(loaded.disconnect_tip().is_none());
+
+    // a truncated file does not load
+    let bytes = save(&started);
+    assert!(Blockchain::load(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn damaged_files_do_not_load() {
+    // a saved blockchain of the given blocks
+    let file = |network: Network, blocks: &Vec<Block>| {
+        let snapshot: Option<(
+            u64,
+            Vec<(OutPoint, UtxoEntry)>,
+        )> = None;
+        let mut bytes = network.encode_to_vec();
+        bytes.extend(blocks.encode_to_vec());
+        bytes.extend(Vec::<Block>::new().encode_to_vec());
+        bytes.extend(snapshot.encode_to_vec());
+        bytes
+    };
+    let load_error = |bytes: Vec<u8>| {
+        Blockchain::load(&bytes[..])
+            .expect_err("should not load")
+            .to_string()
+    };
+
+    let mut full = Blockchain::new(Network::Regtest);
+    mine_blocks(&mut full, 4, &Script::unspendable());
+    let blocks: Vec<_> = full.blocks().cloned().collect();
+    let mut saved = vec![];
+    full.save(&mut saved).unwrap();
+    assert_eq!(file(Network::Regtest, &blocks), saved);
+
+    // a block is missing
+    let mut missing = blocks.clone();
+    missing.remove(2);
+    assert_eq!(
+        load_error(file(Network::Regtest, &missing)),
+        "block does not extend the chain"
+    );
+
+    // a transaction was changed
+    let mut changed = blocks.clone();
+    changed[2].transactions[0].outputs[0].value =
+        Amount::ZERO;
+    assert_eq!(
+        load_error(file(Network::Regtest, &changed)),
+        "invalid merkle root"
+    );
+
+    // a block spends an output which does not exist
+    let mut spending = blocks.clone();
+    let mut block = Block::new(
+        blocks[4].header.clone(),
+        vec![
+            coinbase(5, Script::unspendable()),
+            Transaction::new(
+                vec![TransactionInput {
+                    prev_output: OutPoint::new(
+                        Hash::zero(),
+                        0,
+                    ),
+                    unlocking_script: Script::default(),
+                    sequence: 0,
+                }],
+                vec![],
+            ),
+        ],
+    );
+    block.header.prev_block_hash = blocks[4].hash();
+    block.header.merkle_root =
+        MerkleRoot::calculate(&block.transactions);
+    spending.push(block);
+    assert_eq!(
+        load_error(file(Network::Regtest, &spending)),
+        "block spends a missing output"
+    );
+
+    // or the blocks are of another network
+    assert_eq!(
+        load_error(file(Network::Test, &blocks)),
+        "wrong genesis block"
+    );
+}
@@ -0,0 +1,128 @@
+use btclib::amount::Amount;
+use btclib::crypto::PrivateKey;
+use btclib::params::Network;
+use btclib::script::Script;
+use btclib::sha256::Hash;
+use btclib::types::{
+    Blockchain, OutPoint, TransactionOutput,
+    UtxoCommitment, UtxoEntry,
+};
+use chrono::DateTime;
+
+mod common;
+use common::{
+    coinbase_output, mine_blocks, next_block, spend,
+};
+
+// distinct unspent outputs
+fn utxos(count: u64) -> Vec<(OutPoint, UtxoEntry)> {
+    (0..count)
+        .map(|i| {
+            let outpoint =
+                OutPoint::new(Hash::hash(&i), i as u32);
+            let entry = UtxoEntry {
+                output: TransactionOutput {
+                    value: Amount::from_sat(1000 * i),
+                    locking_script: Script::unspendable(),
+                },
+                height: i,
+                timestamp: DateTime::from_timestamp(
+                    i as i64, 0,
+                )
+                .unwrap(),
+                is_coinbase: i % 2 == 0,
+                marked: false,
+            };
+            (outpoint, entry)
+        })
+        .collect()
+}
+
+fn commitment<'a>(
+    utxos: impl IntoIterator<Item = &'a (OutPoint, UtxoEntry)>,
+) -> UtxoCommitment {
+    let mut commitment = UtxoCommitment::new();
+    for (outpoint, entry) in utxos {
+        commitment.add(outpoint, entry);
+    }
+    commitment
+}
+
+#[test]
+fn commitment_does_not_depend_on_order() {
+    let utxos = utxos(10);
+    let in_order = commitment(&utxos);
+    assert_eq!(commitment(utxos.iter().rev()), in_order);
+    assert_eq!(
+        UtxoCommitment::from_utxos(
+            &utxos.iter().cloned().collect()
+        ),
+        in_order
+    );
+    assert_eq!(in_order.count(), 10);
+    assert_eq!(
+        in_order.total_value(),
+        Amount::from_sat(45_000)
+    );
+
+    // but on every single output
+    assert_ne!(
+        commitment(&utxos[1..]).hash(),
+        in_order.hash()
+    );
+    let mut changed = utxos.clone();
+    changed[0].1.is_coinbase = false;
+    assert_ne!(
+        commitment(&changed).hash(),
+        in_order.hash()
+    );
+}
+
+#[test]
+fn removing_an_output_undoes_adding_it() {
+    let utxos = utxos(5);
+    let (extra, set) = utxos.split_last().unwrap();
+    let before = commitment(set);
+
+    let mut updated = before;
+    updated.add(&extra.0, &extra.1);
+    assert_ne!(updated.hash(), before.hash());
+    updated.remove(&extra.0, &extra.1);
+    assert_eq!(updated, before);
+    assert_eq!(updated.hash(), before.hash());
+
+    // down to the empty set
+    for (outpoint, entry) in set {
+        updated.remove(outpoint, entry);
+    }
+    assert_eq!(updated, UtxoCommitment::new());
+}
+
+#[test]
+fn disconnect_tip_restores_the_summary() {
+    let key = PrivateKey::new_key();
+    let payee = Script::p2pkh(&key.public_key());
+    let maturity =
+        Network::Regtest.params().coinbase_maturity;
+    let mut blockchain = Blockchain::new(Network::Regtest);
+    mine_blocks(&mut blockchain, maturity + 1, &payee);
+    let summary = blockchain.utxo_set_summary();
+
+    // a block both adding and spending outputs
+    let spent = coinbase_output(
+        blockchain.blocks().nth(1).unwrap(),
+    );
+    let transaction = spend(
+        &blockchain,
+        spent,
+        &key,
+        Script::unspendable(),
+    );
+    let block =
+        next_block(&blockchain, &payee, vec![transaction]);
+    blockchain.add_block(block).unwrap();
+    assert_ne!(blockchain.utxo_set_summary(), summary);
+
+    blockchain.disconnect_tip().unwrap();
+    assert_eq!(blockchain.utxo_set_summary(), summary);
+}
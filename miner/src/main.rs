@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Result};
 use btclib::crypto::PublicKey;
 use btclib::network::Message;
+use btclib::params::Network;
 use btclib::types::Block;
 use btclib::util::Saveable;
 use clap::Parser;
@@ -20,9 +21,12 @@ struct Cli {
     address: String,
     #[arg(short, long)]
     public_key_file: String,
+    #[arg(short, long, default_value_t = Network::Main)]
+    network: Network,
 }
 
 struct Miner {
+    network: Network,
     public_key: PublicKey,
     stream: Mutex<TcpStream>,
     current_template: Arc<std::sync::Mutex<Option<Block>>>,
@@ -33,6 +37,7 @@ struct Miner {
 
 impl Miner {
     async fn new(
+        network: Network,
         address: String,
         public_key: PublicKey,
     ) -> Result<Self> {
@@ -40,6 +45,7 @@ impl Miner {
         let (mined_block_sender, mined_block_receiver) =
             flume::unbounded();
         Ok(Self {
+            network,
             public_key,
             stream: Mutex::new(stream),
             current_template: Arc::new(std::sync::Mutex::new(
@@ -84,7 +90,7 @@ impl Miner {
                 {
                     println!(
                         "Mining block with target: {}",
-                        block.header.target
+                        block.header.target()
                     );
                     if block.header.mine(2_000_000) {
                         println!(
@@ -117,14 +123,16 @@ impl Miner {
             Message::FetchTemplate(self.public_key.clone());
 
         let mut stream_lock = self.stream.lock().await;
-        message.send_async(&mut *stream_lock).await?;
+        message
+            .send_async(self.network, &mut *stream_lock)
+            .await?;
         drop(stream_lock);
 
         let mut stream_lock = self.stream.lock().await;
-        match Message::receive_async(&mut *stream_lock).await? {
+        match Message::receive_async(self.network, &mut *stream_lock).await? {
             Message::Template(template) => {
                 drop(stream_lock);
-                println!("Received new template with target: {}", template.header.target);
+                println!("Received new template with target: {}", template.header.target());
                 *self.current_template.lock().unwrap() = Some(template);
                 self.mining.store(true, Ordering::Relaxed);
                 Ok(())
@@ -139,11 +147,13 @@ impl Miner {
         {
             let message = Message::ValidateTemplate(template);
             let mut stream_lock = self.stream.lock().await;
-            message.send_async(&mut *stream_lock).await?;
+            message
+                .send_async(self.network, &mut *stream_lock)
+                .await?;
             drop(stream_lock);
 
             let mut stream_lock = self.stream.lock().await;
-            match Message::receive_async(&mut *stream_lock).await? {
+            match Message::receive_async(self.network, &mut *stream_lock).await? {
                 Message::TemplateValidity(valid) => {
                     drop(stream_lock);
                     if !valid {
@@ -165,9 +175,22 @@ impl Miner {
         println!("Submitting mined block");
         let message = Message::SubmitTemplate(block);
         let mut stream_lock = self.stream.lock().await;
-        message.send_async(&mut *stream_lock).await?;
+        message
+            .send_async(self.network, &mut *stream_lock)
+            .await?;
         self.mining.store(false, Ordering::Relaxed);
-        Ok(())
+
+        match Message::receive_async(self.network, &mut *stream_lock).await? {
+            Message::Accepted(hash) => {
+                println!("Block {} accepted", hash);
+                Ok(())
+            }
+            Message::Reject { hash, reason } => {
+                println!("Block {} rejected: {}", hash, reason);
+                Ok(())
+            }
+            _ => Err(anyhow!("Unexpected message received when submitting block")),
+        }
     }
 }
 
@@ -181,6 +204,7 @@ async fn main() -> Result<()> {
                 anyhow!("Error reading public key: {}", e)
             })?;
 
-    let miner = Miner::new(cli.address, public_key).await?;
+    let miner =
+        Miner::new(cli.network, cli.address, public_key).await?;
     miner.run().await
 }
@@ -1,33 +1,62 @@
 use btclib::sha256::Hash;
 use chrono::Utc;
-use uuid::Uuid;
 
 use tokio::net::TcpStream;
+use tracing::{error, info, warn};
 
-use btclib::network::Message;
-use btclib::types::{
-    Block, BlockHeader, Transaction, TransactionOutput,
-};
-use btclib::util::MerkleRoot;
+use btclib::error::{BtcError, RejectReason};
+use btclib::network::{Message, UtxoInfo};
+use btclib::params::Network;
+use btclib::script::Script;
+
+// tell the sender of a block or transaction why it was
+// rejected
+async fn reject(
+    network: Network,
+    socket: &mut TcpStream,
+    hash: Hash,
+    error: &BtcError,
+) {
+    let message = Message::Reject {
+        hash,
+        reason: error.reject_reason(),
+    };
+    if let Err(e) = message.send_async(network, socket).await {
+        warn!("failed to send reject message: {e}");
+    }
+}
 
 pub async fn handle_connection(mut socket: TcpStream) {
+    let network = crate::BLOCKCHAIN.read().await.network();
+
     loop {
         // read a message from the socket
-        let message = match Message::receive_async(&mut socket)
-            .await
+        let message = match Message::receive_async(
+            network,
+            &mut socket,
+        )
+        .await
         {
             Ok(message) => message,
             Err(e) => {
-                println!("invalid message from peer: {e}, closing that connection");
+                warn!("invalid message from peer: {e}, closing that connection");
                 return;
             }
         };
 
         use btclib::network::Message::*;
         match message {
-            UTXOs(_) | Template(_) | Difference(_)
-            | TemplateValidity(_) | NodeList(_) => {
-                println!(
+            UTXOs(_)
+            | Template(_)
+            | Difference { .. }
+            | TemplateValidity(_)
+            | NodeList(_)
+            | Accepted(_)
+            | IndexedTransaction(_)
+            | History(_)
+            | Headers(_)
+            | UtxoSetSummary(_) => {
+                warn!(
                     "I am neither a miner nor a \
                           wallet! Goodbye"
                 );
@@ -43,8 +72,35 @@ pub async fn handle_connection(mut socket: TcpStream) {
                     return;
                 };
 
+                // we only have the headers of the blocks up to
+                // an unvalidated snapshot, the peer has to get
+                // them from someone else
+                let before_snapshot = blockchain
+                    .snapshot()
+                    .is_some_and(|snapshot| {
+                        (1..=snapshot.height)
+                            .contains(&(height as u64))
+                    });
+                if before_snapshot {
+                    info!(
+                        "block {height} is before the snapshot"
+                    );
+                    let message = Reject {
+                        hash: block.hash(),
+                        reason: RejectReason::NotAvailable,
+                    };
+                    message
+                        .send_async(network, &mut socket)
+                        .await
+                        .unwrap();
+                    continue;
+                }
+
                 let message = NewBlock(block);
-                message.send_async(&mut socket).await.unwrap();
+                message
+                    .send_async(network, &mut socket)
+                    .await
+                    .unwrap();
             }
             DiscoverNodes => {
                 let nodes = crate::NODES
@@ -52,52 +108,119 @@ pub async fn handle_connection(mut socket: TcpStream) {
                     .map(|x| x.key().clone())
                     .collect::<Vec<_>>();
                 let message = NodeList(nodes);
-                message.send_async(&mut socket).await.unwrap();
+                message
+                    .send_async(network, &mut socket)
+                    .await
+                    .unwrap();
             }
-            AskDifference(height) => {
+            AskDifference(chainwork) => {
                 let blockchain = crate::BLOCKCHAIN.read().await;
-                let count = blockchain.block_height() as i32
-                    - height as i32;
-                let message = Difference(count);
-                message.send_async(&mut socket).await.unwrap();
+                let message = Difference {
+                    extra_work: blockchain
+                        .chainwork()
+                        .saturating_sub(chainwork),
+                    height: blockchain.block_height(),
+                };
+                message
+                    .send_async(network, &mut socket)
+                    .await
+                    .unwrap();
             }
             FetchUTXOs(key) => {
-                println!("received request to fetch UTXOs");
+                info!("received request to fetch UTXOs");
                 let blockchain = crate::BLOCKCHAIN.read().await;
+                // outputs are spent in the next block at the
+                // earliest
+                let height = blockchain.block_height();
 
                 let utxos = blockchain
-                    .utxos()
-                    .iter()
-                    .filter(|(_, (_, txout))| {
-                        txout.pubkey == key
-                    })
-                    .map(|(_, (marked, txout))| {
-                        (txout.clone(), *marked)
+                    .utxos_of(&key)
+                    .into_iter()
+                    .map(|(outpoint, entry)| UtxoInfo {
+                        outpoint,
+                        output: entry.output.clone(),
+                        marked: entry.marked,
+                        immature: !entry.is_mature(
+                            height,
+                            blockchain.params(),
+                        ),
                     })
                     .collect::<Vec<_>>();
 
                 let message = UTXOs(utxos);
-                message.send_async(&mut socket).await.unwrap();
+                message
+                    .send_async(network, &mut socket)
+                    .await
+                    .unwrap();
+            }
+            FetchTransaction(txid) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let transaction =
+                    blockchain.find_transaction(&txid).map(
+                        |(location, tx)| (location, tx.clone()),
+                    );
+
+                let message = IndexedTransaction(transaction);
+                message
+                    .send_async(network, &mut socket)
+                    .await
+                    .unwrap();
+            }
+            GetHeaders(locator) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let headers = blockchain.headers_after(
+                    &locator,
+                    btclib::MAX_HEADERS_PER_MESSAGE,
+                );
+
+                let message = Headers(headers);
+                message
+                    .send_async(network, &mut socket)
+                    .await
+                    .unwrap();
+            }
+            FetchHistory(key) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = History(blockchain.history(&key));
+                message
+                    .send_async(network, &mut socket)
+                    .await
+                    .unwrap();
+            }
+            FetchUtxoSetSummary => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = UtxoSetSummary(
+                    blockchain.utxo_set_summary(),
+                );
+                message
+                    .send_async(network, &mut socket)
+                    .await
+                    .unwrap();
             }
 
+            Reject { hash, reason } => {
+                warn!("peer rejected {hash}: {reason}");
+            }
             NewBlock(block) => {
                 let mut blockchain =
                     crate::BLOCKCHAIN.write().await;
-                println!("received new block");
+                let hash = block.hash();
+                info!("received new block {hash}");
 
-                if blockchain.add_block(block).is_err() {
-                    println!("block rejected");
+                if let Err(e) = blockchain.add_block(block) {
+                    warn!("block {hash} rejected: {e}");
+                    reject(network, &mut socket, hash, &e).await;
                 }
             }
             NewTransaction(tx) => {
                 let mut blockchain =
                     crate::BLOCKCHAIN.write().await;
+                let hash = tx.hash();
+                info!("received transaction {hash} from friend");
 
-                println!("received transaction from friend");
-
-                if blockchain.add_to_mempool(tx).is_err() {
-                    println!("transaction rejected, closing connection");
-                    return;
+                if let Err(e) = blockchain.add_to_mempool(tx) {
+                    warn!("transaction {hash} rejected: {e}");
+                    reject(network, &mut socket, hash, &e).await;
                 }
             }
             ValidateTemplate(block_template) => {
@@ -112,24 +235,30 @@ pub async fn handle_connection(mut socket: TcpStream) {
                             .unwrap_or(Hash::zero());
 
                 let message = TemplateValidity(status);
-                message.send_async(&mut socket).await.unwrap();
+                message
+                    .send_async(network, &mut socket)
+                    .await
+                    .unwrap();
             }
             SubmitTemplate(block) => {
-                println!("received allegedly mined template");
+                info!("received allegedly mined template");
                 let mut blockchain =
                     crate::BLOCKCHAIN.write().await;
+                let hash = block.hash();
                 if let Err(e) =
                     blockchain.add_block(block.clone())
                 {
-                    println!(
-                        "block rejected: {e}, closing connection"
-                    );
-                    return;
+                    warn!("block {hash} rejected: {e}");
+                    reject(network, &mut socket, hash, &e).await;
+                    continue;
                 }
 
-                blockchain.rebuild_utxos();
-
-                println!("block looks good, broadcasting");
+                info!("block looks good, broadcasting");
+                let message = Accepted(hash);
+                message
+                    .send_async(network, &mut socket)
+                    .await
+                    .unwrap();
 
                 // send block to all friend nodes
                 let nodes = crate::NODES
@@ -144,11 +273,11 @@ pub async fn handle_connection(mut socket: TcpStream) {
                         let message =
                             Message::NewBlock(block.clone());
                         if message
-                            .send_async(&mut *stream)
+                            .send_async(network, &mut *stream)
                             .await
                             .is_err()
                         {
-                            println!(
+                            warn!(
                                 "failed to send block to {}",
                                 node
                             );
@@ -157,17 +286,24 @@ pub async fn handle_connection(mut socket: TcpStream) {
                 }
             }
             SubmitTransaction(tx) => {
-                println!("submmit tx");
+                info!("submmit tx");
                 let mut blockchain =
                     crate::BLOCKCHAIN.write().await;
+                let hash = tx.hash();
                 if let Err(e) =
                     blockchain.add_to_mempool(tx.clone())
                 {
-                    println!("transaction rejected, closing connection: {e}");
-                    return;
+                    warn!("transaction {hash} rejected: {e}");
+                    reject(network, &mut socket, hash, &e).await;
+                    continue;
                 }
 
-                println!("added transaction to mempool");
+                info!("added transaction to mempool");
+                let message = Accepted(hash);
+                message
+                    .send_async(network, &mut socket)
+                    .await
+                    .unwrap();
 
                 // send transaction to all friend nodes
                 let nodes = crate::NODES
@@ -176,91 +312,42 @@ pub async fn handle_connection(mut socket: TcpStream) {
                     .collect::<Vec<_>>();
 
                 for node in nodes {
-                    println!("sending to friend: {node}");
+                    info!("sending to friend: {node}");
                     if let Some(mut stream) =
                         crate::NODES.get_mut(&node)
                     {
                         let message =
                             Message::NewTransaction(tx.clone());
                         if message
-                            .send_async(&mut *stream)
+                            .send_async(network, &mut *stream)
                             .await
                             .is_err()
                         {
-                            println!("failed to send transaction to {}", node);
+                            warn!("failed to send transaction to {}", node);
                         }
                     }
                 }
 
-                println!("transaction sent to friends");
+                info!("transaction sent to friends");
             }
             FetchTemplate(pubkey) => {
                 let blockchain = crate::BLOCKCHAIN.read().await;
-
-                let mut transactions = vec![];
-                // insert transactions from mempool
-                transactions.extend(
-                    blockchain
-                        .mempool()
-                        .iter()
-                        .take(btclib::BLOCK_TRANSACTION_CAP)
-                        .map(|(_, tx)| tx)
-                        .cloned()
-                        .collect::<Vec<_>>(),
-                );
-                // insert coinbase tx with pubkey
-                transactions.insert(
-                    0,
-                    Transaction {
-                        inputs: vec![],
-                        outputs: vec![TransactionOutput {
-                            pubkey,
-                            unique_id: Uuid::new_v4(),
-                            value: 0,
-                        }],
-                    },
-                );
-
-                let merkle_root =
-                    MerkleRoot::calculate(&transactions);
-
-                let mut block = Block::new(
-                    BlockHeader {
-                        timestamp: Utc::now(),
-                        prev_block_hash: blockchain
-                            .blocks()
-                            .last()
-                            .map(|last_block| last_block.hash())
-                            .unwrap_or(Hash::zero()),
-                        nonce: 0,
-                        target: blockchain.target(),
-                        merkle_root,
-                    },
-                    transactions,
-                );
-
-                let miner_fees = match block
-                    .calculate_miner_fees(blockchain.utxos())
-                {
-                    Ok(fees) => fees,
+                let block = match blockchain.block_template(
+                    Script::p2pkh(&pubkey),
+                    Utc::now(),
+                ) {
+                    Ok(block) => block,
                     Err(e) => {
-                        eprintln!("{e}");
+                        error!("{e}");
                         return;
                     }
                 };
 
-                let reward = blockchain.calculate_block_reward();
-
-                // update coinbase tx with reward
-                block.transactions[0].outputs[0].value =
-                    reward + miner_fees;
-
-                // recalculate merkle root
-                block.header.merkle_root =
-                    MerkleRoot::calculate(&block.transactions);
-
                 let message = Template(block);
-                message.send_async(&mut socket).await.unwrap();
+                message
+                    .send_async(network, &mut socket)
+                    .await
+                    .unwrap();
             }
         }
     }
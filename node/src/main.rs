@@ -1,11 +1,15 @@
 use argh::FromArgs;
 use dashmap::DashMap;
 use static_init::dynamic;
+use tracing::info;
 
 use anyhow::Result;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::RwLock;
+use tracing_subscriber::EnvFilter;
 
+use btclib::params::Network;
+use btclib::sha256::Hash;
 use btclib::types::Blockchain;
 
 use std::path::Path;
@@ -15,7 +19,7 @@ mod util;
 
 #[dynamic]
 pub static BLOCKCHAIN: RwLock<Blockchain> =
-    RwLock::new(Blockchain::new());
+    RwLock::new(Blockchain::new(Network::Main));
 
 // Node pool
 #[dynamic]
@@ -30,11 +34,34 @@ struct Args {
 
     #[argh(
         option,
-        default = "String::from(\"./blockchain.cbor\")"
+        default = "String::from(\"./blockchain.dat\")"
     )]
     /// blockchain file location
     blockchain_file: String,
 
+    #[argh(option, default = "Network::Main")]
+    /// network to run on: main, test or regtest
+    network: Network,
+
+    #[argh(switch)]
+    /// keep an index of all transactions by id
+    txindex: bool,
+
+    #[argh(switch)]
+    /// keep an index of all outputs by public key
+    addressindex: bool,
+
+    #[argh(option)]
+    /// UTXO snapshot to start from if there is no blockchain
+    /// file, instead of downloading every block
+    snapshot: Option<String>,
+
+    #[argh(option)]
+    /// commitment the UTXO snapshot has to have, as printed
+    /// by snapshot_gen. Required unless the network knows the
+    /// snapshot, which no network does yet
+    snapshot_commitment: Option<Hash>,
+
     #[argh(positional)]
     /// addresses of initial nodes
     nodes: Vec<String>,
@@ -42,6 +69,15 @@ struct Args {
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // log to stdout, at info level unless RUST_LOG says
+    // otherwise
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
     // Parse command line arguments
     let args: Args = argh::from_env();
 
@@ -49,51 +85,76 @@ async fn main() -> Result<()> {
     let port = args.port;
     let blockchain_file = args.blockchain_file;
     let nodes = args.nodes;
+    let network = args.network;
+
+    // start from the genesis block of the chosen network
+    *BLOCKCHAIN.write().await = Blockchain::new(network);
+    info!("running on the {} network", network);
 
     util::populate_connections(&nodes).await?;
-    println!("total amount of known nodes: {}", NODES.len());
+    info!("total amount of known nodes: {}", NODES.len());
     // Check if the blockchain_file exists
     if Path::new(&blockchain_file).exists() {
         util::load_blockchain(&blockchain_file).await?;
     } else {
-        println!("blockchain file does not exist!");
-
-        if nodes.is_empty() {
-            println!("no initial nodes provided, starting as a seed node");
-        } else {
-            let (longest_name, longest_count) =
-                util::find_longest_chain_node().await?;
+        info!("blockchain file does not exist!");
 
-            // request the blockchain from the node with the longest blockchain
-            util::download_blockchain(
-                &longest_name,
-                longest_count,
+        if let Some(snapshot_file) = &args.snapshot {
+            util::load_snapshot(
+                snapshot_file,
+                args.snapshot_commitment,
             )
             .await?;
+        }
 
-            println!(
-                "blockchain downloaded from {}",
-                longest_name
-            );
-
-            // recalculate utxos
-            {
-                let mut blockchain = BLOCKCHAIN.write().await;
-                blockchain.rebuild_utxos();
+        if nodes.is_empty() {
+            info!("no initial nodes provided, starting as a seed node");
+        } else {
+            let (heaviest_name, heaviest_height) =
+                util::find_heaviest_chain_node().await?;
+
+            // request the blockchain from the node with the most work
+            if heaviest_name.is_empty() {
+                info!("no node has more work than us");
+            } else {
+                util::download_blockchain(
+                    &heaviest_name,
+                    heaviest_height,
+                )
+                .await?;
+
+                info!(
+                    "blockchain downloaded from {}",
+                    heaviest_name
+                );
             }
+        }
+    }
 
-            // try to adjust difficulty
-            {
-                let mut blockchain = BLOCKCHAIN.write().await;
-                blockchain.try_adjust_target();
-            }
+    // the indexes are built from the blocks we have by now,
+    // and kept up to date from here on
+    if args.txindex || args.addressindex {
+        let mut blockchain = BLOCKCHAIN.write().await;
+        if args.txindex {
+            info!("building the transaction index...");
+            blockchain.enable_tx_index();
+        }
+        if args.addressindex {
+            info!("building the address index...");
+            blockchain.enable_address_index();
         }
     }
 
+    // a blockchain started from a snapshot still has to
+    // validate the blocks before it
+    if BLOCKCHAIN.read().await.snapshot().is_some() {
+        tokio::spawn(util::validate_snapshot());
+    }
+
     // Start the TCP listener on 0.0.0.0:port
     let addr = format!("0.0.0.0:{}", port);
     let listener = TcpListener::bind(&addr).await?;
-    println!("Listening on {}", addr);
+    info!("Listening on {}", addr);
 
     // start a task to periodically cleanup the mempool
     // normally, you would want to keep and join the handle
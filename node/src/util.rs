@@ -1,61 +1,120 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use tokio::net::TcpStream;
 use tokio::time;
+use tracing::{error, info, warn};
 
+use btclib::error::RejectReason;
 use btclib::network::Message;
-use btclib::types::Blockchain;
+use btclib::params::AssumedUtxo;
+use btclib::sha256::Hash;
+use btclib::types::{Blockchain, HeaderChain, UtxoSnapshot};
 use btclib::util::Saveable;
+use btclib::U256;
 
 pub async fn load_blockchain(
     blockchain_file: &str,
 ) -> Result<()> {
-    println!("blockchain file exists, loading...");
+    info!("blockchain file exists, loading...");
     let new_blockchain =
         Blockchain::load_from_file(blockchain_file)?;
-    println!("blockchain loaded");
+    info!("blockchain loaded");
 
     let mut blockchain = crate::BLOCKCHAIN.write().await;
+    let network = blockchain.network();
+    if new_blockchain.network() != network {
+        bail!(
+            "blockchain file belongs to the {} network",
+            new_blockchain.network()
+        );
+    }
+
+    let genesis_hash = network.params().genesis_block.hash();
+    if new_blockchain.blocks().next().map(|block| block.hash())
+        != Some(genesis_hash)
+    {
+        bail!("blockchain file has the wrong genesis block");
+    }
     *blockchain = new_blockchain;
 
-    println!("rebuilding utxos...");
-    blockchain.rebuild_utxos();
-    println!("utxos rebuilt");
+    let summary = blockchain.utxo_set_summary();
+    info!(
+        "utxos: {} outputs worth {}, commitment {}",
+        summary.count, summary.total_value, summary.commitment
+    );
+    info!("current target: {}", blockchain.target());
 
-    println!("checking if target needs to be adjusted...");
-    println!("current target: {}", blockchain.target());
-    blockchain.try_adjust_target();
-    println!("new target: {}", blockchain.target());
+    info!("initialization complete");
+    Ok(())
+}
 
-    println!("initialization complete");
+// start from a UTXO snapshot, checked against the given
+// commitment or else the one the network knows for its height
+pub async fn load_snapshot(
+    snapshot_file: &str,
+    commitment: Option<Hash>,
+) -> Result<()> {
+    info!("loading UTXO snapshot...");
+    let snapshot = UtxoSnapshot::load_from_file(snapshot_file)?;
+    let block_hash = snapshot
+        .headers
+        .last()
+        .context("UTXO snapshot without headers")?
+        .hash();
+    let height = snapshot.headers.len() as u64 - 1;
+
+    let mut blockchain = crate::BLOCKCHAIN.write().await;
+    let network = blockchain.network();
+    let assumed = match commitment {
+        Some(commitment) => AssumedUtxo {
+            height,
+            block_hash,
+            commitment,
+        },
+        None => *network
+            .params()
+            .assumed_utxo(height)
+            .with_context(|| {
+                format!(
+                    "the {network} network knows no UTXO \
+                    snapshot at height {height}, pass the \
+                    commitment it has to have with \
+                    --snapshot-commitment"
+                )
+            })?,
+    };
+
+    *blockchain =
+        Blockchain::from_snapshot(network, snapshot, &assumed)?;
     Ok(())
 }
 
 pub async fn populate_connections(
     nodes: &[String],
 ) -> Result<()> {
-    println!("trying to connect to other nodes...");
+    info!("trying to connect to other nodes...");
+    let network = crate::BLOCKCHAIN.read().await.network();
 
     for node in nodes {
-        println!("connecting to {}", node);
+        info!("connecting to {}", node);
 
         let mut stream = TcpStream::connect(&node).await?;
         let message = Message::DiscoverNodes;
-        message.send_async(&mut stream).await?;
-        println!("sent DiscoverNodes to {}", node);
+        message.send_async(network, &mut stream).await?;
+        info!("sent DiscoverNodes to {}", node);
         let message =
-            Message::receive_async(&mut stream).await?;
+            Message::receive_async(network, &mut stream).await?;
         match message {
             Message::NodeList(child_nodes) => {
-                println!("received NodeList from {}", node);
+                info!("received NodeList from {}", node);
                 for child_node in child_nodes {
-                    println!("adding node {}", child_node);
+                    info!("adding node {}", child_node);
                     let new_stream =
                         TcpStream::connect(&child_node).await?;
                     crate::NODES.insert(child_node, new_stream);
                 }
             }
             _ => {
-                println!("unexpected message from {}", node);
+                warn!("unexpected message from {}", node);
             }
         }
 
@@ -65,12 +124,18 @@ pub async fn populate_connections(
     Ok(())
 }
 
-pub async fn find_longest_chain_node() -> Result<(String, u32)> {
-    println!(
-        "finding nodes with the highest blockchain length..."
-    );
-    let mut longest_name = String::new();
-    let mut longest_count = 0;
+// find the node whose blockchain has the most work, and
+// return it along with its height
+pub async fn find_heaviest_chain_node() -> Result<(String, u64)>
+{
+    info!("finding nodes with the most chainwork...");
+    let mut heaviest_name = String::new();
+    let mut heaviest_height = 0;
+    let mut most_work = U256::zero();
+    let (network, chainwork) = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        (blockchain.network(), blockchain.chainwork())
+    };
 
     let all_nodes = crate::NODES
         .iter()
@@ -78,33 +143,35 @@ pub async fn find_longest_chain_node() -> Result<(String, u32)> {
         .collect::<Vec<_>>();
 
     for node in all_nodes {
-        println!("asking {} for blockchain length", node);
+        info!("asking {} for blockchain work", node);
 
         let mut stream =
             crate::NODES.get_mut(&node).context("no node")?;
 
-        let message = Message::AskDifference(0);
-        message.send_async(&mut *stream).await.unwrap();
+        let message = Message::AskDifference(chainwork);
+        message.send_async(network, &mut *stream).await.unwrap();
 
-        println!("sent AskDifference to {}", node);
+        info!("sent AskDifference to {}", node);
 
         let message =
-            Message::receive_async(&mut *stream).await?;
+            Message::receive_async(network, &mut *stream)
+                .await?;
         match message {
-            Message::Difference(count) => {
-                println!("received Difference from {}", node);
-                if count > longest_count {
-                    println!(
-                        "new longest blockchain: \
+            Message::Difference { extra_work, height } => {
+                info!("received Difference from {}", node);
+                if extra_work > most_work {
+                    info!(
+                        "new heaviest blockchain: \
                    {} blocks from {node}",
-                        count
+                        height
                     );
-                    longest_count = count;
-                    longest_name = node;
+                    most_work = extra_work;
+                    heaviest_height = height;
+                    heaviest_name = node;
                 }
             }
             e => {
-                println!(
+                warn!(
                     "unexpected message from {}: {:?}",
                     node, e
                 );
@@ -112,28 +179,66 @@ pub async fn find_longest_chain_node() -> Result<(String, u32)> {
         }
     }
 
-    Ok((longest_name, longest_count as u32))
+    Ok((heaviest_name, heaviest_height))
 }
 
 pub async fn download_blockchain(
     node: &str,
-    count: u32,
+    height: u64,
 ) -> Result<()> {
+    // every node starts with the genesis block, so only
+    // fetch the blocks after the ones we already have
+    let (network, start) = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        (blockchain.network(), blockchain.block_height())
+    };
+
     let mut stream = crate::NODES.get_mut(node).unwrap();
-    for i in 0..count as usize {
-        let message = Message::FetchBlock(i);
-        message.send_async(&mut *stream).await?;
+
+    // sync and validate the headers first, so that no block
+    // is downloaded unless the work of the chain is real
+    let mut headers = HeaderChain::new(network);
+    while headers.height() < height {
+        let message = Message::GetHeaders(headers.locator());
+        message.send_async(network, &mut *stream).await?;
+
+        let message =
+            Message::receive_async(network, &mut *stream)
+                .await?;
+        let Message::Headers(batch) = message else {
+            bail!("unexpected message from {}", node);
+        };
+        if batch.is_empty() {
+            break;
+        }
+        headers.add_headers(batch)?;
+    }
+    info!("synced {} headers from {}", headers.height(), node);
+
+    for i in start..headers.height() {
+        let message = Message::FetchBlock(i as usize);
+        message.send_async(network, &mut *stream).await?;
 
         let message =
-            Message::receive_async(&mut *stream).await?;
+            Message::receive_async(network, &mut *stream)
+                .await?;
         match message {
             Message::NewBlock(block) => {
+                if Some(block.hash()) != headers.hash_at(i) {
+                    bail!(
+                        "block {} from {} does not match its \
+                        header",
+                        i,
+                        node
+                    );
+                }
+
                 let mut blockchain =
                     crate::BLOCKCHAIN.write().await;
                 blockchain.add_block(block)?;
             }
             _ => {
-                println!("unexpected message from {}", node);
+                warn!("unexpected message from {}", node);
             }
         }
     }
@@ -141,6 +246,110 @@ pub async fn download_blockchain(
     Ok(())
 }
 
+// validate the blocks up to the snapshot the blockchain was
+// started from, replaying them from the genesis block as if
+// there was no snapshot. If they lead to the UTXO set of the
+// snapshot, they take the place of its headers, otherwise
+// the snapshot was invalid and so is everything built on it
+pub async fn validate_snapshot() {
+    let mut interval =
+        time::interval(time::Duration::from_secs(60));
+
+    loop {
+        interval.tick().await;
+
+        let nodes = crate::NODES
+            .iter()
+            .map(|x| x.key().clone())
+            .collect::<Vec<_>>();
+        for node in nodes {
+            let validated = match download_history(&node).await {
+                Ok(Some(validated)) => validated,
+                Ok(None) => {
+                    info!("{node} does not have the blocks before the snapshot either");
+                    continue;
+                }
+                Err(e) => {
+                    warn!("failed to validate the snapshot with {node}: {e}");
+                    continue;
+                }
+            };
+
+            let mut blockchain = crate::BLOCKCHAIN.write().await;
+            if let Err(e) =
+                blockchain.complete_snapshot(validated)
+            {
+                error!("the blocks before the UTXO snapshot do not match it: {e}");
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+}
+
+// download and validate the blocks up to the snapshot from a
+// node, over a connection of our own so as not to hold up
+// anything else. They have to be the blocks of the headers
+// of the snapshot. None if the node only has their headers,
+// because it was started from a snapshot itself
+async fn download_history(
+    node: &str,
+) -> Result<Option<Blockchain>> {
+    let (network, hashes) = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        let snapshot = blockchain
+            .snapshot()
+            .context("no snapshot to validate")?;
+        let hashes: Vec<Hash> = blockchain
+            .blocks()
+            .take(snapshot.height as usize + 1)
+            .map(|block| block.hash())
+            .collect();
+        (blockchain.network(), hashes)
+    };
+    info!(
+        "validating the {} blocks before the snapshot with {}",
+        hashes.len() - 1,
+        node
+    );
+
+    let mut stream = TcpStream::connect(node).await?;
+    let mut validated = Blockchain::new(network);
+    for (height, hash) in hashes.iter().enumerate().skip(1) {
+        let message = Message::FetchBlock(height);
+        message.send_async(network, &mut stream).await?;
+
+        let message =
+            Message::receive_async(network, &mut stream).await?;
+        let block = match message {
+            Message::NewBlock(block) => block,
+            Message::Reject {
+                reason: RejectReason::NotAvailable,
+                ..
+            } => return Ok(None),
+            Message::Reject { reason, .. } => {
+                bail!(
+                    "{} rejected block {}: {}",
+                    node,
+                    height,
+                    reason
+                );
+            }
+            _ => bail!("unexpected message from {}", node),
+        };
+        if block.hash() != *hash {
+            bail!(
+                "block {} from {} is not on our chain",
+                height,
+                node
+            );
+        }
+        validated.add_block(block)?;
+    }
+
+    Ok(Some(validated))
+}
+
 pub async fn cleanup() {
     let mut interval =
         time::interval(time::Duration::from_secs(30));
@@ -148,7 +357,7 @@ pub async fn cleanup() {
     loop {
         interval.tick().await;
 
-        println!("cleaning the mempool from old transactions");
+        info!("cleaning the mempool from old transactions");
         let mut blockchain = crate::BLOCKCHAIN.write().await;
         blockchain.cleanup_mempool();
     }
@@ -161,7 +370,7 @@ pub async fn save(name: String) {
     loop {
         interval.tick().await;
 
-        println!("saving blockchain to drive...");
+        info!("saving blockchain to drive...");
         let blockchain = crate::BLOCKCHAIN.read().await;
         blockchain.save_to_file(name.clone()).unwrap();
     }
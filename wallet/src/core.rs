@@ -10,9 +10,15 @@ use std::fs;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use btclib::crypto::{PrivateKey, PublicKey};
-use btclib::network::Message;
-use btclib::types::{Transaction, TransactionOutput};
+use btclib::amount::Amount;
+use btclib::crypto::{PrivateKey, PublicKey, Signature};
+use btclib::network::{Message, UtxoInfo};
+use btclib::params::Network;
+use btclib::script::Script;
+use btclib::types::{
+    SigHashType, Transaction, TransactionInput,
+    TransactionOutput,
+};
 use btclib::util::Saveable;
 
 /// Represent a key pair with paths to public and private keys.
@@ -62,6 +68,8 @@ pub enum FeeType {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FeeConfig {
     pub fee_type: FeeType,
+    /// Satoshis for a fixed fee, a percentage of the amount
+    /// sent otherwise
     pub value: f64,
 }
 
@@ -78,8 +86,7 @@ pub struct Config {
 #[derive(Clone)]
 struct UtxoStore {
     my_keys: Vec<LoadedKey>,
-    utxos:
-        Arc<SkipMap<PublicKey, Vec<(bool, TransactionOutput)>>>,
+    utxos: Arc<SkipMap<PublicKey, Vec<UtxoInfo>>>,
 }
 
 impl UtxoStore {
@@ -101,6 +108,7 @@ impl UtxoStore {
 /// Represent the core functionality of the wallet.
 pub struct Core {
     pub config: Config,
+    pub network: Network,
     utxos: UtxoStore,
     pub tx_sender: Sender<Transaction>,
     pub stream: Mutex<TcpStream>,
@@ -116,6 +124,7 @@ impl Core {
         let (tx_sender, _) = kanal::bounded(10);
         Core {
             config,
+            network: Network::default(),
             utxos,
             tx_sender,
             stream: Mutex::new(stream),
@@ -155,11 +164,15 @@ impl Core {
             let message =
                 Message::FetchUTXOs(key.public.clone());
             message
-                .send_async(&mut *self.stream.lock().await)
+                .send_async(
+                    self.network,
+                    &mut *self.stream.lock().await,
+                )
                 .await?;
 
             if let Message::UTXOs(utxos) =
                 Message::receive_async(
+                    self.network,
                     &mut *self.stream.lock().await,
                 )
                 .await?
@@ -170,13 +183,9 @@ impl Core {
                     key.public
                 );
                 // Replace the entire UTXO set for this key
-                self.utxos.utxos.insert(
-                    key.public.clone(),
-                    utxos
-                        .into_iter()
-                        .map(|(output, marked)| (marked, output))
-                        .collect(),
-                );
+                self.utxos
+                    .utxos
+                    .insert(key.public.clone(), utxos);
             } else {
                 error!("Unexpected response from node");
                 return Err(anyhow::anyhow!(
@@ -199,23 +208,42 @@ impl Core {
             self.config.default_node
         );
         let message = Message::SubmitTransaction(transaction);
-        message
-            .send_async(&mut *self.stream.lock().await)
-            .await?;
-        info!("Transaction sent successfully");
-        Ok(())
+        let mut stream = self.stream.lock().await;
+        message.send_async(self.network, &mut *stream).await?;
+
+        match Message::receive_async(self.network, &mut *stream)
+            .await?
+        {
+            Message::Accepted(hash) => {
+                info!("Transaction {} accepted", hash);
+                Ok(())
+            }
+            Message::Reject { hash, reason } => {
+                error!(
+                    "Transaction {} rejected: {}",
+                    hash, reason
+                );
+                Err(anyhow::anyhow!(
+                    "Transaction rejected: {}",
+                    reason
+                ))
+            }
+            _ => {
+                error!("Unexpected response from node");
+                Err(anyhow::anyhow!(
+                    "Unexpected response from node"
+                ))
+            }
+        }
     }
 
     /// Prepare and send a transaction asynchronously.
     pub fn send_transaction_async(
         &self,
         recipient: &str,
-        amount: u64,
+        amount: Amount,
     ) -> Result<()> {
-        info!(
-            "Preparing to send {} satoshis to {}",
-            amount, recipient
-        );
+        info!("Preparing to send {} to {}", amount, recipient);
         let recipient_key = self
             .config
             .contacts
@@ -235,66 +263,96 @@ impl Core {
         Ok(())
     }
 
-    /// Get the current balance of all UTXOs.
-    pub fn get_balance(&self) -> u64 {
-        let balance = self
+    /// Get the current balance of all spendable UTXOs.
+    pub fn get_balance(&self) -> Amount {
+        let balance = self.sum_utxos(|utxo| !utxo.immature);
+        debug!("Current balance: {}", balance);
+        balance
+    }
+
+    /// Get the balance of coinbase UTXOs that cannot be spent
+    /// yet.
+    pub fn get_immature_balance(&self) -> Amount {
+        let balance = self.sum_utxos(|utxo| utxo.immature);
+        debug!("Immature balance: {}", balance);
+        balance
+    }
+
+    /// Sum the values of the UTXOs matching a filter.
+    fn sum_utxos(
+        &self,
+        filter: impl Fn(&UtxoInfo) -> bool,
+    ) -> Amount {
+        let values: Vec<Amount> = self
             .utxos
             .utxos
             .iter()
-            .map(|entry| {
+            .flat_map(|entry| {
                 entry
                     .value()
                     .iter()
-                    .map(|utxo| utxo.1.value)
-                    .sum::<u64>()
+                    .filter(|utxo| filter(utxo))
+                    .map(|utxo| utxo.output.value)
+                    .collect::<Vec<_>>()
             })
-            .sum();
-        debug!("Current balance: {} satoshis", balance);
-        balance
+            .collect();
+
+        Amount::checked_sum(values).unwrap_or_else(|| {
+            error!("UTXO values exceed the money supply");
+            Amount::MAX_MONEY
+        })
     }
 
     /// Create a new transaction.
     pub fn create_transaction(
         &self,
         recipient: &PublicKey,
-        amount: u64,
+        amount: Amount,
     ) -> Result<Transaction> {
         debug!(
-            "Creating transaction for {} satoshis to {:?}",
+            "Creating transaction for {} to {:?}",
             amount, recipient
         );
-        let fee = self.calculate_fee(amount);
-        let total_amount = amount + fee;
+        let fee = self.calculate_fee(amount)?;
+        let total_amount = Amount::checked_sum([amount, fee])
+            .ok_or_else(|| {
+                anyhow::anyhow!("Amount too large")
+            })?;
 
-        let mut inputs = Vec::new();
-        let mut input_sum = 0;
+        // pick UTXOs to spend together with the keys they are
+        // locked to
+        let mut selected = Vec::new();
+        let mut input_sum = Amount::ZERO;
 
         for entry in self.utxos.utxos.iter() {
             let pubkey = entry.key();
             let utxos = entry.value();
 
-            for (marked, utxo) in utxos.iter() {
-                if *marked {
+            for utxo in utxos.iter() {
+                if utxo.marked || utxo.immature {
                     continue;
-                } // Skip marked UTXOs
+                } // Skip marked and immature UTXOs
                 if input_sum >= total_amount {
                     break;
                 }
-                inputs.push(btclib::types::TransactionInput {
-                    prev_transaction_output_hash: utxo.hash(),
-                    signature:
-                        btclib::crypto::Signature::sign_output(
-                            &utxo.hash(),
-                            &self
-                                .utxos
-                                .my_keys
-                                .iter()
-                                .find(|k| k.public == *pubkey)
-                                .unwrap()
-                                .private,
-                        ),
-                });
-                input_sum += utxo.value;
+                let key = self
+                    .utxos
+                    .my_keys
+                    .iter()
+                    .find(|k| k.public == *pubkey)
+                    .unwrap();
+                selected.push((
+                    utxo.outpoint,
+                    utxo.output.clone(),
+                    key,
+                ));
+                input_sum = Amount::checked_sum([
+                    input_sum,
+                    utxo.output.value,
+                ])
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Invalid UTXO value")
+                })?;
             }
             if input_sum >= total_amount {
                 break;
@@ -302,40 +360,83 @@ impl Core {
         }
 
         if input_sum < total_amount {
-            error!("Insufficient funds: have {} satoshis, need {} satoshis", input_sum, total_amount);
+            error!(
+                "Insufficient funds: have {}, need {}",
+                input_sum, total_amount
+            );
             return Err(anyhow::anyhow!("Insufficient funds"));
         }
 
         let mut outputs = vec![TransactionOutput {
             value: amount,
-            unique_id: uuid::Uuid::new_v4(),
-            pubkey: recipient.clone(),
+            locking_script: Script::p2pkh(recipient),
         }];
 
-        if input_sum > total_amount {
+        if let Some(change) = input_sum
+            .checked_sub(total_amount)
+            .filter(|change| *change > Amount::ZERO)
+        {
             outputs.push(TransactionOutput {
-                value: input_sum - total_amount,
-                unique_id: uuid::Uuid::new_v4(),
-                pubkey: self.utxos.my_keys[0].public.clone(),
+                value: change,
+                locking_script: Script::p2pkh(
+                    &self.utxos.my_keys[0].public,
+                ),
             });
         }
 
+        let mut transaction = Transaction::new(
+            selected
+                .iter()
+                .map(|(outpoint, _, _)| TransactionInput {
+                    prev_output: *outpoint,
+                    unlocking_script: Script::default(),
+                    sequence: 0,
+                })
+                .collect(),
+            outputs,
+        );
+
+        // sign every input over the whole transaction
+        for (index, (_, utxo, key)) in
+            selected.iter().enumerate()
+        {
+            let sighash =
+                transaction.sighash(index, SigHashType::All)?;
+            let signature =
+                Signature::sign(&sighash, &key.private);
+            transaction.inputs[index].unlocking_script =
+                utxo.locking_script.unlock_single_key(
+                    &signature,
+                    SigHashType::All,
+                    &key.public,
+                )?;
+        }
+
         info!("Transaction created successfully");
-        Ok(Transaction::new(inputs, outputs))
+        Ok(transaction)
     }
 
-    /// Calculate the fee for a transaction.
-    fn calculate_fee(&self, amount: u64) -> u64 {
+    /// Calculate the fee for a transaction. Only the
+    /// configured rate is a float, the fee itself is computed
+    /// exactly in satoshis.
+    fn calculate_fee(&self, amount: Amount) -> Result<Amount> {
+        let value = self.config.fee_config.value;
+        if !value.is_finite() || value < 0.0 {
+            return Err(anyhow::anyhow!("Invalid fee config"));
+        }
+
         let fee = match self.config.fee_config.fee_type {
-            FeeType::Fixed => {
-                self.config.fee_config.value as u64
-            }
+            FeeType::Fixed => Amount::from_sat(value as u64),
             FeeType::Percent => {
-                (amount as f64 * self.config.fee_config.value
-                    / 100.0) as u64
+                // the percentage in millionths of the amount
+                let millionths =
+                    (value * 10_000.0).round() as u128;
+                let fee = amount.to_sat() as u128 * millionths
+                    / 1_000_000;
+                Amount::from_sat(u64::try_from(fee)?)
             }
         };
-        debug!("Calculated fee: {} satoshis", fee);
-        fee
+        debug!("Calculated fee: {}", fee);
+        Ok(fee)
     }
 }
@@ -1,5 +1,6 @@
 // main.rs
 use anyhow::Result;
+use btclib::params::Network;
 use clap::{Parser, Subcommand};
 use cursive::views::TextContent;
 use tracing::{debug, info};
@@ -32,6 +33,9 @@ struct Cli {
 
     #[arg(short, long, value_name = "ADDRESS")]
     node: Option<String>,
+
+    #[arg(long, value_name = "NETWORK", default_value_t = Network::Main)]
+    network: Network,
 }
 
 #[derive(Subcommand)]
@@ -66,6 +70,8 @@ async fn main() -> Result<()> {
         info!("Overriding default node with: {}", node);
         core.config.default_node = node;
     }
+    info!("Using the {} network", cli.network);
+    core.network = cli.network;
 
     let (tx_sender, tx_receiver) = kanal::bounded(10);
     core.tx_sender = tx_sender;
@@ -1,6 +1,7 @@
 // ui.rs
 use crate::core::Core;
 use anyhow::Result;
+use btclib::amount::Amount;
 use cursive::event::{Event, Key};
 use cursive::traits::*;
 use cursive::views::{
@@ -17,13 +18,13 @@ enum Unit {
     Sats,
 }
 
-/// Convert an amount between BTC and Satoshi units.
-fn convert_amount(amount: f64, from: Unit, to: Unit) -> f64 {
-    match (from, to) {
-        (Unit::Btc, Unit::Sats) => amount * 100_000_000.0,
-        (Unit::Sats, Unit::Btc) => amount / 100_000_000.0,
-        _ => amount,
-    }
+/// Parse an amount entered in the given unit.
+fn parse_amount(amount: &str, unit: Unit) -> Result<Amount> {
+    let amount = match unit {
+        Unit::Btc => Amount::from_btc_str(amount.trim())?,
+        Unit::Sats => Amount::from_sat_str(amount.trim())?,
+    };
+    Ok(amount)
 }
 
 /// Initialize and run the user interface.
@@ -200,23 +201,22 @@ fn send_transaction(
             view.get_content()
         })
         .unwrap();
-    let amount: f64 = s
+    let amount = s
         .call_on_name("amount", |view: &mut EditView| {
             view.get_content()
         })
-        .unwrap()
-        .parse()
-        .unwrap_or(0.0);
+        .unwrap();
 
-    let amount_sats =
-        convert_amount(amount, unit, Unit::Sats) as u64;
+    let amount = match parse_amount(&amount, unit) {
+        Ok(amount) => amount,
+        Err(e) => return show_error_dialog(s, e),
+    };
 
     info!(
-        "Attempting to send transaction to {} for {} satoshis",
-        recipient, amount_sats
+        "Attempting to send transaction to {} for {}",
+        recipient, amount
     );
-    match core
-        .send_transaction_async(recipient.as_str(), amount_sats)
+    match core.send_transaction_async(recipient.as_str(), amount)
     {
         Ok(_) => show_success_dialog(s),
         Err(e) => show_error_dialog(s, e),
@@ -3,6 +3,7 @@ use anyhow::Result;
 use std::panic;
 use std::path::PathBuf;
 
+use btclib::amount::Amount;
 use tracing::*;
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
@@ -66,14 +67,17 @@ pub fn generate_dummy_config(path: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-/// Convert satoshis to a BTC string
-pub fn sats_to_btc(sats: u64) -> String {
-    let btc = sats as f64 / 100_000_000.0;
-    format!("{} BTC", btc)
-}
-
 /// Make it big lmao
 pub fn big_mode_btc(core: &Core) -> String {
-    text_to_ascii_art::convert(sats_to_btc(core.get_balance()))
-        .unwrap()
+    let mut balance = text_to_ascii_art::convert(
+        core.get_balance().to_string(),
+    )
+    .unwrap();
+
+    let immature = core.get_immature_balance();
+    if immature > Amount::ZERO {
+        balance.push_str(&format!("\n+ {} immature", immature));
+    }
+
+    balance
 }